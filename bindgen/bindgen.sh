@@ -12,11 +12,21 @@ HERE
 bindgen --allowlist-type 'ctl_lun_list' \
 	--allowlist-type 'ctl_lun_req' \
 	--allowlist-type 'ctl_lun_create_params' \
+	--allowlist-type 'ctl_iscsi' \
+	--allowlist-type 'ctl_get_io_stats' \
+	--allowlist-type 'ctl_lun_map' \
+	--allowlist-type 'ctl_stat_types' \
 	--allowlist-item 'CTL_DEFAULT_DEV' \
 	--rustified-enum 'ctl_lunreq_type' \
 	--rustified-enum 'ctl_lun_list_status' \
 	--rustified-enum 'ctl_lun_status' \
+	--rustified-enum 'ctl_iscsi_type' \
+	--rustified-enum 'ctl_iscsi_digest' \
+	--rustified-enum 'ctl_iscsi_status' \
+	--rustified-enum 'ctl_stat_types' \
+	--rustified-enum 'ctl_stats_status' \
 	--bitfield-enum 'ctl_backend_lun_flags' \
+	--bitfield-enum 'ctl_stats_flags' \
 	${CRATEDIR}/bindgen/wrapper.h -- \
 	-I${SRC_BASE} >> ${CRATEDIR}/src/ffi.rs
 rustfmt ${CRATEDIR}/src/ffi.rs
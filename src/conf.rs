@@ -3,18 +3,44 @@
 // parse initiator-portal as a netmask
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::{Display, Write},
+    fs,
+    hash::Hash,
     io::Read,
-    net::SocketAddr,
-    path::{Path, PathBuf}
+    mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::{
+        fd::AsRawFd,
+        unix::fs::FileTypeExt,
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use anyhow::{Context, Result, anyhow};
-use serde_derive::{Deserialize};
+use anyhow::{Context, Result, anyhow, bail};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_derive::{Deserialize, Serialize};
 use strum::{EnumString, IntoStaticStr};
 use uclicious::*;
 
-#[derive(Clone, Copy, Debug, Default, Eq, EnumString, PartialEq)]
+#[mockall_double::double]
+use crate::ioc::ioc;
+use crate::kconf;
+use crate::secret::{CommandProvider, FileProvider, Secret, SecretProvider};
+
+/// The auth-group used by targets and portal groups that don't specify one
+pub const DEFAULT_AUTH_GROUP: &str = "default";
+/// The portal group used by targets that don't specify one
+pub const DEFAULT_PORTAL_GROUP: &str = "default";
+/// The IANA-assigned iSCSI port
+const ISCSI_PORT: u16 = 3260;
+/// The size of the kernel's serial number field, CTL_SN_LEN
+const MAX_SERIAL_LEN: usize = 16;
+/// The size of the kernel's device id field, CTL_DEVID_LEN
+const MAX_DEVICE_ID_LEN: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default, Eq, EnumString, IntoStaticStr, PartialEq)]
 enum AuthType {
     #[default]
     Unknown,
@@ -28,7 +54,7 @@ enum AuthType {
     ChapMutual
 }
 
-#[derive(Clone, Copy, Debug, Default, Eq, EnumString, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Eq, EnumString, IntoStaticStr, PartialEq)]
 enum DiscoveryFilter {
     #[default]
     #[strum(serialize = "none")]
@@ -42,6 +68,7 @@ enum DiscoveryFilter {
 }
 
 #[derive(Clone, Copy, Debug, Default, Deserialize, Eq, EnumString, IntoStaticStr, PartialEq)]
+#[derive(Serialize)]
 pub enum Backend {
     #[default]
     #[strum(serialize = "block")]
@@ -52,35 +79,45 @@ pub enum Backend {
     Ramdisk
 }
 
-#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, EnumString, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, EnumString, IntoStaticStr, PartialEq)]
+#[derive(Serialize)]
 pub enum DeviceType {
     #[default]
-    #[strum(serialize = "disk", serialize = "direct", serialize = "0")]
-    #[serde(rename = "0")]
+    #[strum(to_string = "disk", serialize = "direct", serialize = "0")]
+    #[serde(rename(deserialize = "0", serialize = "disk"))]
     Disk = 0,
-    #[strum(serialize = "processor", serialize = "3")]
-    #[serde(rename = "3")]
+    #[strum(to_string = "processor", serialize = "3")]
+    #[serde(rename(deserialize = "3", serialize = "processor"))]
     Processor = 3,
-    #[strum(serialize = "cd", serialize = "cdrom", serialize = "dvd", serialize = "dvdrom", serialize = "5")]
-    #[serde(rename = "5")]
+    #[strum(to_string = "cd", serialize = "cdrom", serialize = "dvd", serialize = "dvdrom", serialize = "5")]
+    #[serde(rename(deserialize = "5", serialize = "cd"))]
     Cd = 5
 }
 
+impl DeviceType {
+    /// The kernel's block size for LUNs of this type that don't specify one
+    pub fn default_blocksize(self) -> u32 {
+        match self {
+            DeviceType::Cd => 2048,
+            _ => 512
+        }
+    }
+}
+
 #[derive(Clone, Debug, Uclicious)]
 #[ucl(skip_builder)]
 struct AuthGroup {
     #[ucl(path = "auth-type", default, from_str)]
-    #[expect(unused)]    // TODO: implement me
     auth_type: AuthType,
     #[ucl(default)]
     chap: Vec<Chap>,
     #[ucl(default, path = "chap-mutual")]
     chap_mutual: Vec<ChapMutual>,
     #[ucl(default, path = "initiator-name")]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     intiator_name: Option<String>,
     #[ucl(path = "initiator-portal", default)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     initiator_portal: Vec<String>
 }
 
@@ -93,64 +130,126 @@ impl AuthGroup {
     }
 }
 
+impl FromObject<ObjectRef> for Secret {
+    fn try_from(value: ObjectRef) -> std::result::Result<Self, ObjectError> {
+        <String as FromObject<ObjectRef>>::try_from(value).map(Secret::new)
+    }
+}
+
+/// Load a secret from its secret-file or secret-command, if it has one, after checking that
+/// exactly one of its sources is set.
+fn load_secret(
+    key: &str,
+    secret: &mut Option<Secret>,
+    file: &Option<PathBuf>,
+    command: &Option<String>) -> Result<()>
+{
+    let (source, provider): (_, Box<dyn SecretProvider>) = match (&secret, file, command) {
+        (Some(_), None, None) => return Ok(()),
+        (None, Some(path), None) => ("file", Box::new(FileProvider(path.clone()))),
+        (None, None, Some(cmd)) => ("command", Box::new(CommandProvider(cmd.clone()))),
+        (None, None, None) => bail!("one of {0}, {0}-file, or {0}-command is required", key),
+        _ => bail!("only one of {0}, {0}-file, or {0}-command may be set", key)
+    };
+    *secret = Some(provider.load().with_context(|| format!("{}-{}", key, source))?);
+    Ok(())
+}
+
 #[derive(Clone, Debug, Uclicious)]
 #[ucl(skip_builder)]
 struct Chap {
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     user: String,
-    #[expect(unused)]    // TODO: implement me
-    secret: String
+    /// After Conf::open, this holds the secret no matter where it came from.
+    #[ucl(default)]
+    secret: Option<Secret>,
+    #[ucl(default, path = "secret-file")]
+    secret_file: Option<PathBuf>,
+    #[ucl(default, path = "secret-command")]
+    secret_command: Option<String>,
+}
+
+impl Chap {
+    fn load_secrets(&mut self) -> Result<()> {
+        load_secret("secret", &mut self.secret, &self.secret_file, &self.secret_command)
+    }
 }
 
 #[derive(Clone, Debug, Uclicious)]
 #[ucl(skip_builder)]
 struct ChapMutual {
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     user: String,
-    #[expect(unused)]    // TODO: implement me
-    secret: String,
+    /// After Conf::open, this holds the secret no matter where it came from.
+    #[ucl(default)]
+    secret: Option<Secret>,
+    #[ucl(default, path = "secret-file")]
+    secret_file: Option<PathBuf>,
+    #[ucl(default, path = "secret-command")]
+    secret_command: Option<String>,
     #[ucl(path = "mutual-user")]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     mutual_user: String,
-    #[ucl(path = "mutual-secret")]
-    #[expect(unused)]    // TODO: implement me
-    mutual_secret: String,
+    /// After Conf::open, this holds the mutual secret no matter where it came from.
+    #[ucl(default, path = "mutual-secret")]
+    mutual_secret: Option<Secret>,
+    #[ucl(default, path = "mutual-secret-file")]
+    mutual_secret_file: Option<PathBuf>,
+    #[ucl(default, path = "mutual-secret-command")]
+    mutual_secret_command: Option<String>,
+}
+
+impl ChapMutual {
+    fn load_secrets(&mut self) -> Result<()> {
+        load_secret("secret", &mut self.secret, &self.secret_file, &self.secret_command)?;
+        load_secret("mutual-secret", &mut self.mutual_secret, &self.mutual_secret_file,
+            &self.mutual_secret_command)
+    }
+}
+
+/// Load the secrets for a list of chap and chap-mutual entries
+fn load_chap_secrets(chap: &mut [Chap], chap_mutual: &mut [ChapMutual]) -> Result<()> {
+    for c in chap.iter_mut() {
+        c.load_secrets().with_context(|| format!("chap user \"{}\"", c.user))?;
+    }
+    for c in chap_mutual.iter_mut() {
+        c.load_secrets().with_context(|| format!("chap-mutual user \"{}\"", c.user))?;
+    }
+    Ok(())
 }
 
 #[derive(Clone, Debug, Uclicious)]
 #[ucl(skip_builder)]
 pub struct PortalGroup {
-    #[ucl(path = "discovery-auth-group")]
-    #[expect(unused)]    // TODO: implement me
-    discovery_auth_group: String,
+    /// Conf::open fills in the "default" auth-group if the config file doesn't specify one
+    #[ucl(path = "discovery-auth-group", default)]
+    discovery_auth_group: Option<String>,
     #[ucl(path = "discovery-filter", default, from_str)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     discovery_filter: DiscoveryFilter,
     // TODO: allow listen to be specified with or without a port number
-    #[ucl(from_str)]
-    #[expect(unused)]    // TODO: implement me
-    listen: SocketAddr,
+    pub listen: Vec<SocketAddr>,
     // listen-iser is not implemented
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     offload: Option<String>,
     #[ucl(default, path = "option")]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     options: HashMap<String, String>,
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     redirect: Option<String>,
     #[ucl(default)]
     pub tag: Option<u16>,
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     foreign: bool,
     // TODO: parse the custom constants for DSCP, like "CSx"
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     dscp: Option<i32>,
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     pcp: Option<i32>
 }
 
@@ -167,105 +266,602 @@ pub struct Lun {
     pub device_id: String,
     #[ucl(default, path = "device-type", from_str)]
     pub device_type: DeviceType,
-    #[ucl(default, path = "option")]
-    pub options: HashMap<String, String>,
-    pub path: PathBuf,
+    #[ucl(default, path = "option", try_from = "HashMap<String, String>")]
+    pub options: LunOptions,
+    /// Options passed to the kernel verbatim, without any validation.  Useful for kernel options
+    /// that ctld doesn't know about yet.
+    #[ucl(default, path = "raw-option")]
+    pub raw_options: HashMap<String, String>,
+    /// The backing file or device.  Must be specified for block-backed LUNs.
+    #[ucl(default)]
+    pub path: Option<PathBuf>,
     #[ucl(default)]
     pub serial: Option<String>,
-    /// Must be specified for ramdisk-backed LUNs.  Optional for block-backed.
+    /// Size in bytes.  Must be specified for ramdisk-backed LUNs.  Optional for block-backed.
     #[ucl(default)]
     pub size: Option<u64>,
 }
 
-#[derive(Clone, Debug, Uclicious)]
-#[ucl(skip_builder)]
-struct TargetLun {
-    #[expect(unused)]    // TODO: implement me
-    number: u64,
-    #[expect(unused)]    // TODO: implement me
-    name: String
+impl Lun {
+    /// The configured block size, or else the kernel's default for the device type
+    pub fn blocksize(&self) -> u32 {
+        self.blocksize.unwrap_or_else(|| self.device_type.default_blocksize())
+    }
+
+    /// Get the size in bytes of a block-backed LUN's backing file or device.  Returns `None` for
+    /// other backends.
+    pub fn backing_size(&self) -> Result<Option<u64>> {
+        if self.backend != Backend::Block {
+            return Ok(None);
+        }
+        let path = self.path.as_ref().context("block-backed LUNs must have a path")?;
+        let md = fs::metadata(path).with_context(|| format!("path {}", path.display()))?;
+        let ft = md.file_type();
+        if ft.is_file() {
+            Ok(Some(md.len()))
+        } else if ft.is_char_device() || ft.is_block_device() {
+            let f = fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+            let mut mediasize: nix::libc::off_t = 0;
+            unsafe{ ioc::diocgmediasize(f.as_raw_fd(), &mut mediasize) }
+                .with_context(|| format!("DIOCGMEDIASIZE {}", path.display()))?;
+            Ok(Some(mediasize as u64))
+        } else {
+            bail!("path {} is not a regular file or a device", path.display());
+        }
+    }
+
+    /// Parse a single LUN definition: a JSON object with the same keys as a `lun` block in the
+    /// configuration file.
+    ///
+    /// UCL isn't accepted, because definitions come from control socket clients, and UCL would
+    /// let them close the `lun` block early or use macros like `.include`.
+    pub fn parse(name: &str, definition: &str) -> Result<Self> {
+        let obj: serde_json::Map<String, serde_json::Value> = serde_json::from_str(definition)
+            .context("LUN definition must be a JSON object")?;
+        // Re-serialize it, so the UCL parser sees nothing but a single JSON object
+        let body = serde_json::Value::Object(obj).to_string();
+        let ucl = format!("lun {{\n{} {}\n}}\ntarget {{}}\n", ucl_quote(name), body);
+        let mut conf = Conf::from_ucl(&ucl)?;
+        conf.luns.remove(name).with_context(|| format!("no definition for lun \"{}\"", name))
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        for (k, _) in self.options.to_kernel() {
+            if self.raw_options.contains_key(k) {
+                bail!("option {} is set by both option and raw-option", k);
+            }
+        }
+        if let Some(serial) = &self.serial {
+            if serial.len() > MAX_SERIAL_LEN {
+                bail!("serial {} is longer than {} bytes", serial, MAX_SERIAL_LEN);
+            }
+        }
+        if self.device_id.len() > MAX_DEVICE_ID_LEN {
+            bail!("device-id {} is longer than {} bytes", self.device_id, MAX_DEVICE_ID_LEN);
+        }
+        if let Some(bs) = self.blocksize {
+            if !bs.is_power_of_two() || !(512..=65536).contains(&bs) {
+                bail!("blocksize {} is not a power of two between 512 and 65536", bs);
+            }
+        }
+        let blocksize = u64::from(self.blocksize());
+        if let Some(size) = self.size {
+            if size % blocksize != 0 {
+                bail!("size {} is not a multiple of the blocksize {}", size, blocksize);
+            }
+        }
+        match self.backend {
+            Backend::Ramdisk => {
+                if self.size.is_none() {
+                    bail!("ramdisk-backed LUNs must have a size");
+                }
+            },
+            Backend::Block => {
+                let backing_size = self.backing_size()?.unwrap();
+                if let Some(size) = self.size {
+                    if size > backing_size {
+                        bail!("size {} exceeds the size of {}, {}", size,
+                            self.path.as_ref().unwrap().display(), backing_size);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The "ha_role" LUN option
+#[derive(Clone, Copy, Debug, Eq, EnumString, IntoStaticStr, PartialEq)]
+pub enum HaRole {
+    #[strum(serialize = "primary")]
+    Primary,
+    #[strum(serialize = "secondary")]
+    Secondary
+}
+
+/// The "provisioning_type" LUN option
+#[derive(Clone, Copy, Debug, Eq, EnumString, IntoStaticStr, PartialEq)]
+pub enum ProvisioningType {
+    #[strum(serialize = "resource")]
+    Resource,
+    #[strum(serialize = "thin")]
+    Thin,
+    #[strum(serialize = "unknown")]
+    Unknown
+}
+
+/// The "reordering" LUN option
+#[derive(Clone, Copy, Debug, Eq, EnumString, IntoStaticStr, PartialEq)]
+pub enum Reordering {
+    #[strum(serialize = "restricted")]
+    Restricted,
+    #[strum(serialize = "unrestricted")]
+    Unrestricted
+}
+
+/// The "serseq" LUN option
+#[derive(Clone, Copy, Debug, Eq, EnumString, IntoStaticStr, PartialEq)]
+pub enum Serseq {
+    #[strum(serialize = "off")]
+    Off,
+    #[strum(serialize = "on")]
+    On,
+    #[strum(serialize = "read")]
+    Read,
+    #[strum(serialize = "soft")]
+    Soft
+}
+
+/// Options that CTL understands for a LUN, as set by the `option` block.
+///
+/// Every option is passed to the kernel as a string, but parsing them here catches typos and
+/// malformed values before the kernel silently ignores them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LunOptions {
+    pub vendor: Option<String>,
+    pub product: Option<String>,
+    pub revision: Option<String>,
+    pub scsiname: Option<String>,
+    pub eui: Option<String>,
+    pub naa: Option<String>,
+    pub uuid: Option<String>,
+    pub ident_info: Option<String>,
+    pub text_ident_info: Option<String>,
+    pub ha_role: Option<HaRole>,
+    pub insecure_tpc: Option<bool>,
+    pub readcache: Option<bool>,
+    pub readonly: Option<bool>,
+    pub removable: Option<bool>,
+    pub reordering: Option<Reordering>,
+    pub serseq: Option<Serseq>,
+    pub pblocksize: Option<u32>,
+    pub pblockoffset: Option<u32>,
+    pub ublocksize: Option<u32>,
+    pub ublockoffset: Option<u32>,
+    pub rpm: Option<u16>,
+    pub formfactor: Option<u8>,
+    pub temperature: Option<u8>,
+    pub reftemperature: Option<u8>,
+    pub provisioning_type: Option<ProvisioningType>,
+    pub unmap: Option<bool>,
+    pub unmap_max_lba: Option<u64>,
+    pub unmap_max_descr: Option<u64>,
+    pub write_same_max_lba: Option<u64>,
+    pub avail_threshold: Option<u64>,
+    pub used_threshold: Option<u64>,
+    pub pool_avail_threshold: Option<u64>,
+    pub pool_used_threshold: Option<u64>,
+    pub writecache: Option<bool>,
+    /// Only meaningful for block-backed LUNs
+    pub num_threads: Option<u32>,
+    /// Only meaningful for ramdisk-backed LUNs
+    pub capacity: Option<u64>,
+    /// Options that we don't recognize.  They're still passed to the kernel, but Conf::open warns
+    /// about them.  Use `raw-option` to pass new kernel options without a warning.
+    pub unknown: BTreeMap<String, String>,
+}
+
+impl LunOptions {
+    /// Render the options the way that the kernel expects them: as name/value string pairs.
+    pub fn to_kernel(&self) -> Vec<(&str, String)> {
+        fn onoff(b: bool) -> String {
+            String::from(if b {"on"} else {"off"})
+        }
+
+        let mut opts = Vec::new();
+        let strings = [
+            ("vendor", &self.vendor),
+            ("product", &self.product),
+            ("revision", &self.revision),
+            ("scsiname", &self.scsiname),
+            ("eui", &self.eui),
+            ("naa", &self.naa),
+            ("uuid", &self.uuid),
+            ("ident_info", &self.ident_info),
+            ("text_ident_info", &self.text_ident_info),
+        ];
+        for (k, v) in strings {
+            if let Some(v) = v {
+                opts.push((k, v.clone()));
+            }
+        }
+        let enums = [
+            ("ha_role", self.ha_role.map(<&str>::from)),
+            ("reordering", self.reordering.map(<&str>::from)),
+            ("serseq", self.serseq.map(<&str>::from)),
+            ("provisioning_type", self.provisioning_type.map(<&str>::from)),
+        ];
+        for (k, v) in enums {
+            if let Some(v) = v {
+                opts.push((k, v.to_owned()));
+            }
+        }
+        let bools = [
+            ("insecure_tpc", self.insecure_tpc),
+            ("readcache", self.readcache),
+            ("readonly", self.readonly),
+            ("removable", self.removable),
+            ("unmap", self.unmap),
+            ("writecache", self.writecache),
+        ];
+        for (k, v) in bools {
+            if let Some(v) = v {
+                opts.push((k, onoff(v)));
+            }
+        }
+        let numbers = [
+            ("pblocksize", self.pblocksize.map(u64::from)),
+            ("pblockoffset", self.pblockoffset.map(u64::from)),
+            ("ublocksize", self.ublocksize.map(u64::from)),
+            ("ublockoffset", self.ublockoffset.map(u64::from)),
+            ("rpm", self.rpm.map(u64::from)),
+            ("formfactor", self.formfactor.map(u64::from)),
+            ("temperature", self.temperature.map(u64::from)),
+            ("reftemperature", self.reftemperature.map(u64::from)),
+            ("unmap_max_lba", self.unmap_max_lba),
+            ("unmap_max_descr", self.unmap_max_descr),
+            ("write_same_max_lba", self.write_same_max_lba),
+            ("avail-threshold", self.avail_threshold),
+            ("used-threshold", self.used_threshold),
+            ("pool-avail-threshold", self.pool_avail_threshold),
+            ("pool-used-threshold", self.pool_used_threshold),
+            ("num_threads", self.num_threads.map(u64::from)),
+            ("capacity", self.capacity),
+        ];
+        for (k, v) in numbers {
+            if let Some(v) = v {
+                opts.push((k, v.to_string()));
+            }
+        }
+        for (k, v) in self.unknown.iter() {
+            opts.push((k.as_str(), v.clone()));
+        }
+        opts
+    }
+}
+
+/// Serialize the options the same way the kernel reports them, as a map of strings
+impl Serialize for LunOptions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut opts = self.to_kernel();
+        opts.sort_unstable();
+        let mut map = serializer.serialize_map(Some(opts.len()))?;
+        for (k, v) in opts {
+            map.serialize_entry(k, &v)?;
+        }
+        map.end()
+    }
+}
+
+impl LunOptions {
+    /// Parse options as the kernel reports them.  Unlike `try_from`, a value that doesn't parse
+    /// is kept in `unknown` instead of failing, so that one odd option can't hide the whole LUN.
+    pub fn from_kernel(map: HashMap<String, String>) -> Self {
+        let mut opts = LunOptions::default();
+        for (k, v) in map.into_iter() {
+            if opts.set(&k, v.clone()).is_err() {
+                opts.unknown.insert(k, v);
+            }
+        }
+        opts
+    }
+
+    /// Parse and set a single option
+    fn set(&mut self, k: &str, v: String) -> Result<()> {
+        /// Check a free-form string against the length of its field in the INQUIRY data
+        fn string(v: String, maxlen: usize) -> Result<Option<String>> {
+            if v.len() > maxlen {
+                bail!("\"{}\" is longer than {} bytes", v, maxlen);
+            }
+            Ok(Some(v))
+        }
+
+        /// A binary identifier written in hex, with an optional 0x prefix
+        fn hex(v: String, maxbytes: usize) -> Result<Option<String>> {
+            let digits = v.strip_prefix("0x").unwrap_or(&v);
+            if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                bail!("\"{}\" is not a hexadecimal number", v);
+            }
+            if digits.len() > 2 * maxbytes {
+                bail!("\"{}\" is longer than {} bytes", v, maxbytes);
+            }
+            Ok(Some(v))
+        }
+
+        /// Either a hex string or a UUID in the usual 8-4-4-4-12 format
+        fn uuid(v: String) -> Result<Option<String>> {
+            if v.starts_with("0x") {
+                return hex(v, 16);
+            }
+            let groups = v.split('-').map(str::len).collect::<Vec<_>>();
+            if groups != [8, 4, 4, 4, 12] || !v.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+                bail!("\"{}\" is not a valid UUID", v);
+            }
+            Ok(Some(v))
+        }
+
+        fn onoff(v: String) -> Result<Option<bool>> {
+            match v.as_str() {
+                "on" => Ok(Some(true)),
+                "off" => Ok(Some(false)),
+                _ => bail!("\"{}\" must be either \"on\" or \"off\"", v)
+            }
+        }
+
+        /// Split an integer written the way strtoq(3) reads it with base 0 (decimal, 0x hex or
+        /// 0 octal) from whatever follows it
+        fn integer(v: &str) -> Option<(u64, &str)> {
+            let (radix, digits) = match v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+                Some(hex) => (16, hex),
+                None if v.starts_with('0') => (8, v),
+                None => (10, v)
+            };
+            let end = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+            let n = u64::from_str_radix(&digits[..end], radix).ok()?;
+            Some((n, &digits[end..]))
+        }
+
+        /// A number that CTL reads with strtol(3), so hex works too
+        fn number<T: TryFrom<u64>>(v: String) -> Result<Option<T>> {
+            integer(&v)
+                .filter(|(_, rest)| rest.is_empty())
+                .and_then(|(n, _)| T::try_from(n).ok())
+                .map(Some)
+                .with_context(|| format!("invalid value \"{}\"", v))
+        }
+
+        /// A size that CTL reads with ctl_expand_number, so it may have a suffix like 4k
+        fn size<T: TryFrom<u64>>(v: String) -> Result<Option<T>> {
+            let (n, unit) = integer(&v).with_context(|| format!("invalid value \"{}\"", v))?;
+            let shift = match unit.to_ascii_lowercase().as_str() {
+                "" | "b" => 0,
+                "k" => 10,
+                "m" => 20,
+                "g" => 30,
+                "t" => 40,
+                "p" => 50,
+                "e" => 60,
+                _ => bail!("invalid value \"{}\"", v)
+            };
+            n.checked_mul(1 << shift)
+                .and_then(|n| T::try_from(n).ok())
+                .map(Some)
+                .with_context(|| format!("\"{}\" is too large", v))
+        }
+
+        fn parse<T: FromStr>(v: String) -> Result<Option<T>>
+            where T::Err: std::error::Error + Send + Sync + 'static
+        {
+            v.parse::<T>()
+                .map(Some)
+                .with_context(|| format!("invalid value \"{}\"", v))
+        }
+
+        let opts = self;
+        let r = match k {
+            "vendor" => string(v, 8).map(|v| opts.vendor = v),
+            "product" => string(v, 16).map(|v| opts.product = v),
+            "revision" => string(v, 4).map(|v| opts.revision = v),
+            "scsiname" => string(v, 223).map(|v| opts.scsiname = v),
+            "eui" => hex(v, 16).map(|v| opts.eui = v),
+            "naa" => hex(v, 16).map(|v| opts.naa = v),
+            "uuid" => uuid(v).map(|v| opts.uuid = v),
+            "ident_info" => hex(v, 256).map(|v| opts.ident_info = v),
+            "text_ident_info" => string(v, 256).map(|v| opts.text_ident_info = v),
+            "ha_role" => parse(v).map(|v| opts.ha_role = v),
+            "insecure_tpc" => onoff(v).map(|v| opts.insecure_tpc = v),
+            "readcache" => onoff(v).map(|v| opts.readcache = v),
+            "readonly" => onoff(v).map(|v| opts.readonly = v),
+            "removable" => onoff(v).map(|v| opts.removable = v),
+            "reordering" => parse(v).map(|v| opts.reordering = v),
+            "serseq" => parse(v).map(|v| opts.serseq = v),
+            "pblocksize" => size(v).map(|v| opts.pblocksize = v),
+            "pblockoffset" => size(v).map(|v| opts.pblockoffset = v),
+            "ublocksize" => size(v).map(|v| opts.ublocksize = v),
+            "ublockoffset" => size(v).map(|v| opts.ublockoffset = v),
+            "rpm" => number(v).map(|v| opts.rpm = v),
+            "formfactor" => parse::<u8>(v).and_then(|v| {
+                match v {
+                    Some(ff) if ff > 15 => bail!("formfactor {} is greater than 15", ff),
+                    _ => Ok(v)
+                }
+            }).map(|v| opts.formfactor = v),
+            "temperature" => parse(v).map(|v| opts.temperature = v),
+            "reftemperature" => parse(v).map(|v| opts.reftemperature = v),
+            "provisioning_type" => parse(v).map(|v| opts.provisioning_type = v),
+            "unmap" => onoff(v).map(|v| opts.unmap = v),
+            "unmap_max_lba" => size(v).map(|v| opts.unmap_max_lba = v),
+            "unmap_max_descr" => size(v).map(|v| opts.unmap_max_descr = v),
+            "write_same_max_lba" => size(v).map(|v| opts.write_same_max_lba = v),
+            "avail-threshold" => size(v).map(|v| opts.avail_threshold = v),
+            "used-threshold" => size(v).map(|v| opts.used_threshold = v),
+            "pool-avail-threshold" => size(v).map(|v| opts.pool_avail_threshold = v),
+            "pool-used-threshold" => size(v).map(|v| opts.pool_used_threshold = v),
+            "writecache" => onoff(v).map(|v| opts.writecache = v),
+            "num_threads" => parse(v).map(|v| opts.num_threads = v),
+            "capacity" => size(v).map(|v| opts.capacity = v),
+            "file" | "ctld_name" => {
+                Err(anyhow!("must not be set as an option"))
+            }
+            _ => {
+                opts.unknown.insert(k.to_owned(), v);
+                Ok(())
+            }
+        };
+        r.with_context(|| format!("option {}", k))
+    }
+}
+
+impl TryFrom<HashMap<String, String>> for LunOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(map: HashMap<String, String>) -> Result<Self> {
+        let mut opts = LunOptions::default();
+        for (k, v) in map.into_iter() {
+            opts.set(&k, v)?;
+        }
+        Ok(opts)
+    }
+}
+
+/// One of a target's LUNs: either a reference to a top-level `lun`, or a LUN defined inline with
+/// the same settings as a top-level one.
+#[derive(Clone, Debug)]
+pub struct TargetLun {
+    pub number: u64,
+    pub name: String,
+    /// Was this LUN defined inline?  If so, `name` was generated like `<target>,lun,<number>`.
+    pub inline: bool,
+    /// An inline definition, until Conf::open moves it into `Conf::luns`
+    definition: Option<Box<Lun>>,
+}
+
+impl TargetLun {
+    /// The name ctld(8) gives to a LUN defined inline in a target
+    pub fn inline_name(target: &str, number: u64) -> String {
+        format!("{},lun,{}", target, number)
+    }
+}
+
+impl FromObject<ObjectRef> for TargetLun {
+    fn try_from(value: ObjectRef) -> std::result::Result<Self, ObjectError> {
+        let number = value.lookup("number")
+            .ok_or_else(|| ObjectError::KeyNotFound(String::from("number")))?;
+        let number = <u64 as FromObject<ObjectRef>>::try_from(number)?;
+        let Some(name) = value.lookup("name") else {
+            let definition = <Lun as FromObject<ObjectRef>>::try_from(value)?;
+            return Ok(TargetLun {
+                number,
+                name: String::new(),
+                inline: true,
+                definition: Some(Box::new(definition))
+            });
+        };
+        if let Some(k) = value.iter().filter_map(|o| o.key())
+            .find(|k| k != "number" && k != "name")
+        {
+            return Err(ObjectError::other(format!(
+                "lun {}: \"{}\" is only allowed in inline definitions, not with name", number, k)));
+        }
+        Ok(TargetLun {
+            number,
+            name: <String as FromObject<ObjectRef>>::try_from(name)?,
+            inline: false,
+            definition: None
+        })
+    }
 }
 
 #[derive(Clone, Debug, Uclicious)]
 #[ucl(skip_builder)]
-struct TargetPortalGroup {
-    #[expect(unused)]    // TODO: implement me
-    name: String,
+pub struct TargetPortalGroup {
+    pub name: String,
     #[ucl(default, path = "ag-name")]
-    #[expect(unused)]    // TODO: implement me
     ag_name: Option<String>
 }
 
 #[derive(Clone, Debug, Uclicious)]
 #[ucl(skip_builder)]
-struct Target {
+pub struct Target {
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
-    alias: Option<String>,
-    #[ucl(path = "auth-group")]
-    #[expect(unused)]    // TODO: implement me
-    auth_group: String,
+    pub alias: Option<String>,
+    /// Conf::open fills in the "default" auth-group if the target specifies neither an auth-group
+    /// nor its own authentication settings.
+    #[ucl(path = "auth-group", default)]
+    auth_group: Option<String>,
     #[ucl(path = "auth-type", default, from_str)]
-    #[expect(unused)]    // TODO: implement me
     auth_type: AuthType,
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
     chap: Vec<Chap>,
     #[ucl(default, path = "chap-mutual")]
-    #[expect(unused)]    // TODO: implement me
     chap_mutual: Vec<ChapMutual>,
     #[ucl(default, path = "initiator-name")]
-    #[expect(unused)]    // TODO: implement me
     intiator_name: Option<String>,
     #[ucl(path = "initiator-portal", default)]
-    #[expect(unused)]    // TODO: implement me
     initiator_portal: Vec<String>,
-    #[ucl(path = "portal-group")]
-    #[expect(unused)]    // TODO: implement me
-    portal_group: TargetPortalGroup,
+    /// Conf::open fills in the "default" portal-group if the config file doesn't specify any
+    #[ucl(path = "portal-group", default)]
+    pub portal_groups: Vec<TargetPortalGroup>,
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     port: Option<String>,
     #[ucl(default)]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     redirect: Option<String>,
-    #[expect(unused)]    // TODO: implement me
-    lun: Vec<TargetLun>,
+    pub lun: Vec<TargetLun>,
+}
+
+impl Target {
+    /// Does the target have its own authentication settings, rather than using an auth-group?
+    fn has_own_auth(&self) -> bool {
+        self.auth_type != AuthType::Unknown ||
+            !self.chap.is_empty() ||
+            !self.chap_mutual.is_empty() ||
+            self.intiator_name.is_some() ||
+            !self.initiator_portal.is_empty()
+    }
 }
 
 /// The UCL configuration file format
 #[derive(Debug, Uclicious)]
 pub struct Conf {
-    #[ucl(path = "auth-group")]
+    #[ucl(path = "auth-group", default)]
     auth_groups: HashMap<String, AuthGroup>,
+    /// Log verbosity.  Each `-d` on the command line adds one.
     #[ucl(default = "0")]
-    #[expect(unused)]    // TODO: implement me
-    debug: i32,
+    pub debug: i32,
     #[ucl(default = "30")]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     maxproc: i32,
+    /// Lock file that keeps a second ctld from running at the same time
     #[ucl(default = "PathBuf::from(\"/var/run/ctld.pid\")")]
-    #[expect(unused)]    // TODO: implement me
-    pidfile: PathBuf,
-    #[ucl(path = "portal-group")]
+    pub pidfile: PathBuf,
+    #[ucl(path = "portal-group", default)]
     pub portal_groups: HashMap<String, PortalGroup>,
     #[ucl(path = "lun")]
     pub luns: HashMap<String, Lun>,
     #[ucl(path = "target")]
-    #[expect(unused)]    // TODO: implement me
-    targets: HashMap<String, Target>,
+    pub targets: HashMap<String, Target>,
     #[ucl(default = "60")]
-    #[expect(unused)]    // TODO: implement me
+    // TODO: implement me
     timeout: i32,
     #[ucl(default, path = "isns-server")]
-    #[expect(unused)]    // TODO: implement me
-    isns_server: Vec<SocketAddr>,
+    pub isns_server: Vec<SocketAddr>,
+    /// How often to refresh iSNS registrations, in seconds
     #[ucl(path = "isns-period", default = "900")]
-    #[expect(unused)]    // TODO: implement me
-    isns_period: i32,
+    pub isns_period: i32,
+    /// Timeout for iSNS requests, in seconds
     #[ucl(path = "isns-timeout", default = "5")]
-    #[expect(unused)]    // TODO: implement me
-    isns_timeout: i32
+    pub isns_timeout: i32,
+    /// Where to remember automatically assigned portal-group tags across restarts
+    #[ucl(path = "tag-file", default = "PathBuf::from(\"/var/db/ctld/tags.json\")")]
+    pub tag_file: PathBuf,
+    /// UNIX-domain socket for administrative requests
+    #[ucl(path = "control-socket", default = "PathBuf::from(\"/var/run/ctld.sock\")")]
+    pub control_socket: PathBuf,
+    /// Where to serve Prometheus metrics over HTTP, if anywhere
+    #[ucl(default, path = "metrics-listen")]
+    pub metrics_listen: Option<SocketAddr>
 }
 
 impl Conf {
@@ -273,22 +869,701 @@ impl Conf {
         let mut f = std::fs::File::open(p).context("opening config file")?;
         let mut contents = String::new();
         f.read_to_string(&mut contents).context("reading config file")?;
+        Self::from_ucl(&contents)
+    }
+
+    /// Parse and validate a configuration that has already been read into memory
+    pub fn from_ucl(contents: &str) -> Result<Self> {
         let mut builder = Conf::builder().unwrap();
-        builder.add_chunk_full(&contents, Priority::default(), DEFAULT_DUPLICATE_STRATEGY)
+        builder.add_chunk_full(contents, Priority::default(), DEFAULT_DUPLICATE_STRATEGY)
             .context("parsing config file")?;
-        let conf: Conf = builder.build().map_err(|e| anyhow::Error::msg(format!("{}", e)))?;
+        let mut conf: Conf = builder.build().map_err(|e| anyhow::Error::msg(format!("{}", e)))?;
+        conf.add_defaults();
+        conf.add_inline_luns()?;
         conf.validate()?;
+        conf.load_secrets()?;
         Ok(conf)
     }
 
-    fn validate(&self) -> Result<()> {
-        for (_, ag) in self.auth_groups.iter() {
-            ag.validate()?;
+    /// Read any CHAP secrets that are stored outside of the configuration file.  Since this is
+    /// part of Conf::open, reloading the configuration reads them again.
+    fn load_secrets(&mut self) -> Result<()> {
+        let mut names = self.auth_groups.keys().cloned().collect::<Vec<_>>();
+        names.sort_unstable();
+        for name in names {
+            let ag = self.auth_groups.get_mut(&name).unwrap();
+            load_chap_secrets(&mut ag.chap, &mut ag.chap_mutual)
+                .with_context(|| format!("auth-group \"{}\"", name))?;
+        }
+        let mut names = self.targets.keys().cloned().collect::<Vec<_>>();
+        names.sort_unstable();
+        for name in names {
+            let target = self.targets.get_mut(&name).unwrap();
+            load_chap_secrets(&mut target.chap, &mut target.chap_mutual)
+                .with_context(|| format!("target \"{}\"", name))?;
+        }
+        Ok(())
+    }
+
+    /// Add the predefined auth-groups and portal-group, like ctld(8) does, unless the config file
+    /// defines its own.  Then point objects that don't specify an auth-group or portal-group at
+    /// the defaults.
+    fn add_defaults(&mut self) {
+        let auth_group = |auth_type| AuthGroup {
+            auth_type,
+            chap: Vec::new(),
+            chap_mutual: Vec::new(),
+            intiator_name: None,
+            initiator_portal: Vec::new()
+        };
+        self.auth_groups.entry(DEFAULT_AUTH_GROUP.to_owned())
+            .or_insert_with(|| auth_group(AuthType::Unknown));
+        self.auth_groups.entry(String::from("no-authentication"))
+            .or_insert_with(|| auth_group(AuthType::None));
+        self.auth_groups.entry(String::from("no-access"))
+            .or_insert_with(|| auth_group(AuthType::Deny));
+        self.portal_groups.entry(DEFAULT_PORTAL_GROUP.to_owned())
+            .or_insert_with(|| PortalGroup {
+                discovery_auth_group: None,
+                discovery_filter: DiscoveryFilter::default(),
+                listen: vec![
+                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, ISCSI_PORT)),
+                    SocketAddr::from((Ipv6Addr::UNSPECIFIED, ISCSI_PORT)),
+                ],
+                offload: None,
+                options: HashMap::new(),
+                redirect: None,
+                tag: None,
+                foreign: false,
+                dscp: None,
+                pcp: None
+            });
+
+        for pg in self.portal_groups.values_mut() {
+            pg.discovery_auth_group.get_or_insert_with(|| DEFAULT_AUTH_GROUP.to_owned());
+        }
+        for target in self.targets.values_mut() {
+            if target.auth_group.is_none() && !target.has_own_auth() {
+                target.auth_group = Some(DEFAULT_AUTH_GROUP.to_owned());
+            }
+            if target.portal_groups.is_empty() {
+                target.portal_groups.push(TargetPortalGroup {
+                    name: DEFAULT_PORTAL_GROUP.to_owned(),
+                    ag_name: None
+                });
+            }
+        }
+    }
+
+    /// Move LUNs that are defined inline in targets into `luns`, under generated names, so the
+    /// rest of ctld can treat them like any other LUN.
+    fn add_inline_luns(&mut self) -> Result<()> {
+        for (name, target) in self.targets.iter_mut() {
+            for tl in target.lun.iter_mut() {
+                let Some(definition) = tl.definition.take() else {
+                    continue;
+                };
+                tl.name = TargetLun::inline_name(name, tl.number);
+                if self.luns.contains_key(&tl.name) {
+                    bail!("target \"{}\": lun {}: LUN \"{}\" is already defined",
+                        name, tl.number, tl.name);
+                }
+                self.luns.insert(tl.name.clone(), *definition);
+            }
+        }
+        Ok(())
+    }
+
+    /// Build a configuration that matches what the kernel is currently serving, for example
+    /// after LUNs and ports were created by hand with ctladm(8).
+    ///
+    /// LUNs are named after their ctld_name option, if any, and otherwise after their CTL LUN
+    /// number.  Targets come from the kernel's iSCSI ports.  The kernel doesn't know where its
+    /// portal groups listened, so only the first portal group listens on the wildcard addresses,
+    /// and the others are generated without any listen addresses, which [`Conf::to_ucl`] points
+    /// out with a comment.  Nor does it know what authentication they used, so targets use the
+    /// no-access auth-group until an administrator chooses one.  Options that ctld doesn't
+    /// understand are preserved as raw options.
+    ///
+    /// The predefined auth-groups and portal-group are not added, so the result is suitable for
+    /// [`Conf::to_ucl`].
+    pub fn from_kernel(klun_list: &kconf::Ctllunlist, kport_list: &kconf::Ctlportlist)
+        -> Result<Self>
+    {
+        let mut luns = HashMap::new();
+        let mut lun_names = HashMap::new();
+        for klun in klun_list.lun.iter() {
+            let name = klun.ctld_name.clone().unwrap_or_else(|| format!("lun{}", klun.id));
+            let ctl_lun: u32 = klun.id.try_into()
+                .with_context(|| format!("lun {}: invalid LUN number", klun.id))?;
+            let mut options = klun.options.clone();
+            let raw_options = mem::take(&mut options.unknown).into_iter().collect();
+            let lun = Lun {
+                backend: klun.backend_type,
+                blocksize: Some(klun.blocksize),
+                ctl_lun: Some(ctl_lun),
+                device_id: klun.device_id.clone(),
+                device_type: klun.lun_type,
+                options,
+                raw_options,
+                path: klun.file.as_ref().map(PathBuf::from),
+                serial: Some(klun.serial_number.clone()).filter(|s| !s.is_empty()),
+                size: Some(klun.size * u64::from(klun.blocksize)),
+            };
+            lun_names.insert(klun.id, name.clone());
+            if luns.insert(name.clone(), lun).is_some() {
+                bail!("lun {}: ctld_name \"{}\" is used by more than one LUN", klun.id, name);
+            }
+        }
+
+        let mut pg_tags = BTreeMap::new();
+        let mut targets: HashMap<String, Target> = HashMap::new();
+        for kport in kport_list.targ_port.iter() {
+            if kport.frontend_type != kconf::Frontend::Iscsi {
+                continue;
+            }
+            let Some(target_name) = &kport.cfiscsi_target else {
+                continue;
+            };
+            let tag = kport.cfiscsi_portal_group_tag;
+            let pg_name = match (&kport.ctld_portal_group_name, tag) {
+                (Some(name), _) => name.clone(),
+                (None, Some(tag)) => format!("pg{}", tag),
+                (None, None) => bail!("port {}: has neither a portal group name nor a tag",
+                    kport.id)
+            };
+            pg_tags.entry(pg_name.clone()).or_insert(tag);
+            let tpg = TargetPortalGroup {
+                name: pg_name,
+                ag_name: None
+            };
+            if let Some(target) = targets.get_mut(target_name) {
+                // ctld gives every port of a target the same LUN map
+                target.portal_groups.push(tpg);
+                continue;
+            }
+            let mut lun = Vec::new();
+            for tl in kport.luns.iter() {
+                let name = lun_names.get(&u64::from(tl.lun))
+                    .with_context(|| format!("port {}: LUN {} does not exist", kport.id, tl.lun))?;
+                let number = u64::from(tl.id);
+                lun.push(TargetLun {
+                    number,
+                    inline: *name == TargetLun::inline_name(target_name, number),
+                    name: name.clone(),
+                    definition: None
+                });
+            }
+            targets.insert(target_name.clone(), Target {
+                alias: None,
+                auth_group: Some(String::from("no-access")),
+                auth_type: AuthType::Unknown,
+                chap: Vec::new(),
+                chap_mutual: Vec::new(),
+                intiator_name: None,
+                initiator_portal: Vec::new(),
+                portal_groups: vec![tpg],
+                port: None,
+                redirect: None,
+                lun
+            });
+        }
+
+        let mut portal_groups = HashMap::new();
+        for (i, (name, tag)) in pg_tags.into_iter().enumerate() {
+            // Two portal groups can't both listen on the wildcard addresses
+            let listen = if i == 0 {
+                vec![
+                    SocketAddr::from((Ipv4Addr::UNSPECIFIED, ISCSI_PORT)),
+                    SocketAddr::from((Ipv6Addr::UNSPECIFIED, ISCSI_PORT)),
+                ]
+            } else {
+                crate::warn!("portal-group \"{}\": listen addresses are unknown", name);
+                Vec::new()
+            };
+            portal_groups.insert(name, PortalGroup {
+                discovery_auth_group: None,
+                discovery_filter: DiscoveryFilter::default(),
+                listen,
+                offload: None,
+                options: HashMap::new(),
+                redirect: None,
+                tag,
+                foreign: false,
+                dscp: None,
+                pcp: None
+            });
+        }
+
+        Ok(Conf {
+            auth_groups: HashMap::new(),
+            debug: 0,
+            maxproc: 30,
+            pidfile: PathBuf::from("/var/run/ctld.pid"),
+            portal_groups,
+            luns,
+            targets,
+            timeout: 60,
+            isns_server: Vec::new(),
+            isns_period: 900,
+            isns_timeout: 5,
+            tag_file: PathBuf::from("/var/db/ctld/tags.json"),
+            control_socket: PathBuf::from("/var/run/ctld.sock"),
+            metrics_listen: None
+        })
+    }
+
+    /// Serialize the configuration in the same UCL format that [`Conf::open`] reads.
+    ///
+    /// Inline CHAP secrets are replaced by a placeholder.  Secrets read from a file or command
+    /// are written as their source, never as their value.
+    pub fn to_ucl(&self) -> String {
+        self.write_ucl(UclWriter::default())
+    }
+
+    /// Like [`Conf::to_ucl`], but write inline CHAP secrets as they are.
+    ///
+    /// Only use this when the output must be read back as a working configuration.
+    pub fn to_ucl_with_secrets(&self) -> String {
+        self.write_ucl(UclWriter{secrets: true, ..Default::default()})
+    }
+
+    fn write_ucl(&self, mut w: UclWriter) -> String {
+        w.value("debug", self.debug);
+        w.value("maxproc", self.maxproc);
+        w.string("pidfile", &self.pidfile.to_string_lossy());
+        w.value("timeout", self.timeout);
+        if !self.isns_server.is_empty() {
+            w.strings("isns-server", self.isns_server.iter());
+        }
+        w.value("isns-period", self.isns_period);
+        w.value("isns-timeout", self.isns_timeout);
+        w.string("tag-file", &self.tag_file.to_string_lossy());
+        w.string("control-socket", &self.control_socket.to_string_lossy());
+        if let Some(addr) = &self.metrics_listen {
+            w.string("metrics-listen", &addr.to_string());
+        }
+
+        w.open("auth-group");
+        for (name, ag) in sorted(&self.auth_groups) {
+            w.open(&ucl_quote(name));
+            if ag.auth_type != AuthType::Unknown {
+                w.string("auth-type", ag.auth_type.into());
+            }
+            w.auth(&ag.chap, &ag.chap_mutual, &ag.intiator_name, &ag.initiator_portal);
+            w.close();
+        }
+        w.close();
+
+        w.open("portal-group");
+        for (name, pg) in sorted(&self.portal_groups) {
+            w.open(&ucl_quote(name));
+            if let Some(dag) = &pg.discovery_auth_group {
+                w.string("discovery-auth-group", dag);
+            }
+            if pg.discovery_filter != DiscoveryFilter::None {
+                w.string("discovery-filter", pg.discovery_filter.into());
+            }
+            if pg.listen.is_empty() {
+                w.comment("listen addresses unknown");
+            }
+            w.strings("listen", pg.listen.iter());
+            if let Some(offload) = &pg.offload {
+                w.string("offload", offload);
+            }
+            w.options("option", sorted(&pg.options).into_iter().map(|(k, v)| (k.as_str(), v)));
+            if let Some(redirect) = &pg.redirect {
+                w.string("redirect", redirect);
+            }
+            if let Some(tag) = pg.tag {
+                w.value("tag", tag);
+            }
+            if pg.foreign {
+                w.value("foreign", true);
+            }
+            if let Some(dscp) = pg.dscp {
+                w.value("dscp", dscp);
+            }
+            if let Some(pcp) = pg.pcp {
+                w.value("pcp", pcp);
+            }
+            w.close();
+        }
+        w.close();
+
+        // Inline LUNs are written in their targets instead
+        let inline = self.targets.values()
+            .flat_map(|target| target.lun.iter())
+            .filter(|tl| tl.inline && self.luns.contains_key(&tl.name))
+            .map(|tl| tl.name.as_str())
+            .collect::<HashSet<_>>();
+        w.open("lun");
+        for (name, lun) in sorted(&self.luns) {
+            if inline.contains(name.as_str()) {
+                continue;
+            }
+            w.open(&ucl_quote(name));
+            w.lun(lun);
+            w.close();
+        }
+        w.close();
+
+        w.open("target");
+        for (name, target) in sorted(&self.targets) {
+            w.open(&ucl_quote(name));
+            if let Some(alias) = &target.alias {
+                w.string("alias", alias);
+            }
+            if let Some(ag) = &target.auth_group {
+                w.string("auth-group", ag);
+            }
+            if target.auth_type != AuthType::Unknown {
+                w.string("auth-type", target.auth_type.into());
+            }
+            w.auth(&target.chap, &target.chap_mutual, &target.intiator_name,
+                &target.initiator_portal);
+            match target.portal_groups.as_slice() {
+                [] => (),
+                [tpg] => {
+                    w.open("portal-group");
+                    w.target_portal_group(tpg);
+                    w.close();
+                },
+                tpgs => {
+                    w.open_array("portal-group");
+                    for tpg in tpgs {
+                        w.open_element();
+                        w.target_portal_group(tpg);
+                        w.close_element();
+                    }
+                    w.close_array();
+                }
+            }
+            if let Some(port) = &target.port {
+                w.string("port", port);
+            }
+            if let Some(redirect) = &target.redirect {
+                w.string("redirect", redirect);
+            }
+            w.open_array("lun");
+            for tl in target.lun.iter() {
+                w.open_element();
+                w.value("number", tl.number);
+                match self.luns.get(&tl.name) {
+                    Some(lun) if tl.inline => w.lun(lun),
+                    _ => w.string("name", &tl.name)
+                }
+                w.close_element();
+            }
+            w.close_array();
+            w.close();
+        }
+        w.close();
+        w.buf
+    }
+
+    /// Check the configuration for internal consistency.  Every error names the offending object.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.isns_period <= 0 {
+            bail!("isns-period must be positive");
+        }
+        if self.isns_timeout <= 0 {
+            bail!("isns-timeout must be positive");
+        }
+        for (name, ag) in sorted(&self.auth_groups) {
+            ag.validate().with_context(|| format!("auth-group \"{}\"", name))?;
+            if [AuthType::None, AuthType::Deny].contains(&ag.auth_type) &&
+                (!ag.chap.is_empty() || !ag.chap_mutual.is_empty())
+            {
+                crate::warn!("auth-group \"{}\": chap and chap-mutual are ignored with auth-type \
+                    {}", name, <&str>::from(ag.auth_type));
+            }
+        }
+        for (name, pg) in sorted(&self.portal_groups) {
+            if let Some(dag) = &pg.discovery_auth_group {
+                if !self.auth_groups.contains_key(dag) {
+                    bail!("portal-group \"{}\": discovery-auth-group \"{}\" does not exist",
+                        name, dag);
+                }
+            }
+        }
+        for (name, target) in sorted(&self.targets) {
+            self.validate_target(name, target)?;
+        }
+
+        let luns = sorted(&self.luns);
+        for (name, lun) in luns.iter() {
+            lun.validate().with_context(|| format!("lun \"{}\"", name))?;
+            for key in lun.options.unknown.keys() {
+                crate::warn!("lun \"{}\": unknown option \"{}\".  Use raw-option if it is \
+                    intentional.", name, key);
+            }
+        }
+        check_unique("ctl-lun", luns.iter().filter_map(|(name, lun)| {
+            lun.ctl_lun.map(|id| (name.as_str(), id))
+        }))?;
+        check_unique("device-id", luns.iter().map(|(name, lun)| {
+            (name.as_str(), lun.device_id.as_str())
+        }))?;
+        check_unique("serial", luns.iter().filter_map(|(name, lun)| {
+            lun.serial.as_deref().map(|serial| (name.as_str(), serial))
+        }))?;
+        check_unique("path", luns.iter().filter_map(|(name, lun)| {
+            lun.path.as_ref().map(|path| (name.as_str(), path.display().to_string()))
+        }))?;
+
+        let pgs = sorted(&self.portal_groups);
+        check_unique("tag", pgs.iter().filter_map(|(name, pg)| {
+            pg.tag.map(|tag| (name.as_str(), tag))
+        }))?;
+        Ok(())
+    }
+
+    fn validate_target(&self, name: &str, target: &Target) -> Result<()> {
+        if let Some(ag) = &target.auth_group {
+            if target.has_own_auth() {
+                bail!("target \"{}\": cannot use both auth-group and its own authentication settings",
+                    name);
+            }
+            if !self.auth_groups.contains_key(ag) {
+                bail!("target \"{}\": auth-group \"{}\" does not exist", name, ag);
+            }
+        }
+        let mut pg_names = HashSet::new();
+        for tpg in target.portal_groups.iter() {
+            if !pg_names.insert(&tpg.name) {
+                bail!("target \"{}\": portal-group \"{}\" is listed twice", name, tpg.name);
+            }
+            if !self.portal_groups.contains_key(&tpg.name) {
+                bail!("target \"{}\": portal-group \"{}\" does not exist", name, tpg.name);
+            }
+            if let Some(ag_name) = &tpg.ag_name {
+                if !self.auth_groups.contains_key(ag_name) {
+                    bail!("target \"{}\": portal-group \"{}\": auth-group \"{}\" does not exist",
+                        name, tpg.name, ag_name);
+                }
+            }
+        }
+        let mut numbers = HashMap::new();
+        let mut lun_names = HashMap::new();
+        for tlun in target.lun.iter() {
+            if !self.luns.contains_key(&tlun.name) {
+                bail!("target \"{}\": lun {}: LUN \"{}\" does not exist",
+                    name, tlun.number, tlun.name);
+            }
+            if let Some(prev) = numbers.insert(tlun.number, &tlun.name) {
+                bail!("target \"{}\": lun {} is mapped to both \"{}\" and \"{}\"",
+                    name, tlun.number, prev, tlun.name);
+            }
+            if let Some(prev) = lun_names.insert(&tlun.name, tlun.number) {
+                bail!("target \"{}\": LUN \"{}\" is mapped twice, as lun {} and lun {}",
+                    name, tlun.name, prev, tlun.number);
+            }
         }
         Ok(())
     }
 }
 
+/// Quote a string for UCL, escaping anything that UCL would otherwise interpret.
+fn ucl_quote(s: &str) -> String {
+    let mut q = String::with_capacity(s.len() + 2);
+    q.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => q.push_str("\\\""),
+            '\\' => q.push_str("\\\\"),
+            '\n' => q.push_str("\\n"),
+            '\t' => q.push_str("\\t"),
+            c => q.push(c)
+        }
+    }
+    q.push('"');
+    q
+}
+
+/// Helper for [`Conf::to_ucl`] that keeps track of indentation.
+#[derive(Default)]
+struct UclWriter {
+    buf: String,
+    depth: usize,
+    /// Write inline secrets instead of a placeholder
+    secrets: bool
+}
+
+impl UclWriter {
+    fn line(&mut self, s: impl Display) {
+        writeln!(self.buf, "{:indent$}{}", "", s, indent = 4 * self.depth).unwrap();
+    }
+
+    /// Write the body of a LUN definition
+    fn lun(&mut self, lun: &Lun) {
+        self.string("backend", lun.backend.into());
+        if let Some(blocksize) = lun.blocksize {
+            self.value("blocksize", blocksize);
+        }
+        if let Some(ctl_lun) = lun.ctl_lun {
+            self.value("ctl_lun", ctl_lun);
+        }
+        self.string("device-id", &lun.device_id);
+        self.string("device-type", lun.device_type.into());
+        let mut options = lun.options.to_kernel();
+        options.sort_unstable();
+        self.options("option", options.iter().map(|(k, v)| (*k, v)));
+        self.options("raw-option",
+            sorted(&lun.raw_options).into_iter().map(|(k, v)| (k.as_str(), v)));
+        if let Some(path) = &lun.path {
+            self.string("path", &path.to_string_lossy());
+        }
+        if let Some(serial) = &lun.serial {
+            self.string("serial", serial);
+        }
+        if let Some(size) = lun.size {
+            self.value("size", size);
+        }
+    }
+
+    /// Write the body of one of a target's portal-group entries
+    fn target_portal_group(&mut self, tpg: &TargetPortalGroup) {
+        self.string("name", &tpg.name);
+        if let Some(ag_name) = &tpg.ag_name {
+            self.string("ag-name", ag_name);
+        }
+    }
+
+    fn comment(&mut self, s: &str) {
+        self.line(format_args!("# {}", s));
+    }
+
+    fn value(&mut self, key: &str, v: impl Display) {
+        self.line(format_args!("{} = {}", key, v));
+    }
+
+    fn string(&mut self, key: &str, v: &str) {
+        self.value(key, ucl_quote(v));
+    }
+
+    fn strings<T: Display>(&mut self, key: &str, v: impl Iterator<Item = T>) {
+        let v = v.map(|s| ucl_quote(&s.to_string())).collect::<Vec<_>>();
+        self.value(key, format_args!("[{}]", v.join(", ")));
+    }
+
+    /// Write a block of string options, unless there aren't any.
+    fn options<'a>(&mut self, key: &str, mut opts: impl Iterator<Item = (&'a str, &'a String)>) {
+        let Some(first) = opts.next() else {
+            return;
+        };
+        self.open(key);
+        for (k, v) in std::iter::once(first).chain(opts) {
+            self.string(k, v);
+        }
+        self.close();
+    }
+
+    /// Write the authentication settings shared by auth-groups and targets
+    fn auth(&mut self, chap: &[Chap], chap_mutual: &[ChapMutual],
+        initiator_name: &Option<String>, initiator_portal: &[String])
+    {
+        if !chap.is_empty() {
+            self.open_array("chap");
+            for c in chap {
+                self.open_element();
+                self.string("user", &c.user);
+                self.secret("secret", &c.secret, &c.secret_file, &c.secret_command);
+                self.close_element();
+            }
+            self.close_array();
+        }
+        if !chap_mutual.is_empty() {
+            self.open_array("chap-mutual");
+            for c in chap_mutual {
+                self.open_element();
+                self.string("user", &c.user);
+                self.secret("secret", &c.secret, &c.secret_file, &c.secret_command);
+                self.string("mutual-user", &c.mutual_user);
+                self.secret("mutual-secret", &c.mutual_secret, &c.mutual_secret_file,
+                    &c.mutual_secret_command);
+                self.close_element();
+            }
+            self.close_array();
+        }
+        if let Some(initiator_name) = initiator_name {
+            self.string("initiator-name", initiator_name);
+        }
+        if !initiator_portal.is_empty() {
+            self.strings("initiator-portal", initiator_portal.iter());
+        }
+    }
+
+    /// Write a secret the way the config file specified it, rather than its loaded value.
+    fn secret(&mut self, key: &str, secret: &Option<Secret>, file: &Option<PathBuf>,
+        command: &Option<String>)
+    {
+        if let Some(file) = file {
+            self.string(&format!("{}-file", key), &file.to_string_lossy());
+        } else if let Some(command) = command {
+            self.string(&format!("{}-command", key), command);
+        } else if let Some(secret) = secret {
+            if self.secrets {
+                self.string(key, secret.expose());
+            } else {
+                self.string(key, crate::secret::REDACTED);
+            }
+        }
+    }
+
+    fn open(&mut self, key: &str) {
+        self.line(format_args!("{} {{", key));
+        self.depth += 1;
+    }
+
+    fn close(&mut self) {
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn open_array(&mut self, key: &str) {
+        self.line(format_args!("{} = [", key));
+        self.depth += 1;
+    }
+
+    fn close_array(&mut self) {
+        self.depth -= 1;
+        self.line("]");
+    }
+
+    fn open_element(&mut self) {
+        self.line("{");
+        self.depth += 1;
+    }
+
+    fn close_element(&mut self) {
+        self.depth -= 1;
+        self.line("},");
+    }
+}
+
+/// Iterate over a map in a stable order, so errors and output are reproducible.
+pub(crate) fn sorted<V>(map: &HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut v = map.iter().collect::<Vec<_>>();
+    v.sort_unstable_by_key(|(k, _)| *k);
+    v
+}
+
+/// Check that no two named objects share the same value for some property.
+fn check_unique<'a, K, I>(what: &str, items: I) -> Result<()>
+    where K: Display + Eq + Hash,
+          I: IntoIterator<Item = (&'a str, K)>
+{
+    let mut seen = HashMap::new();
+    for (name, k) in items {
+        if let Some(prev) = seen.get(&k) {
+            bail!("{} {} is used by both \"{}\" and \"{}\"", what, k, prev, name);
+        }
+        seen.insert(k, name);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod t {
     use super::*;
@@ -320,4 +1595,1118 @@ target {
 }").unwrap();
         Conf::open(f.path()).unwrap_err();
     }
+
+    /// auth-type none and deny ignore CHAP credentials, but having them isn't an error
+    #[test]
+    fn chap_and_auth_type_none() {
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(b"
+auth-group ag0 {
+    auth-type = none
+    chap = [{
+        user = foo
+        secret = bar
+    }]
+}
+lun {}
+target {}").unwrap();
+        let conf = Conf::open(f.path()).unwrap();
+        assert_eq!(conf.auth_groups["ag0"].auth_type, AuthType::None);
+    }
+
+    /// CHAP secrets must not leak into debug logs
+    #[test]
+    fn debug_redacts_secrets() {
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(b"
+auth-group ag0 {
+    chap-mutual = [{
+        user = foo
+        secret = topsecret1
+        mutual-user = \"mutualfoo\"
+        mutual-secret = \"topsecret2\"
+    }]
+}
+auth-group ag1 {
+    chap = [{
+        user = foo
+        secret = topsecret3
+    }]
+}
+lun {}
+target {}").unwrap();
+        let conf = Conf::open(f.path()).unwrap();
+        let s = format!("{:?}", conf);
+        assert!(s.contains("mutualfoo"));
+        assert!(!s.contains("topsecret"), "{}", s);
+    }
+
+    mod lun_parse {
+        use super::*;
+
+        #[test]
+        fn json() {
+            let lun = Lun::parse("disk0",
+                r#"{"backend": "ramdisk", "device-id": "dev0", "size": 1048576}"#).unwrap();
+            assert_eq!(lun.backend, Backend::Ramdisk);
+            assert_eq!(lun.device_id, "dev0");
+            assert_eq!(lun.size, Some(1 << 20));
+        }
+
+        #[test]
+        fn ucl() {
+            let e = Lun::parse("disk0", "{ backend = ramdisk\n device-id = dev0\n size = 1048576 }")
+                .unwrap_err();
+            assert_eq!(e.to_string(), "LUN definition must be a JSON object");
+        }
+
+        /// A definition can't escape its lun block
+        #[test]
+        fn injection() {
+            let def = r#"{"backend": "ramdisk", "device-id": "dev0", "size": 1048576}
+}
+.include "/etc/master.passwd"
+lun { x {"#;
+            Lun::parse("disk0", def).unwrap_err();
+            let def = r#"{"backend": "ramdisk", "device-id": "} .include \"/etc/master.passwd\"",
+                "size": 1048576}"#;
+            let lun = Lun::parse("disk0", def).unwrap();
+            assert_eq!(lun.device_id, "} .include \"/etc/master.passwd\"");
+        }
+
+        /// The definition is validated like any other LUN
+        #[test]
+        fn invalid() {
+            let e = Lun::parse("disk0", r#"{"backend": "ramdisk", "device-id": "dev0"}"#)
+                .unwrap_err();
+            assert_eq!(format!("{:#}", e), "lun \"disk0\": ramdisk-backed LUNs must have a size");
+        }
+    }
+
+    mod inline_lun {
+        use super::*;
+
+        const CONF: &str = "
+lun {
+    disk1 {
+        backend = ramdisk
+        device-id = dev1
+        size = 1048576
+    }
+}
+target {
+    \"iqn.2018-10.org.example:t0\" {
+        lun = [
+            { number = 0, backend = ramdisk, device-id = dev0, size = 2097152 },
+            { number = 1, name = disk1 },
+        ]
+    }
+}
+";
+
+        /// Inline LUNs are moved into Conf::luns, under generated names
+        #[test]
+        fn parse() {
+            let conf = Conf::from_ucl(CONF).unwrap();
+            let name = "iqn.2018-10.org.example:t0,lun,0";
+            assert_eq!(conf.luns.len(), 2);
+            assert_eq!(conf.luns[name].device_id, "dev0");
+            assert_eq!(conf.luns[name].size, Some(2097152));
+            let t0 = &conf.targets["iqn.2018-10.org.example:t0"];
+            assert_eq!(t0.lun[0].name, name);
+            assert!(t0.lun[0].inline);
+            assert_eq!(t0.lun[1].name, "disk1");
+            assert!(!t0.lun[1].inline);
+        }
+
+        /// Inline LUNs are written back inline
+        #[test]
+        fn round_trip() {
+            let conf = Conf::from_ucl(CONF).unwrap();
+            let ucl = conf.to_ucl();
+            assert!(!ucl.contains("\"iqn.2018-10.org.example:t0,lun,0\""), "{}", ucl);
+            let conf2 = Conf::from_ucl(&ucl).unwrap();
+            assert_eq!(conf2.to_ucl(), ucl);
+            assert_eq!(conf2.luns["iqn.2018-10.org.example:t0,lun,0"].device_id, "dev0");
+        }
+
+        #[test]
+        fn name_and_definition() {
+            let e = Conf::from_ucl(&CONF.replace("name = disk1", "name = disk1, size = 512"))
+                .unwrap_err();
+            assert!(e.to_string().contains("\"size\" is only allowed in inline definitions"),
+                "{}", e);
+        }
+
+        /// A generated name may not collide with a top-level LUN
+        #[test]
+        fn name_conflict() {
+            let e = Conf::from_ucl(&CONF.replace("disk1", "\"iqn.2018-10.org.example:t0,lun,0\""))
+                .unwrap_err();
+            assert_eq!(e.to_string(), "target \"iqn.2018-10.org.example:t0\": lun 0: LUN \
+                \"iqn.2018-10.org.example:t0,lun,0\" is already defined");
+        }
+
+        /// A kernel LUN whose ctld_name was generated for an inline LUN is exported inline
+        #[test]
+        fn from_kernel() {
+            let klun_list = kconf::Ctllunlist::from_xml(
+"<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<device_id>dev0</device_id>
+	<ctld_name>iqn.2018-10.org.example:t0,lun,0</ctld_name>
+</lun>
+</ctllunlist>").unwrap();
+            let kport_list = kconf::Ctlportlist::from_xml(
+"<ctlportlist>
+<targ_port id=\"3\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">0</lun>
+</targ_port>
+</ctlportlist>").unwrap();
+            let conf = Conf::from_kernel(&klun_list, &kport_list).unwrap();
+            assert!(conf.targets["iqn.2018-10.org.example:t0"].lun[0].inline);
+            let ucl = conf.to_ucl();
+            assert!(ucl.contains("device-id = \"dev0\""), "{}", ucl);
+            assert!(!ucl.contains("name = \"iqn.2018-10.org.example:t0,lun,0\""), "{}", ucl);
+            let conf2 = Conf::from_ucl(&ucl).unwrap();
+            assert_eq!(conf2.luns["iqn.2018-10.org.example:t0,lun,0"].size, Some(2048 * 512));
+        }
+
+        /// Inline definitions are validated like top-level ones
+        #[test]
+        fn invalid() {
+            let e = Conf::from_ucl(&CONF.replace("size = 2097152", "size = 1000")).unwrap_err();
+            assert_eq!(format!("{:#}", e), "lun \"iqn.2018-10.org.example:t0,lun,0\": size 1000 \
+                is not a multiple of the blocksize 512");
+        }
+    }
+
+    mod secret_sources {
+        use super::*;
+
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        fn open(auth_group: &str) -> Result<Conf> {
+            let mut f = NamedTempFile::new().unwrap();
+            write!(f, "auth-group ag0 {{\n{}\n}}\nlun {{}}\ntarget {{}}", auth_group).unwrap();
+            Conf::open(f.path())
+        }
+
+        fn secret_file(contents: &str, mode: u32) -> NamedTempFile {
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(contents.as_bytes()).unwrap();
+            fs::set_permissions(f.path(), fs::Permissions::from_mode(mode)).unwrap();
+            f
+        }
+
+        #[test]
+        fn file() {
+            let sf = secret_file("topsecret\n", 0o600);
+            let conf = open(&format!("chap = [{{ user = foo, secret-file = \"{}\" }}]",
+                sf.path().display())).unwrap();
+            let chap = &conf.auth_groups["ag0"].chap[0];
+            assert_eq!(chap.secret.as_ref().unwrap(), "topsecret");
+            // Writing the configuration back out should not reveal the secret
+            let ucl = conf.to_ucl();
+            assert!(ucl.contains(&format!("secret-file = \"{}\"", sf.path().display())), "{}",
+                ucl);
+            assert!(!ucl.contains("topsecret"), "{}", ucl);
+        }
+
+        #[test]
+        fn file_insecure() {
+            let sf = secret_file("topsecret\n", 0o644);
+            let e = open(&format!("chap = [{{ user = foo, secret-file = \"{}\" }}]",
+                sf.path().display())).unwrap_err();
+            let e = format!("{:#}", e);
+            assert!(e.starts_with("auth-group \"ag0\": chap user \"foo\": secret-file: "), "{}", e);
+            assert!(e.ends_with("must not be accessible by group or other (mode is 644)"), "{}", e);
+        }
+
+        #[test]
+        fn command() {
+            let conf = open("chap-mutual = [{
+                user = foo
+                secret-command = \"echo topsecret1\"
+                mutual-user = bar
+                mutual-secret-command = \"echo topsecret2\"
+            }]").unwrap();
+            let chap = &conf.auth_groups["ag0"].chap_mutual[0];
+            assert_eq!(chap.secret.as_ref().unwrap(), "topsecret1");
+            assert_eq!(chap.mutual_secret.as_ref().unwrap(), "topsecret2");
+        }
+
+        #[test]
+        fn both() {
+            let e = open("chap = [{ user = foo, secret = bar, secret-command = \"echo baz\" }]")
+                .unwrap_err();
+            assert_eq!(format!("{:#}", e), "auth-group \"ag0\": chap user \"foo\": only one of \
+                secret, secret-file, or secret-command may be set");
+        }
+
+        #[test]
+        fn neither() {
+            let e = open("chap-mutual = [{ user = foo, secret = bar, mutual-user = baz }]")
+                .unwrap_err();
+            assert_eq!(format!("{:#}", e), "auth-group \"ag0\": chap-mutual user \"foo\": one \
+                of mutual-secret, mutual-secret-file, or mutual-secret-command is required");
+        }
+    }
+
+    mod backing_size {
+        use super::*;
+
+        use std::sync::Mutex;
+
+        /// Serialize ioc::diocgmediasize calls and expectations
+        static DIOCGMEDIASIZE_MTX: Mutex<()> = Mutex::new(());
+
+        fn lun(backend: Backend, path: Option<&Path>) -> Lun {
+            Lun {
+                backend,
+                blocksize: None,
+                ctl_lun: None,
+                device_id: String::from("dev0"),
+                device_type: DeviceType::Disk,
+                options: Default::default(),
+                raw_options: Default::default(),
+                path: path.map(Path::to_owned),
+                serial: None,
+                size: None
+            }
+        }
+
+        #[test]
+        fn ramdisk() {
+            assert_eq!(lun(Backend::Ramdisk, None).backing_size().unwrap(), None);
+        }
+
+        #[test]
+        fn file() {
+            let f = NamedTempFile::new().unwrap();
+            f.as_file().set_len(12345).unwrap();
+            assert_eq!(lun(Backend::Block, Some(f.path())).backing_size().unwrap(), Some(12345));
+        }
+
+        /// Devices' sizes come from DIOCGMEDIASIZE
+        #[test]
+        fn device() {
+            let _m = DIOCGMEDIASIZE_MTX.lock().unwrap();
+            let ctx = ioc::diocgmediasize_context();
+            ctx.expect()
+                .returning(|_fd, mediasize| {
+                    unsafe{ *mediasize = 1 << 30 };
+                    Ok(0)
+                });
+            let size = lun(Backend::Block, Some(Path::new("/dev/null"))).backing_size().unwrap();
+            assert_eq!(size, Some(1 << 30));
+        }
+    }
+
+    mod defaults {
+        use super::*;
+
+        fn open(contents: &str) -> Conf {
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(contents.as_bytes()).unwrap();
+            Conf::open(f.path()).unwrap()
+        }
+
+        /// The predefined groups exist even if the config file doesn't mention them
+        #[test]
+        fn predefined() {
+            let conf = open("lun {}\ntarget {}");
+            assert_eq!(conf.auth_groups["default"].auth_type, AuthType::Unknown);
+            assert_eq!(conf.auth_groups["no-authentication"].auth_type, AuthType::None);
+            assert_eq!(conf.auth_groups["no-access"].auth_type, AuthType::Deny);
+            let pg = &conf.portal_groups["default"];
+            assert_eq!(pg.listen, vec![
+                "0.0.0.0:3260".parse::<SocketAddr>().unwrap(),
+                "[::]:3260".parse::<SocketAddr>().unwrap()
+            ]);
+            assert_eq!(pg.discovery_auth_group.as_deref(), Some("default"));
+        }
+
+        /// The config file may redefine the predefined groups
+        #[test]
+        fn overridden() {
+            let conf = open("
+auth-group {
+    no-authentication {
+        auth-type = deny
+    }
+}
+portal-group {
+    default {
+        discovery-auth-group = no-access
+        listen = \"192.0.2.1:3260\"
+    }
+}
+lun {}
+target {}");
+            assert_eq!(conf.auth_groups["no-authentication"].auth_type, AuthType::Deny);
+            let pg = &conf.portal_groups["default"];
+            assert_eq!(pg.listen, vec!["192.0.2.1:3260".parse::<SocketAddr>().unwrap()]);
+            assert_eq!(pg.discovery_auth_group.as_deref(), Some("no-access"));
+        }
+
+        /// Targets that don't specify an auth-group or portal-group use the defaults
+        #[test]
+        fn target() {
+            let conf = open("
+lun {
+    disk0 {
+        backend = ramdisk
+        device-id = dev0
+        size = 1048576
+    }
+}
+target {
+    t0 {
+        lun = [ { number = 0, name = disk0 } ]
+    }
+    t1 {
+        auth-type = none
+        lun = [ { number = 0, name = disk0 } ]
+    }
+}");
+            let t0 = &conf.targets["t0"];
+            assert_eq!(t0.auth_group.as_deref(), Some("default"));
+            assert_eq!(t0.portal_groups[0].name, "default");
+            // A target with its own authentication settings doesn't need an auth-group
+            let t1 = &conf.targets["t1"];
+            assert_eq!(t1.auth_group, None);
+        }
+    }
+
+    mod lun_options {
+        use super::*;
+
+        fn parse(opts: &[(&str, &str)]) -> Result<LunOptions> {
+            let map = opts.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<HashMap<_, _>>();
+            LunOptions::try_from(map)
+        }
+
+        fn check_err(opts: &[(&str, &str)], expected: &str) {
+            let e = format!("{:#}", parse(opts).unwrap_err());
+            assert!(e.contains(expected), "Error {:?} does not contain {:?}", e, expected);
+        }
+
+        /// Every option from kconf's example LUN list should parse
+        #[test]
+        fn all() {
+            let opts = [
+                ("vendor", "foo"), ("product", "bar"), ("revision", "0123"), ("scsiname", "baz"),
+                ("eui", "0xdeadbeef"), ("naa", "0x1a7ebabe"),
+                ("uuid", "2dec855d-895c-40a1-8e98-8cba77d79777"), ("ident_info", "0x8888"),
+                ("text_ident_info", "eighteighteighteight"), ("ha_role", "primary"),
+                ("insecure_tpc", "on"), ("readcache", "off"), ("readonly", "on"),
+                ("removable", "on"), ("reordering", "unrestricted"), ("serseq", "on"),
+                ("pblocksize", "4096"), ("pblockoffset", "512"), ("ublocksize", "131072"),
+                ("ublockoffset", "0"), ("rpm", "7200"), ("formfactor", "2"),
+                ("temperature", "75"), ("reftemperature", "70"), ("provisioning_type", "thin"),
+                ("unmap", "on"), ("unmap_max_lba", "1048576"), ("write_same_max_lba", "1048576"),
+                ("avail-threshold", "20"), ("used-threshold", "81"),
+                ("pool-avail-threshold", "22"), ("pool-used-threshold", "83"),
+                ("writecache", "off"),
+            ];
+            let lo = parse(&opts).unwrap();
+            assert_eq!(lo.vendor.as_deref(), Some("foo"));
+            assert_eq!(lo.ha_role, Some(HaRole::Primary));
+            assert_eq!(lo.readcache, Some(false));
+            assert_eq!(lo.readonly, Some(true));
+            assert_eq!(lo.reordering, Some(Reordering::Unrestricted));
+            assert_eq!(lo.serseq, Some(Serseq::On));
+            assert_eq!(lo.rpm, Some(7200));
+            assert_eq!(lo.provisioning_type, Some(ProvisioningType::Thin));
+            assert_eq!(lo.pool_used_threshold, Some(83));
+            assert!(lo.unknown.is_empty());
+
+            // Every option should be passed to the kernel exactly as it was written
+            let mut kernel = lo.to_kernel().into_iter()
+                .map(|(k, v)| (k.to_owned(), v))
+                .collect::<Vec<_>>();
+            kernel.sort();
+            let mut expected = opts.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>();
+            expected.sort();
+            assert_eq!(kernel, expected);
+        }
+
+        #[test]
+        fn bad_bool() {
+            check_err(&[("readonly", "yes")], "option readonly: \"yes\" must be either");
+        }
+
+        #[test]
+        fn bad_enum() {
+            check_err(&[("provisioning_type", "thick")], "option provisioning_type");
+        }
+
+        #[test]
+        fn bad_number() {
+            check_err(&[("rpm", "fast")], "option rpm: invalid value \"fast\"");
+        }
+
+        /// Sizes accept the same suffixes as the kernel's ctl_expand_number
+        #[test]
+        fn size_suffix() {
+            let lo = parse(&[("pblocksize", "4k"), ("capacity", "2G"), ("ublocksize", "0x10000"),
+                ("unmap_max_lba", "1048576b")]).unwrap();
+            assert_eq!(lo.pblocksize, Some(4096));
+            assert_eq!(lo.capacity, Some(2 << 30));
+            assert_eq!(lo.ublocksize, Some(65536));
+            assert_eq!(lo.unmap_max_lba, Some(1048576));
+        }
+
+        #[test]
+        fn bad_size() {
+            check_err(&[("capacity", "4q")], "option capacity: invalid value \"4q\"");
+            check_err(&[("capacity", "k")], "option capacity: invalid value \"k\"");
+            check_err(&[("pblocksize", "4g")], "option pblocksize: \"4g\" is too large");
+            check_err(&[("capacity", "16e")], "option capacity: \"16e\" is too large");
+        }
+
+        /// Like the kernel, rpm may be written in hex
+        #[test]
+        fn hex_rpm() {
+            assert_eq!(parse(&[("rpm", "0x1c20")]).unwrap().rpm, Some(7200));
+            assert_eq!(parse(&[("rpm", "1")]).unwrap().rpm, Some(1));
+        }
+
+        #[test]
+        fn provisioning_type_unknown() {
+            let lo = parse(&[("provisioning_type", "unknown")]).unwrap();
+            assert_eq!(lo.provisioning_type, Some(ProvisioningType::Unknown));
+            assert!(lo.unknown.is_empty());
+        }
+
+        #[test]
+        fn number_out_of_range() {
+            check_err(&[("rpm", "65536")], "option rpm");
+            check_err(&[("formfactor", "16")], "formfactor 16 is greater than 15");
+        }
+
+        #[test]
+        fn bad_hex() {
+            check_err(&[("naa", "0xnothex")], "option naa: \"0xnothex\" is not a hexadecimal number");
+        }
+
+        #[test]
+        fn bad_uuid() {
+            check_err(&[("uuid", "2dec855d-895c-40a1-8e98")], "is not a valid UUID");
+        }
+
+        #[test]
+        fn too_long() {
+            check_err(&[("vendor", "ninechars")], "option vendor: \"ninechars\" is longer than 8 bytes");
+        }
+
+        /// file and ctld_name are set from the LUN's own fields
+        #[test]
+        fn reserved() {
+            check_err(&[("file", "/tmp/foo")], "option file: must not be set as an option");
+        }
+
+        /// Unknown options are kept, so they can still reach the kernel
+        #[test]
+        fn unknown() {
+            let lo = parse(&[("readcahce", "on")]).unwrap();
+            assert_eq!(lo.unknown.get("readcahce").map(String::as_str), Some("on"));
+            assert_eq!(lo.to_kernel(), vec![("readcahce", String::from("on"))]);
+        }
+
+        /// Options are parsed from the configuration file's option block
+        #[test]
+        fn from_conf() {
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(b"
+lun {
+    disk0 {
+        backend = ramdisk
+        device-id = dev0
+        size = 1048576
+        option {
+            vendor = \"FreeBSD\"
+            readonly = \"on\"
+        }
+        raw-option {
+            brand_new_option = \"42\"
+        }
+    }
+}
+target {}").unwrap();
+            let conf = Conf::open(f.path()).unwrap();
+            let lun = &conf.luns["disk0"];
+            assert_eq!(lun.options.vendor.as_deref(), Some("FreeBSD"));
+            assert_eq!(lun.options.readonly, Some(true));
+            assert_eq!(lun.raw_options["brand_new_option"], "42");
+        }
+
+        /// Options in the option block are rejected when they have an invalid value
+        #[test]
+        fn from_conf_invalid() {
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(b"
+lun {
+    disk0 {
+        backend = ramdisk
+        device-id = dev0
+        size = 1048576
+        option {
+            readonly = \"maybe\"
+        }
+    }
+}
+target {}").unwrap();
+            Conf::open(f.path()).unwrap_err();
+        }
+
+        /// An option may not be set both ways
+        #[test]
+        fn raw_and_typed() {
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(b"
+lun {
+    disk0 {
+        backend = ramdisk
+        device-id = dev0
+        size = 1048576
+        option {
+            vendor = \"FreeBSD\"
+        }
+        raw-option {
+            vendor = \"Other\"
+        }
+    }
+}
+target {}").unwrap();
+            let e = format!("{:#}", Conf::open(f.path()).unwrap_err());
+            assert!(e.contains("lun \"disk0\": option vendor is set by both option and raw-option"),
+                "{}", e);
+        }
+    }
+
+    mod ucl {
+        use super::*;
+
+        fn reopen(conf: &Conf) -> Conf {
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(conf.to_ucl_with_secrets().as_bytes()).unwrap();
+            Conf::open(f.path()).unwrap()
+        }
+
+        #[test]
+        fn quote() {
+            assert_eq!(ucl_quote("foo"), "\"foo\"");
+            assert_eq!(ucl_quote("a \"b\" \\c"), "\"a \\\"b\\\" \\\\c\"");
+        }
+
+        /// Kernel state created by ctladm should round-trip through the UCL format
+        #[test]
+        fn from_kernel() {
+            let klun_list = kconf::Ctllunlist::from_xml(
+"<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>MYSERIAL0</serial_number>
+	<device_id>MYDEVID0</device_id>
+	<ctld_name>disk0</ctld_name>
+	<vendor>FreeBSD</vendor>
+	<readonly>on</readonly>
+	<brand_new_option>42</brand_new_option>
+</lun>
+<lun id=\"3\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>5</lun_type>
+	<size>1024</size>
+	<blocksize>2048</blocksize>
+	<serial_number>MYSERIAL3</serial_number>
+	<device_id>MYDEVID3</device_id>
+</lun>
+</ctllunlist>").unwrap();
+            let kport_list = kconf::Ctlportlist::from_xml(
+"<ctlportlist>
+<targ_port id=\"0\">
+	<frontend_type>camsim</frontend_type>
+	<port_type>8</port_type>
+	<online>NO</online>
+	<port_name>camsim</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+</targ_port>
+<targ_port id=\"3\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_state>1</cfiscsi_state>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<ctld_portal_group_name>pg0</ctld_portal_group_name>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">3</lun>
+	<lun id=\"1\">0</lun>
+</targ_port>
+<targ_port id=\"4\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t1</cfiscsi_target>
+	<cfiscsi_state>1</cfiscsi_state>
+	<cfiscsi_portal_group_tag>258</cfiscsi_portal_group_tag>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">0</lun>
+</targ_port>
+<targ_port id=\"5\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_state>1</cfiscsi_state>
+	<cfiscsi_portal_group_tag>258</cfiscsi_portal_group_tag>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">3</lun>
+	<lun id=\"1\">0</lun>
+</targ_port>
+</ctlportlist>").unwrap();
+            let conf = Conf::from_kernel(&klun_list, &kport_list).unwrap();
+            let ucl = conf.to_ucl();
+            assert!(ucl.contains("\"pg258\" {\n        # listen addresses unknown\n"), "{}", ucl);
+            let conf = reopen(&conf);
+
+            assert_eq!(conf.luns.len(), 2);
+            let disk0 = &conf.luns["disk0"];
+            assert_eq!(disk0.backend, Backend::Ramdisk);
+            assert_eq!(disk0.ctl_lun, Some(0));
+            assert_eq!(disk0.size, Some(2048 * 512));
+            assert_eq!(disk0.serial.as_deref(), Some("MYSERIAL0"));
+            assert_eq!(disk0.options.vendor.as_deref(), Some("FreeBSD"));
+            assert_eq!(disk0.options.readonly, Some(true));
+            assert!(disk0.options.unknown.is_empty());
+            assert_eq!(disk0.raw_options["brand_new_option"], "42");
+            let lun3 = &conf.luns["lun3"];
+            assert_eq!(lun3.ctl_lun, Some(3));
+            assert_eq!(lun3.device_type, DeviceType::Cd);
+            assert_eq!(lun3.blocksize, Some(2048));
+
+            assert_eq!(conf.portal_groups["pg0"].tag, Some(257));
+            assert_eq!(conf.portal_groups["pg0"].listen.len(), 2);
+            assert_eq!(conf.portal_groups["pg258"].tag, Some(258));
+            // Only one portal group may listen on the wildcard addresses
+            assert!(conf.portal_groups["pg258"].listen.is_empty());
+            assert_eq!(conf.targets.len(), 2);
+            let t0 = &conf.targets["iqn.2018-10.org.example:t0"];
+            // Nothing can log in until an administrator chooses an auth-group
+            assert_eq!(t0.auth_group.as_deref(), Some("no-access"));
+            let pgs = t0.portal_groups.iter().map(|tpg| tpg.name.as_str()).collect::<Vec<_>>();
+            assert_eq!(pgs, vec!["pg0", "pg258"]);
+            let luns = t0.lun.iter().map(|tl| (tl.number, tl.name.as_str())).collect::<Vec<_>>();
+            assert_eq!(luns, vec![(0, "lun3"), (1, "disk0")]);
+            let t1 = &conf.targets["iqn.2018-10.org.example:t1"];
+            assert_eq!(t1.portal_groups.len(), 1);
+            assert_eq!(t1.portal_groups[0].name, "pg258");
+        }
+
+        /// A port that maps a nonexistent LUN can't be converted
+        #[test]
+        fn from_kernel_missing_lun() {
+            let klun_list = kconf::Ctllunlist::from_xml("<ctllunlist></ctllunlist>").unwrap();
+            let kport_list = kconf::Ctlportlist::from_xml(
+"<ctlportlist>
+<targ_port id=\"3\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">3</lun>
+</targ_port>
+</ctlportlist>").unwrap();
+            let e = Conf::from_kernel(&klun_list, &kport_list).unwrap_err();
+            assert_eq!(format!("{:#}", e), "port 3: LUN 3 does not exist");
+        }
+
+        /// Serializing a configuration and reading it back should be lossless
+        #[test]
+        fn round_trip() {
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(b"
+debug = 1
+isns-server = [\"192.0.2.1:3205\"]
+auth-group {
+    ag0 {
+        chap = [{
+            user = \"foo\"
+            secret = \"bar \\\"baz\\\"\"
+        }]
+        initiator-portal = [\"192.0.2.0/24\"]
+    }
+    ag1 {
+        chap-mutual = [{
+            user = foo
+            secret = bar
+            mutual-user = mfoo
+            mutual-secret = mbar
+        }]
+    }
+}
+portal-group {
+    pg0 {
+        discovery-auth-group = no-authentication
+        discovery-filter = portal-name
+        listen = \"0.0.0.0:3260\"
+        option {
+            foo = bar
+        }
+        tag = 7
+    }
+}
+lun {
+    disk0 {
+        backend = ramdisk
+        blocksize = 4096
+        device-id = dev0
+        device-type = processor
+        size = 1048576
+        option {
+            product = \"widget\"
+        }
+    }
+}
+target {
+    \"iqn.2018-10.org.example:t0\" {
+        alias = \"my target\"
+        auth-group = ag0
+        portal-group { name = pg0, ag-name = ag1 }
+        lun = [
+            { number = 0, name = disk0 },
+        ]
+    }
+    \"iqn.2018-10.org.example:t1\" {
+        auth-type = none
+        lun = []
+    }
+}").unwrap();
+            let conf = Conf::open(f.path()).unwrap();
+            let ucl = conf.to_ucl_with_secrets();
+            let conf2 = reopen(&conf);
+            assert_eq!(conf2.to_ucl_with_secrets(), ucl);
+            assert_eq!(conf2.debug, 1);
+            assert_eq!(conf2.auth_groups["ag0"].chap[0].secret.as_ref().unwrap(), "bar \"baz\"");
+            assert_eq!(conf2.auth_groups["ag1"].chap_mutual[0].mutual_user, "mfoo");
+            assert_eq!(conf2.portal_groups["pg0"].discovery_filter, DiscoveryFilter::PortalName);
+            assert_eq!(conf2.portal_groups["pg0"].options["foo"], "bar");
+            assert_eq!(conf2.luns["disk0"].device_type, DeviceType::Processor);
+            assert_eq!(conf2.luns["disk0"].options.product.as_deref(), Some("widget"));
+            let t0 = &conf2.targets["iqn.2018-10.org.example:t0"];
+            assert_eq!(t0.alias.as_deref(), Some("my target"));
+            assert_eq!(t0.portal_groups[0].ag_name.as_deref(), Some("ag1"));
+            let t1 = &conf2.targets["iqn.2018-10.org.example:t1"];
+            assert_eq!(t1.auth_type, AuthType::None);
+            assert_eq!(t1.auth_group, None);
+        }
+
+        /// Inline secrets are redacted unless explicitly requested
+        #[test]
+        fn redact() {
+            let conf = Conf::from_ucl("auth-group {
+    ag0 {
+        chap-mutual = [{
+            user = foo
+            secret = topsecret1
+            mutual-user = bar
+            mutual-secret = topsecret2
+        }]
+    }
+}
+lun {}
+target {}").unwrap();
+            let ucl = conf.to_ucl();
+            assert!(!ucl.contains("topsecret"), "{}", ucl);
+            assert!(ucl.contains("mutual-secret = \"<redacted>\""), "{}", ucl);
+            let ucl = conf.to_ucl_with_secrets();
+            assert!(ucl.contains("mutual-secret = \"topsecret2\""), "{}", ucl);
+        }
+    }
+
+    mod validate {
+        use super::*;
+
+        /// A valid configuration that each test breaks in a different way
+        const BASE: &str = "
+auth-group {
+    ag0 {
+        auth-type = none
+    }
+}
+portal-group {
+    pg0 {
+        discovery-auth-group = ag0
+        listen = \"0.0.0.0:3260\"
+        tag = 1
+    }
+    pg1 {
+        discovery-auth-group = no-authentication
+        listen = \"[::]:3260\"
+        tag = 2
+    }
+}
+lun {
+    disk0 {
+        ctl_lun = 0
+        device-id = dev0
+        path = \"@PATH0@\"
+        serial = ser0
+    }
+    disk1 {
+        ctl_lun = 1
+        device-id = dev1
+        path = \"@PATH1@\"
+        serial = ser1
+    }
+}
+target {
+    \"iqn.2018-10.org.example:t0\" {
+        auth-group = ag0
+        portal-group { name = pg0, ag-name = ag0 }
+        lun = [
+            { number = 0, name = disk0 },
+            { number = 1, name = disk1 },
+        ]
+    }
+}
+";
+
+        /// Open a configuration, replacing the @PATHn@ placeholders with 1 MiB backing files
+        fn open(contents: &str) -> Result<Conf> {
+            let backing = (0..2).map(|_| {
+                let f = NamedTempFile::new().unwrap();
+                f.as_file().set_len(1 << 20).unwrap();
+                f
+            }).collect::<Vec<_>>();
+            let mut contents = contents.to_owned();
+            for (i, b) in backing.iter().enumerate() {
+                contents = contents.replace(&format!("@PATH{}@", i), b.path().to_str().unwrap());
+            }
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(contents.as_bytes()).unwrap();
+            Conf::open(f.path())
+        }
+
+        /// Open a broken configuration, and check that the error names the offender
+        fn check_err(contents: &str, expected: &str) {
+            let e = format!("{:#}", open(contents).unwrap_err());
+            assert!(e.contains(expected), "Error {:?} does not contain {:?}", e, expected);
+        }
+
+        #[test]
+        fn ok() {
+            open(BASE).unwrap();
+        }
+
+        #[test]
+        fn isns_period_zero() {
+            check_err(&format!("isns-period = 0\n{}", BASE), "isns-period must be positive");
+        }
+
+        #[test]
+        fn isns_timeout_zero() {
+            check_err(&format!("isns-timeout = 0\n{}", BASE), "isns-timeout must be positive");
+        }
+
+        #[test]
+        fn missing_auth_group() {
+            check_err(&BASE.replace("\n        auth-group = ag0", "\n        auth-group = ag1"),
+                "target \"iqn.2018-10.org.example:t0\": auth-group \"ag1\" does not exist");
+        }
+
+        #[test]
+        fn missing_discovery_auth_group() {
+            check_err(&BASE.replace("= no-authentication", "= ag9"),
+                "portal-group \"pg1\": discovery-auth-group \"ag9\" does not exist");
+        }
+
+        #[test]
+        fn auth_group_and_own_auth() {
+            check_err(&BASE.replace("\n        auth-group = ag0",
+                    "\n        auth-group = ag0\n        auth-type = none"),
+                "cannot use both auth-group and its own authentication settings");
+        }
+
+        #[test]
+        fn missing_portal_group() {
+            check_err(&BASE.replace("name = pg0", "name = pg9"),
+                "portal-group \"pg9\" does not exist");
+        }
+
+        /// A target may be served by several portal groups, but only once by each
+        #[test]
+        fn several_portal_groups() {
+            let conf = open(&BASE.replace("portal-group { name = pg0, ag-name = ag0 }",
+                "portal-group = [{ name = pg0, ag-name = ag0 }, { name = pg1 }]")).unwrap();
+            let t0 = &conf.targets["iqn.2018-10.org.example:t0"];
+            let pgs = t0.portal_groups.iter().map(|tpg| tpg.name.as_str()).collect::<Vec<_>>();
+            assert_eq!(pgs, vec!["pg0", "pg1"]);
+
+            check_err(&BASE.replace("portal-group { name = pg0, ag-name = ag0 }",
+                "portal-group = [{ name = pg0 }, { name = pg0 }]"),
+                "target \"iqn.2018-10.org.example:t0\": portal-group \"pg0\" is listed twice");
+        }
+
+        #[test]
+        fn missing_ag_name() {
+            check_err(&BASE.replace("ag-name = ag0", "ag-name = ag9"),
+                "auth-group \"ag9\" does not exist");
+        }
+
+        #[test]
+        fn missing_lun() {
+            check_err(&BASE.replace("name = disk1 }", "name = disk9 }"),
+                "lun 1: LUN \"disk9\" does not exist");
+        }
+
+        #[test]
+        fn duplicate_lun_number() {
+            check_err(&BASE.replace("number = 1", "number = 0"),
+                "lun 0 is mapped to both \"disk0\" and \"disk1\"");
+        }
+
+        #[test]
+        fn lun_mapped_twice() {
+            check_err(&BASE.replace("name = disk1 }", "name = disk0 }"),
+                "LUN \"disk0\" is mapped twice");
+        }
+
+        #[test]
+        fn duplicate_ctl_lun() {
+            check_err(&BASE.replace("ctl_lun = 1", "ctl_lun = 0"),
+                "ctl-lun 0 is used by both \"disk0\" and \"disk1\"");
+        }
+
+        #[test]
+        fn long_serial() {
+            check_err(&BASE.replace("serial = ser1", "serial = 0123456789abcdefg"),
+                "lun \"disk1\": serial 0123456789abcdefg is longer than 16 bytes");
+        }
+
+        #[test]
+        fn long_device_id() {
+            let id = "d".repeat(65);
+            check_err(&BASE.replace("device-id = dev1", &format!("device-id = {}", id)),
+                &format!("lun \"disk1\": device-id {} is longer than 64 bytes", id));
+        }
+
+        #[test]
+        fn duplicate_device_id() {
+            check_err(&BASE.replace("device-id = dev1", "device-id = dev0"),
+                "device-id dev0 is used by both \"disk0\" and \"disk1\"");
+        }
+
+        #[test]
+        fn duplicate_serial() {
+            check_err(&BASE.replace("serial = ser1", "serial = ser0"),
+                "serial ser0 is used by both \"disk0\" and \"disk1\"");
+        }
+
+        #[test]
+        fn duplicate_path() {
+            check_err(&BASE.replace("@PATH1@", "@PATH0@"),
+                "is used by both \"disk0\" and \"disk1\"");
+        }
+
+        #[test]
+        fn ramdisk_without_size() {
+            check_err(&BASE.replace("ctl_lun = 1", "ctl_lun = 1\n        backend = ramdisk"),
+                "lun \"disk1\": ramdisk-backed LUNs must have a size");
+        }
+
+        #[test]
+        fn blocksize_not_power_of_two() {
+            check_err(&BASE.replace("ctl_lun = 1", "ctl_lun = 1\n        blocksize = 1000"),
+                "lun \"disk1\": blocksize 1000 is not a power of two");
+        }
+
+        #[test]
+        fn blocksize_too_big() {
+            check_err(&BASE.replace("ctl_lun = 1", "ctl_lun = 1\n        blocksize = 131072"),
+                "lun \"disk1\": blocksize 131072 is not a power of two between 512 and 65536");
+        }
+
+        #[test]
+        fn size_not_multiple_of_blocksize() {
+            check_err(&BASE.replace("ctl_lun = 1", "ctl_lun = 1\n        blocksize = 4096\n        size = 6144"),
+                "lun \"disk1\": size 6144 is not a multiple of the blocksize 4096");
+        }
+
+        /// cd LUNs default to the kernel's 2048-byte blocks
+        #[test]
+        fn cd_default_blocksize() {
+            check_err(&BASE.replace("ctl_lun = 1", "ctl_lun = 1\n        device-type = cd\n        size = 3072"),
+                "lun \"disk1\": size 3072 is not a multiple of the blocksize 2048");
+            open(&BASE.replace("ctl_lun = 1", "ctl_lun = 1\n        device-type = cd\n        size = 4096"))
+                .unwrap();
+        }
+
+        #[test]
+        fn size_exceeds_backing_file() {
+            check_err(&BASE.replace("ctl_lun = 1", "ctl_lun = 1\n        size = 2097152"),
+                "lun \"disk1\": size 2097152 exceeds the size of");
+        }
+
+        #[test]
+        fn block_without_path() {
+            check_err(&BASE.replace("path = \"@PATH1@\"", ""),
+                "lun \"disk1\": block-backed LUNs must have a path");
+        }
+
+        #[test]
+        fn block_nonexistent_path() {
+            check_err(&BASE.replace("@PATH1@", "/nonexistent/disk1"),
+                "lun \"disk1\": path /nonexistent/disk1");
+        }
+
+        #[test]
+        fn block_directory() {
+            check_err(&BASE.replace("@PATH1@", "/"),
+                "lun \"disk1\": path / is not a regular file or a device");
+        }
+
+        #[test]
+        fn duplicate_tag() {
+            check_err(&BASE.replace("tag = 2", "tag = 1"),
+                "tag 1 is used by both \"pg0\" and \"pg1\"");
+        }
+    }
 }
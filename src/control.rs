@@ -0,0 +1,537 @@
+//! The administrative control socket.
+//!
+//! Clients connect to a UNIX-domain stream socket and send requests, one JSON object per line.
+//! Each request gets a one-line response, either `{"result": ...}` or `{"error": "..."}`.  For
+//! example:
+//!
+//! ```text
+//! {"method": "lun_resize", "params": {"name": "disk0", "size": 2147483648}}
+//! {"result": null}
+//! ```
+//!
+//! Only root and the user that ctld runs as may use the socket.
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use nix::unistd::{Uid, geteuid};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::conf;
+use crate::kernel::{self, SessionFilter};
+use crate::log::{self, PeerGuard};
+use crate::state::State;
+use crate::{debug, error, warn};
+
+/// How long a client may take to send each request before it is disconnected
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A request to the control socket
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum Request {
+    /// Report the configured targets, and the kernel's LUNs, ports, and sessions
+    Status,
+    /// Reread the configuration file and apply it.  The result is the plan that was applied.
+    Reload,
+    /// Report what a reload would do, without doing it
+    Plan,
+    /// Get the running configuration, in ctl.conf syntax.  Inline CHAP secrets are redacted.
+    ConfigExport,
+    /// Create a LUN.  `lun` is a JSON object with the same keys as a `lun` block in the
+    /// configuration file.
+    LunAdd {
+        name: String,
+        lun: Value
+    },
+    /// Remove a LUN that isn't used by any target
+    LunRemove {
+        name: String
+    },
+    /// Change a LUN's size, in bytes
+    LunResize {
+        name: String,
+        size: u64
+    },
+    /// Ask iSCSI sessions to log out
+    SessionLogout(SessionFilter),
+    /// Forcibly terminate iSCSI sessions
+    SessionTerminate(SessionFilter),
+}
+
+/// The response to a [`Request`]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Result(Value),
+    Error(String)
+}
+
+/// A connection to a running ctld's control socket
+#[derive(Debug)]
+pub struct Client {
+    stream: BufReader<UnixStream>
+}
+
+impl Client {
+    pub fn connect(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("connecting to {}", path.display()))?;
+        Ok(Client{stream: BufReader::new(stream)})
+    }
+
+    /// Send a request, and wait for its result
+    pub fn call(&mut self, req: &Request) -> Result<Value> {
+        let mut buf = serde_json::to_vec(req).context("serializing request")?;
+        buf.push(b'\n');
+        self.stream.get_mut().write_all(&buf).context("sending request")?;
+        let mut line = String::new();
+        self.stream.read_line(&mut line).context("reading response")?;
+        if line.is_empty() {
+            bail!("ctld closed the connection");
+        }
+        match serde_json::from_str(&line).context("parsing response")? {
+            Response::Result(v) => Ok(v),
+            Response::Error(e) => bail!("{}", e)
+        }
+    }
+}
+
+/// Serves the control socket in a background thread.  The socket is removed on Drop.
+#[derive(Debug)]
+pub struct Server {
+    path: PathBuf
+}
+
+impl Server {
+    /// Listen on `path`, replacing whatever was there.  The caller must already hold the
+    /// pidfile, so any existing socket must be left over from an earlier ctld.
+    pub fn start(path: &Path, state: Arc<Mutex<State>>) -> Result<Self> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("removing {}", path.display()));
+            },
+            _ => ()
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("binding {}", path.display()))?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("chmod {}", path.display()))?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let state = state.clone();
+                        thread::spawn(move || {
+                            let peer = log::peer("control socket", None);
+                            if let Err(e) = serve(stream, &state, &peer) {
+                                warn!("{:?}", e);
+                            }
+                        });
+                    },
+                    Err(e) => error!("control socket: accept: {}", e)
+                }
+            }
+        });
+        Ok(Server{path: path.to_owned()})
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Get the effective user id of the process on the other end of a UNIX-domain socket
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> Result<Uid> {
+    let (uid, _gid) = nix::unistd::getpeereid(stream).context("getpeereid")?;
+    Ok(uid)
+}
+
+/// Get the effective user id of the process on the other end of a UNIX-domain socket
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> Result<Uid> {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+
+    let cred = getsockopt(stream, PeerCredentials).context("SO_PEERCRED")?;
+    Ok(Uid::from_raw(cred.uid()))
+}
+
+/// Answer a client's requests until it disconnects.  Messages logged meanwhile are tagged with
+/// the client's uid, through `peer`.
+fn serve(stream: UnixStream, state: &Mutex<State>, peer: &PeerGuard) -> Result<()> {
+    let uid = peer_uid(&stream)?;
+    peer.set_name(&format!("uid {}", uid));
+    if !uid.is_root() && uid != geteuid() {
+        // Tell the client why, so it isn't left guessing
+        let _ = respond(&stream, &Response::Error(String::from("permission denied")));
+        bail!("rejected connection from uid {}", uid);
+    }
+    stream.set_read_timeout(Some(TIMEOUT)).context("setting timeout")?;
+    for line in BufReader::new(&stream).lines() {
+        let line = line.context("reading request")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(req) => {
+                debug!("control request: {:?}", req);
+                match handle(state, req) {
+                    Ok(v) => Response::Result(v),
+                    Err(e) => Response::Error(format!("{:#}", e))
+                }
+            },
+            Err(e) => Response::Error(format!("invalid request: {}", e))
+        };
+        respond(&stream, &response)?;
+    }
+    Ok(())
+}
+
+fn respond(mut stream: &UnixStream, response: &Response) -> Result<()> {
+    let mut buf = serde_json::to_vec(response).context("serializing response")?;
+    buf.push(b'\n');
+    stream.write_all(&buf).context("writing response")
+}
+
+/// Perform a request
+fn handle(state: &Mutex<State>, req: Request) -> Result<Value> {
+    let mut state = state.lock()
+        .map_err(|_| anyhow!("internal state is unusable after an earlier panic"))?;
+    let v = match req {
+        Request::Status => serde_json::to_value(state.status()?)?,
+        Request::Reload => serde_json::to_value(state.reload()?)?,
+        Request::Plan => serde_json::to_value(state.plan()?)?,
+        Request::ConfigExport => Value::String(state.conf().to_ucl()),
+        Request::LunAdd{name, lun} => {
+            let lun = conf::Lun::parse(&name, &lun.to_string())?;
+            state.add_lun(&name, lun)?;
+            Value::Null
+        },
+        Request::LunRemove{name} => {
+            state.remove_lun(&name)?;
+            Value::Null
+        },
+        Request::LunResize{name, size} => {
+            state.resize_lun(&name, size)?;
+            Value::Null
+        },
+        Request::SessionLogout(filter) => {
+            kernel::logout(&filter)?;
+            Value::Null
+        },
+        Request::SessionTerminate(filter) => {
+            kernel::terminate(&filter)?;
+            Value::Null
+        },
+    };
+    Ok(v)
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use std::io::Read;
+
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use crate::conf::Conf;
+    use crate::ffi;
+    use crate::ioc::{
+        CTL_ISCSI_MTX,
+        CTL_LIST_MTX,
+        CTL_LUN_REQ_MTX,
+        expect_lists,
+        fill_iscsi_list,
+        mock_ioc
+    };
+
+    const LUNS: &str = "<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number></serial_number>
+	<device_id>dev0</device_id>
+	<ctld_name>disk0</ctld_name>
+</lun>
+</ctllunlist>";
+
+    const SESSIONS: &str = "<ctlislist>
+<connection id=\"3\"><initiator>iqn.1994-09.org.freebsd:a</initiator><initiator_addr>192.0.2.1</initiator_addr><target>iqn.2018-10.org.example:t0</target><target_portal_group_tag>257</target_portal_group_tag><header_digest>None</header_digest><data_digest>None</data_digest><max_recv_data_segment_length>8192</max_recv_data_segment_length><max_send_data_segment_length>8192</max_send_data_segment_length><max_burst_length>262144</max_burst_length><first_burst_length>65536</first_burst_length><immediate_data>0</immediate_data><iser>0</iser></connection>
+</ctlislist>";
+
+    /// A ctld whose kernel already has the one LUN that it's configured with
+    fn state(dir: &TempDir) -> Arc<Mutex<State>> {
+        let path = dir.path().join("ctl.conf");
+        fs::write(&path, format!("
+tag-file = \"{}/tags.json\"
+auth-group {{
+    ag0 {{
+        chap = [{{ user = foo, secret = topsecret }}]
+    }}
+}}
+lun {{
+    disk0 {{
+        backend = ramdisk
+        device-id = dev0
+        size = 1048576
+    }}
+}}
+target {{}}
+", dir.path().display())).unwrap();
+        let conf = Conf::open(&path).unwrap();
+        Arc::new(Mutex::new(State::start(path, conf).unwrap()))
+    }
+
+    /// Send some requests over a connection, and return the responses
+    fn transact(state: &Arc<Mutex<State>>, requests: &str) -> Vec<Response> {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let state2 = state.clone();
+        let thread = thread::spawn(move || {
+            let peer = log::peer("control socket", None);
+            serve(server, &state2, &peer)
+        });
+        client.write_all(requests.as_bytes()).unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).unwrap();
+        thread.join().unwrap().unwrap();
+        buf.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    fn shutdown(state: &Arc<Mutex<State>>, ctx: &mock_ioc::__ctl_lun_req::Context) {
+        ctx.checkpoint();
+        ctx.expect()
+            .withf(|_fd, req| unsafe{ (**req).reqtype == ffi::ctl_lunreq_type::CTL_LUNREQ_RM })
+            .returning(|_fd, _req| Ok(0));
+        state.lock().unwrap().shutdown();
+    }
+
+    /// Requests are adjacently tagged, with the parameters optional for those that take none
+    #[test]
+    fn request_format() {
+        assert_eq!(serde_json::from_str::<Request>(r#"{"method": "status"}"#).unwrap(),
+            Request::Status);
+        assert_eq!(serde_json::from_str::<Request>(
+            r#"{"method": "lun_resize", "params": {"name": "disk0", "size": 4096}}"#).unwrap(),
+            Request::LunResize{name: String::from("disk0"), size: 4096});
+        assert_eq!(serde_json::from_str::<Request>(
+            r#"{"method": "session_terminate", "params": {"initiator": "iqn.x"}}"#).unwrap(),
+            Request::SessionTerminate(SessionFilter::Initiator(String::from("iqn.x"))));
+        assert_eq!(serde_json::from_str::<Request>(
+            r#"{"method": "session_logout", "params": "all"}"#).unwrap(),
+            Request::SessionLogout(SessionFilter::All));
+        assert_eq!(serde_json::to_string(&Response::Error(String::from("oops"))).unwrap(),
+            r#"{"error":"oops"}"#);
+    }
+
+    #[test]
+    fn status() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let _m3 = CTL_ISCSI_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, "<ctlportlist></ctlportlist>");
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let state = state(&dir);
+        let iscsi_ctx = mock_ioc::ctl_iscsi_context();
+        iscsi_ctx.expect()
+            .returning(|_fd, req| {
+                unsafe{ fill_iscsi_list(req, SESSIONS) };
+                Ok(0)
+            });
+
+        let responses = transact(&state, "{\"method\": \"status\"}\n");
+        let Response::Result(status) = &responses[0] else {
+            panic!("{:?}", responses);
+        };
+        assert_eq!(status["luns"][0]["ctld_name"], "disk0");
+        assert_eq!(status["sessions"][0]["initiator"], "iqn.1994-09.org.freebsd:a");
+        assert_eq!(status["targets"], json!([]));
+
+        shutdown(&state, &ctx);
+    }
+
+    /// Several requests can be sent over one connection.  Failures are reported without
+    /// closing it.
+    #[test]
+    fn lun_add_resize_remove() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, "<ctlportlist></ctlportlist>");
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let state = state(&dir);
+        ctx.expect()
+            .withf(|_fd, req| unsafe {
+                (**req).reqtype == ffi::ctl_lunreq_type::CTL_LUNREQ_CREATE &&
+                    (**req).reqdata.create.lun_size_bytes == 2097152
+            })
+            .times(1)
+            .returning(|_fd, req| {
+                unsafe {
+                    (*req).reqdata.create.req_lun_id = 1;
+                    (*req).status = ffi::ctl_lun_status::CTL_LUN_OK;
+                }
+                Ok(0)
+            });
+        ctx.expect()
+            .withf(|_fd, req| unsafe {
+                (**req).reqtype == ffi::ctl_lunreq_type::CTL_LUNREQ_MODIFY &&
+                    (**req).reqdata.modify.lun_id == 1 &&
+                    (**req).reqdata.modify.lun_size_bytes == 4194304
+            })
+            .times(1)
+            .returning(|_fd, req| {
+                unsafe{ (*req).status = ffi::ctl_lun_status::CTL_LUN_OK };
+                Ok(0)
+            });
+        ctx.expect()
+            .withf(|_fd, req| unsafe {
+                (**req).reqtype == ffi::ctl_lunreq_type::CTL_LUNREQ_RM &&
+                    (**req).reqdata.rm.lun_id == 1
+            })
+            .times(1)
+            .returning(|_fd, _req| Ok(0));
+
+        let responses = transact(&state, concat!(
+            r#"{"method": "lun_add", "params": {"name": "disk1", "lun": {"backend": "ramdisk", "device-id": "dev1", "size": 2097152}}}"#, "\n",
+            r#"{"method": "lun_add", "params": {"name": "disk2", "lun": "{ backend = ramdisk, device-id = dev2, size = 1048576 }"}}"#, "\n",
+            r#"{"method": "lun_add", "params": {"name": "disk1", "lun": {"backend": "ramdisk", "device-id": "dev2", "size": 1048576}}}"#, "\n",
+            r#"{"method": "lun_resize", "params": {"name": "disk1", "size": 4194304}}"#, "\n",
+            r#"{"method": "lun_resize", "params": {"name": "disk2", "size": 4194304}}"#, "\n",
+            r#"{"method": "lun_remove", "params": {"name": "disk1"}}"#, "\n",
+        ));
+        assert_eq!(responses, vec![
+            Response::Result(Value::Null),
+            Response::Error(String::from(concat!("LUN definition must be a JSON object: ",
+                r#"invalid type: string "{ backend = ramdisk, device-id = dev2, size = 1048576 }", "#,
+                "expected a map at line 1 column 57"))),
+            Response::Error(String::from("lun \"disk1\" already exists")),
+            Response::Result(Value::Null),
+            Response::Error(String::from("lun \"disk2\" does not exist")),
+            Response::Result(Value::Null),
+        ]);
+        assert!(!state.lock().unwrap().conf().luns.contains_key("disk1"));
+
+        shutdown(&state, &ctx);
+    }
+
+    #[test]
+    fn session_terminate() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let _m3 = CTL_ISCSI_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, "<ctlportlist></ctlportlist>");
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let state = state(&dir);
+        let iscsi_ctx = mock_ioc::ctl_iscsi_context();
+        iscsi_ctx.expect()
+            .withf(|_fd, req| unsafe {
+                (**req).type_ == ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE &&
+                    (**req).data.terminate.connection_id == 3
+            })
+            .times(1)
+            .returning(|_fd, req| {
+                unsafe{ (*req).status = ffi::ctl_iscsi_status::CTL_ISCSI_OK };
+                Ok(0)
+            });
+
+        let responses = transact(&state,
+            "{\"method\": \"session_terminate\", \"params\": {\"id\": 3}}\n");
+        assert_eq!(responses, vec![Response::Result(Value::Null)]);
+
+        shutdown(&state, &ctx);
+    }
+
+    /// The exported configuration can be read back, but doesn't reveal CHAP secrets
+    #[test]
+    fn config_export() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, "<ctlportlist></ctlportlist>");
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let state = state(&dir);
+
+        let responses = transact(&state, "{\"method\": \"config_export\"}\n");
+        let Response::Result(Value::String(ucl)) = &responses[0] else {
+            panic!("{:?}", responses);
+        };
+        let conf = Conf::from_ucl(ucl).unwrap();
+        assert_eq!(conf.luns["disk0"].size, Some(1048576));
+        assert!(ucl.contains("user = \"foo\""), "{}", ucl);
+        assert!(!ucl.contains("topsecret"), "{}", ucl);
+
+        shutdown(&state, &ctx);
+    }
+
+    #[test]
+    fn invalid_request() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, "<ctlportlist></ctlportlist>");
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let state = state(&dir);
+
+        let responses = transact(&state, "{\"method\": \"format_disk\"}\nnot json\n");
+        assert_eq!(responses.len(), 2);
+        for response in responses {
+            let Response::Error(e) = response else {
+                panic!("{:?}", response);
+            };
+            assert!(e.starts_with("invalid request: "), "{}", e);
+        }
+
+        shutdown(&state, &ctx);
+    }
+
+    /// The socket is only accessible by its owner, and is removed when the server stops
+    #[test]
+    fn client_server() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, "<ctlportlist></ctlportlist>");
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let state = state(&dir);
+        let path = dir.path().join("ctld.sock");
+        // A stale socket gets replaced
+        fs::write(&path, b"").unwrap();
+
+        let server = Server::start(&path, state.clone()).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        ctx.expect()
+            .withf(|_fd, req| unsafe{ (**req).reqtype == ffi::ctl_lunreq_type::CTL_LUNREQ_RM })
+            .times(1)
+            .returning(|_fd, _req| Ok(0));
+        let mut client = Client::connect(&path).unwrap();
+        let v = client.call(&Request::LunRemove{name: String::from("disk0")}).unwrap();
+        assert_eq!(v, Value::Null);
+        let e = client.call(&Request::LunRemove{name: String::from("disk0")}).unwrap_err();
+        assert_eq!(e.to_string(), "lun \"disk0\" does not exist");
+        drop(server);
+        assert!(!path.exists());
+
+        shutdown(&state, &ctx);
+    }
+}
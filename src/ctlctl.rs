@@ -0,0 +1,262 @@
+//! Manage a running ctld through its control socket
+use std::{
+    fs,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde_json::Value;
+
+use ctld::control::{Client, Request};
+use ctld::kernel::SessionFilter;
+use ctld::plan::Plan;
+use ctld::table::{self, array, cell};
+
+#[derive(Debug, clap::Parser)]
+struct Cli {
+    /// control socket path
+    #[clap(short = 's', long, default_value = "/var/run/ctld.sock")]
+    socket: PathBuf,
+    /// print results as JSON instead of tables
+    #[clap(long, global = true)]
+    json: bool,
+    #[clap(subcommand)]
+    cmd: Cmd
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Cmd {
+    /// show targets, LUNs, ports, and sessions
+    Status,
+    /// reread ctld's configuration file and apply it
+    Reload,
+    /// show what a reload would change, without changing anything
+    Plan,
+    /// manage LUNs
+    #[clap(subcommand)]
+    Lun(LunCmd),
+    /// inspect targets
+    #[clap(subcommand)]
+    Target(TargetCmd),
+    /// manage iSCSI sessions
+    #[clap(subcommand)]
+    Session(SessionCmd),
+    /// inspect the running configuration
+    #[clap(subcommand)]
+    Config(ConfigCmd),
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum LunCmd {
+    /// list every LUN in the kernel
+    List,
+    /// create a LUN.  It lasts until ctld's next reload unless it's also added to ctl.conf.
+    Add {
+        name: String,
+        /// the LUN's settings, as a JSON object with the same keys as a lun block in ctl.conf.
+        /// Read from stdin if "-", or from a file if it starts with "@".
+        definition: String
+    },
+    /// remove a LUN that isn't used by any target
+    Remove {
+        name: String
+    },
+    /// change a LUN's size
+    Resize {
+        name: String,
+        /// the new size, in bytes or with a k, m, g, or t suffix
+        #[clap(value_parser = parse_size)]
+        size: u64
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum TargetCmd {
+    /// list the configured targets
+    List,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum SessionCmd {
+    /// list iSCSI sessions
+    List,
+    /// terminate iSCSI sessions
+    #[clap(group(clap::ArgGroup::new("which").required(true).args(["id", "initiator", "all"])))]
+    Kill {
+        /// the session's connection id, as shown by "session list"
+        #[clap(long)]
+        id: Option<u32>,
+        /// every session from this initiator
+        #[clap(long)]
+        initiator: Option<String>,
+        /// every session
+        #[clap(long)]
+        all: bool,
+        /// ask the initiator to log out, instead of dropping the connection
+        #[clap(long)]
+        logout: bool,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum ConfigCmd {
+    /// print the running configuration in ctl.conf syntax
+    Export,
+}
+
+/// Parse a size in bytes, with an optional binary suffix
+fn parse_size(s: &str) -> Result<u64> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 30),
+        Some((i, 't' | 'T')) => (&s[..i], 40),
+        _ => (s, 0)
+    };
+    let n: u64 = digits.parse().with_context(|| format!("invalid size \"{}\"", s))?;
+    n.checked_mul(1 << shift).with_context(|| format!("size \"{}\" is too large", s))
+}
+
+fn target_table(status: &Value) {
+    let rows = array(status, "targets").iter().map(|target| {
+        let luns = target["luns"].as_object()
+            .map(|luns| luns.iter()
+                .map(|(number, name)| format!("{}={}", number, cell(name)))
+                .collect::<Vec<_>>()
+                .join(","))
+            .unwrap_or_default();
+        vec![
+            cell(&target["name"]),
+            array(target, "portal_groups").iter().map(cell).collect::<Vec<_>>().join(","),
+            luns,
+            cell(&target["alias"]),
+        ]
+    }).collect::<Vec<_>>();
+    print!("{}", table::format(&["NAME", "PORTAL_GROUPS", "LUNS", "ALIAS"], &rows));
+}
+
+/// Read a LUN definition from the command line, a file, or stdin
+fn read_definition(definition: String) -> Result<String> {
+    if definition == "-" {
+        let mut s = String::new();
+        io::stdin().read_to_string(&mut s).context("reading stdin")?;
+        Ok(s)
+    } else if let Some(path) = definition.strip_prefix('@') {
+        fs::read_to_string(path).with_context(|| format!("reading {}", path))
+    } else {
+        Ok(definition)
+    }
+}
+
+fn main() -> Result<()> {
+    let cli: Cli = Cli::parse();
+
+    let request = match cli.cmd {
+        Cmd::Status => Request::Status,
+        Cmd::Reload => Request::Reload,
+        Cmd::Plan => Request::Plan,
+        Cmd::Lun(LunCmd::List) | Cmd::Target(TargetCmd::List) |
+            Cmd::Session(SessionCmd::List) => Request::Status,
+        Cmd::Lun(LunCmd::Add{ref name, ref definition}) => Request::LunAdd {
+            name: name.clone(),
+            lun: serde_json::from_str(&read_definition(definition.clone())?)
+                .context("LUN definition must be a JSON object")?
+        },
+        Cmd::Lun(LunCmd::Remove{ref name}) => Request::LunRemove{name: name.clone()},
+        Cmd::Lun(LunCmd::Resize{ref name, size}) => {
+            Request::LunResize{name: name.clone(), size}
+        },
+        Cmd::Session(SessionCmd::Kill{id, ref initiator, all, logout}) => {
+            let filter = match (id, initiator) {
+                (Some(id), _) => SessionFilter::Id(id),
+                (None, Some(initiator)) => SessionFilter::Initiator(initiator.clone()),
+                (None, None) => {
+                    assert!(all);
+                    SessionFilter::All
+                }
+            };
+            if logout {
+                Request::SessionLogout(filter)
+            } else {
+                Request::SessionTerminate(filter)
+            }
+        },
+        Cmd::Config(ConfigCmd::Export) => Request::ConfigExport,
+    };
+
+    let mut client = Client::connect(&cli.socket)?;
+    let result = client.call(&request)?;
+
+    match cli.cmd {
+        Cmd::Lun(LunCmd::List) if cli.json => {
+            println!("{}", serde_json::to_string_pretty(&result["luns"])?);
+        },
+        Cmd::Target(TargetCmd::List) if cli.json => {
+            println!("{}", serde_json::to_string_pretty(&result["targets"])?);
+        },
+        Cmd::Session(SessionCmd::List) if cli.json => {
+            println!("{}", serde_json::to_string_pretty(&result["sessions"])?);
+        },
+        _ if cli.json => {
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        },
+        Cmd::Status => {
+            target_table(&result);
+            println!();
+            print!("{}", table::luns(&result["luns"]));
+            println!();
+            print!("{}", table::ports(&result["ports"]));
+            println!();
+            print!("{}", table::sessions(&result["sessions"]));
+        },
+        Cmd::Reload | Cmd::Plan => {
+            let plan: Plan = serde_json::from_value(result).context("parsing plan")?;
+            print!("{}", plan);
+        },
+        Cmd::Lun(LunCmd::List) => print!("{}", table::luns(&result["luns"])),
+        Cmd::Target(TargetCmd::List) => target_table(&result),
+        Cmd::Session(SessionCmd::List) => print!("{}", table::sessions(&result["sessions"])),
+        Cmd::Config(ConfigCmd::Export) => {
+            print!("{}", result.as_str().context("expected a string")?);
+        },
+        Cmd::Lun(_) | Cmd::Session(_) => ()
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn parse_size_plain() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("1048576").unwrap(), 1 << 20);
+    }
+
+    #[test]
+    fn parse_size_suffix() {
+        assert_eq!(parse_size("4k").unwrap(), 4 << 10);
+        assert_eq!(parse_size("4K").unwrap(), 4 << 10);
+        assert_eq!(parse_size("10m").unwrap(), 10 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1t").unwrap(), 1 << 40);
+    }
+
+    #[test]
+    fn parse_size_bad() {
+        for s in ["", "k", "4x", "-4k", "1.5g", "4 k"] {
+            let e = parse_size(s).unwrap_err();
+            assert_eq!(e.to_string(), format!("invalid size \"{}\"", s));
+        }
+    }
+
+    #[test]
+    fn parse_size_overflow() {
+        let e = parse_size("16777216t").unwrap_err();
+        assert_eq!(e.to_string(), "size \"16777216t\" is too large");
+    }
+}
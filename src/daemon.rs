@@ -0,0 +1,162 @@
+//! Detach from the terminal, and tell whoever started ctld once it is ready to serve.
+use std::{
+    fs,
+    io::{Read, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::net::UnixDatagram,
+    },
+    path::Path,
+    process,
+};
+
+use anyhow::{Context, Result};
+use nix::unistd::{self, ForkResult};
+
+/// Sent through the pipe when startup succeeded.  Anything else is an error message.
+const READY: &[u8] = b"\0";
+
+/// The daemon's link to the parent process, which waits to exit until startup has finished.
+#[derive(Debug)]
+pub struct Daemon {
+    tx: fs::File
+}
+
+impl Daemon {
+    /// Startup succeeded.  The parent process will exit with status 0.
+    pub fn ready(mut self) -> Result<()> {
+        self.tx.write_all(READY).context("notifying parent process")
+    }
+
+    /// Startup failed.  The parent process will print the error and exit with status 1.
+    pub fn failed(mut self, e: &anyhow::Error) {
+        // If the parent can't be told, it will still see EOF and exit with an error.
+        let _ = self.tx.write_all(format!("{:?}", e).as_bytes());
+    }
+}
+
+/// Fork into the background.
+///
+/// Only the child returns.  The parent stays in the foreground until the child calls
+/// [`Daemon::ready`] or [`Daemon::failed`], or dies, so that rc scripts see the correct exit
+/// status.  Must be called before spawning any threads.
+pub fn daemonize() -> Result<Daemon> {
+    let (rx, tx) = unistd::pipe().context("pipe")?;
+    // Safe as long as no other threads have been spawned yet
+    match unsafe { unistd::fork() }.context("fork")? {
+        ForkResult::Parent{..} => {
+            drop(tx);
+            process::exit(wait_for_child(rx))
+        },
+        ForkResult::Child => {
+            drop(rx);
+            unistd::setsid().context("setsid")?;
+            unistd::chdir("/").context("chdir")?;
+            let null = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/null")
+                .context("opening /dev/null")?;
+            for fd in 0..=2 {
+                unistd::dup2(null.as_raw_fd(), fd).context("dup2")?;
+            }
+            Ok(Daemon{tx: fs::File::from(tx)})
+        }
+    }
+}
+
+/// Wait for the daemonized child to report how startup went, and return an exit status.
+fn wait_for_child(rx: OwnedFd) -> i32 {
+    let mut msg = Vec::new();
+    // A read error is as bad as the child dying
+    let _ = fs::File::from(rx).read_to_end(&mut msg);
+    if msg == READY {
+        0
+    } else if msg.is_empty() {
+        eprintln!("Error: ctld exited during startup");
+        1
+    } else {
+        eprintln!("Error: {}", String::from_utf8_lossy(&msg));
+        1
+    }
+}
+
+/// Tell a supervisor that ctld is ready to serve.
+///
+/// Writes a newline to `fd`, if set, and closes it, like s6's notification-fd.  And sends
+/// `READY=1` to the datagram socket at `socket`, if set, like sd_notify(3).
+pub fn notify_ready(fd: Option<OwnedFd>, socket: Option<&Path>) -> Result<()> {
+    if let Some(fd) = fd {
+        fs::File::from(fd).write_all(b"\n").context("writing to the readiness file descriptor")?;
+    }
+    if let Some(socket) = socket {
+        UnixDatagram::unbound()
+            .and_then(|sock| sock.send_to(b"READY=1", socket))
+            .with_context(|| format!("notifying {}", socket.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    mod wait_for_child {
+        use super::*;
+
+        #[test]
+        fn ready() {
+            let (rx, tx) = unistd::pipe().unwrap();
+            Daemon{tx: fs::File::from(tx)}.ready().unwrap();
+            assert_eq!(wait_for_child(rx), 0);
+        }
+
+        #[test]
+        fn failed() {
+            let (rx, tx) = unistd::pipe().unwrap();
+            Daemon{tx: fs::File::from(tx)}.failed(&anyhow::anyhow!("oops"));
+            assert_eq!(wait_for_child(rx), 1);
+        }
+
+        /// If the child dies without saying anything, the parent must not report success
+        #[test]
+        fn died() {
+            let (rx, tx) = unistd::pipe().unwrap();
+            drop(tx);
+            assert_eq!(wait_for_child(rx), 1);
+        }
+    }
+
+    mod notify_ready {
+        use super::*;
+
+        #[test]
+        fn fd() {
+            let (rx, tx) = unistd::pipe().unwrap();
+            notify_ready(Some(tx), None).unwrap();
+            let mut buf = Vec::new();
+            fs::File::from(rx).read_to_end(&mut buf).unwrap();
+            assert_eq!(buf, b"\n");
+        }
+
+        #[test]
+        fn socket() {
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().join("notify");
+            let sock = UnixDatagram::bind(&path).unwrap();
+            notify_ready(None, Some(&path)).unwrap();
+            let mut buf = [0u8; 64];
+            let len = sock.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"READY=1");
+        }
+
+        #[test]
+        fn nobody_listening() {
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().join("notify");
+            notify_ready(None, Some(&path)).unwrap_err();
+        }
+    }
+}
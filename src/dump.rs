@@ -1,32 +1,342 @@
-//! Helper utility to dump the kernel's XML config
-use anyhow::{Context, Result};
+//! Helper utility to dump the kernel's XML config, or a saved copy of it
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
 use clap::Parser;
+use serde_derive::Serialize;
 
+use ctld::conf::Conf;
 use ctld::kconf;
+use ctld::table;
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+enum Format {
+    /// The kernel's raw XML
+    #[default]
+    Xml,
+    /// Pretty-printed JSON
+    Json,
+    /// A ctl.conf file that would recreate the kernel's LUNs and targets
+    Ucl,
+    /// Human-readable tables
+    Table
+}
 
 #[derive(Debug, Default, clap::Parser)]
 struct Cli {
+    /// output format
+    #[clap(long, value_enum, default_value_t)]
+    format: Format,
     /// dump the kernel's LUN list
     #[clap(short = 'l')]
     lun: bool,
     /// dump the kernel's port list
     #[clap(short = 'p')]
-    port: bool
+    port: bool,
+    /// dump the kernel's iSCSI session list
+    #[clap(short = 's')]
+    session: bool,
+    /// read a saved LUN list from FILE instead of the kernel, or from stdin if FILE is "-"
+    #[clap(long, value_name = "FILE")]
+    lun_xml: Option<PathBuf>,
+    /// read a saved port list from FILE instead of the kernel, or from stdin if FILE is "-"
+    #[clap(long, value_name = "FILE")]
+    port_xml: Option<PathBuf>,
+    /// read a saved session list from FILE instead of the kernel, or from stdin if FILE is "-"
+    #[clap(long, value_name = "FILE")]
+    session_xml: Option<PathBuf>,
+    /// instead of dumping, report any XML elements that ctld doesn't understand
+    #[clap(long)]
+    validate: bool,
+    /// instead of dumping, show the I/O rates of each LUN (-l) or port (-p), busiest first
+    #[clap(long, conflicts_with = "validate")]
+    stats: bool,
+    /// how long to measure I/O rates over
+    #[clap(long, value_name = "SECONDS", default_value_t = 1.0, requires = "stats")]
+    interval: f64,
+}
+
+/// Read a list's XML from a saved file, from stdin, or from the running kernel.
+fn read_xml(file: Option<&Path>, what: &str, from_kernel: fn() -> Result<String>)
+    -> Result<String>
+{
+    match file {
+        Some(p) if p == Path::new("-") => {
+            let mut xml = String::new();
+            io::stdin().read_to_string(&mut xml)
+                .with_context(|| format!("reading {} list from stdin", what))?;
+            Ok(xml)
+        },
+        Some(p) => {
+            fs::read_to_string(p)
+                .with_context(|| format!("reading {} list from {}", what, p.display()))
+        },
+        None => from_kernel().with_context(|| format!("getting {} list", what))
+    }
+}
+
+/// Everything requested on the command line, as a single JSON document
+#[derive(Serialize)]
+struct Dump {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    luns: Option<Vec<kconf::Lun>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ports: Option<Vec<kconf::TargPort>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sessions: Option<Vec<kconf::Connection>>,
+}
+
+/// I/O rates requested on the command line, as a single JSON document
+#[derive(Serialize)]
+struct StatsDump {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    luns: Option<Vec<Rates>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ports: Option<Vec<Rates>>,
+}
+
+/// One LUN's or port's I/O rates over an interval
+#[derive(Debug, Serialize)]
+struct Rates {
+    id: u32,
+    name: String,
+    read_iops: f64,
+    read_bytes_per_sec: f64,
+    write_iops: f64,
+    write_bytes_per_sec: f64,
+    other_iops: f64,
+    /// Average latency in milliseconds, if the kernel measures it
+    read_latency_ms: Option<f64>,
+    write_latency_ms: Option<f64>,
+}
+
+/// Compute every item's rates between two samples, busiest first
+fn rates(
+    earlier: &kconf::IoStatsList,
+    later: &kconf::IoStatsList,
+    interval: Duration,
+    names: &HashMap<u32, String>
+) -> Vec<Rates> {
+    // Prefer the kernel's timestamps, since the ioctls themselves may be slow
+    let elapsed = match later.timestamp.saturating_sub(earlier.timestamp) {
+        Duration::ZERO => interval,
+        elapsed => elapsed
+    }.as_secs_f64();
+    let latency = |op: &kconf::OpStats| {
+        (later.time_valid && op.operations > 0)
+            .then(|| op.time.as_secs_f64() * 1000.0 / op.operations as f64)
+    };
+    let mut rates = later.items.iter().filter_map(|l| {
+        // Skip items that were created during the interval
+        let e = earlier.items.iter().find(|e| e.item == l.item)?;
+        let d = l.since(e);
+        Some(Rates {
+            id: d.item,
+            name: names.get(&d.item).cloned().unwrap_or_default(),
+            read_iops: d.read.operations as f64 / elapsed,
+            read_bytes_per_sec: d.read.bytes as f64 / elapsed,
+            write_iops: d.write.operations as f64 / elapsed,
+            write_bytes_per_sec: d.write.bytes as f64 / elapsed,
+            other_iops: d.other.operations as f64 / elapsed,
+            read_latency_ms: latency(&d.read),
+            write_latency_ms: latency(&d.write),
+        })
+    }).collect::<Vec<_>>();
+    rates.sort_by(|a, b| {
+        let total = |r: &Rates| r.read_bytes_per_sec + r.write_bytes_per_sec;
+        total(b).total_cmp(&total(a)).then(a.id.cmp(&b.id))
+    });
+    rates
+}
+
+/// Format a number of bytes with a binary suffix
+fn human(bytes: f64) -> String {
+    const SUFFIXES: [&str; 5] = ["", "K", "M", "G", "T"];
+    let mut v = bytes;
+    let mut i = 0;
+    while v >= 1024.0 && i < SUFFIXES.len() - 1 {
+        v /= 1024.0;
+        i += 1;
+    }
+    if i == 0 {
+        format!("{:.0}", v)
+    } else {
+        format!("{:.1}{}", v, SUFFIXES[i])
+    }
+}
+
+fn rates_table(rates: &[Rates]) {
+    let ms = |v: Option<f64>| v.map(|v| format!("{:.2}", v)).unwrap_or_else(|| String::from("-"));
+    let rows = rates.iter().map(|r| vec![
+        r.id.to_string(),
+        r.name.clone(),
+        format!("{:.0}", r.read_iops),
+        human(r.read_bytes_per_sec),
+        ms(r.read_latency_ms),
+        format!("{:.0}", r.write_iops),
+        human(r.write_bytes_per_sec),
+        ms(r.write_latency_ms),
+        format!("{:.0}", r.other_iops),
+    ]).collect::<Vec<_>>();
+    print!("{}", table::format(
+        &["ID", "NAME", "R_IOPS", "R_BPS", "R_MS", "W_IOPS", "W_BPS", "W_MS", "O_IOPS"], &rows));
+}
+
+/// Measure and print I/O rates
+fn stats(cli: &Cli, lun: bool, port: bool) -> Result<()> {
+    let interval = Duration::try_from_secs_f64(cli.interval)
+        .with_context(|| format!("invalid interval {}", cli.interval))?;
+    let sample = || -> Result<_> {
+        let luns = lun.then(kconf::IoStatsList::luns).transpose()
+            .context("getting LUN statistics")?;
+        let ports = port.then(kconf::IoStatsList::ports).transpose()
+            .context("getting port statistics")?;
+        Ok((luns, ports))
+    };
+    let (luns0, ports0) = sample()?;
+    thread::sleep(interval);
+    let (luns1, ports1) = sample()?;
+
+    let mut dump = StatsDump{luns: None, ports: None};
+    if let (Some(earlier), Some(later)) = (luns0, luns1) {
+        let names = kconf::Ctllunlist::from_kernel().context("getting LUN list")?.lun.into_iter()
+            .filter_map(|l| Some((u32::try_from(l.id).ok()?, l.ctld_name?)))
+            .collect::<HashMap<_, _>>();
+        dump.luns = Some(rates(&earlier, &later, interval, &names));
+    }
+    if let (Some(earlier), Some(later)) = (ports0, ports1) {
+        let names = kconf::Ctlportlist::from_kernel().context("getting port list")?.targ_port
+            .into_iter()
+            .filter_map(|p| {
+                let target = p.cfiscsi_target.or(p.target).unwrap_or_default();
+                Some((p.id.parse().ok()?, format!("{} {}", p.port_name, target).trim().to_owned()))
+            }).collect::<HashMap<_, _>>();
+        dump.ports = Some(rates(&earlier, &later, interval, &names));
+    }
+
+    if cli.format == Format::Json {
+        let json = serde_json::to_string_pretty(&dump).context("serializing JSON")?;
+        println!("{}", json);
+    } else {
+        if let Some(luns) = &dump.luns {
+            rates_table(luns);
+        }
+        if let Some(ports) = &dump.ports {
+            if dump.luns.is_some() {
+                println!();
+            }
+            rates_table(ports);
+        }
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
     let cli: Cli = Cli::parse();
 
-    if cli.lun {
-        let xml = kconf::Ctllunlist::as_xml().context("getting LUN list")?;
-        println!("{}", xml);
+    // Naming a saved file implies dumping that list
+    let lun = cli.lun || cli.lun_xml.is_some();
+    let port = cli.port || cli.port_xml.is_some();
+    let session = cli.session || cli.session_xml.is_some();
+    let lun_xml = || read_xml(cli.lun_xml.as_deref(), "LUN", kconf::Ctllunlist::as_xml);
+    let port_xml = || read_xml(cli.port_xml.as_deref(), "port", kconf::Ctlportlist::as_xml);
+    let session_xml = || {
+        read_xml(cli.session_xml.as_deref(), "session", kconf::Ctlislist::as_xml)
+    };
+
+    if cli.stats {
+        // Show both LUNs and ports unless only one was asked for
+        return if lun || port {
+            stats(&cli, lun, port)
+        } else {
+            stats(&cli, true, true)
+        };
+    }
+
+    if cli.validate {
+        let mut unknown = Vec::new();
+        if lun {
+            unknown.extend(kconf::Ctllunlist::unknown_elements(&lun_xml()?)?);
+        }
+        if port {
+            unknown.extend(kconf::Ctlportlist::unknown_elements(&port_xml()?)?);
+        }
+        if session {
+            unknown.extend(kconf::Ctlislist::unknown_elements(&session_xml()?)?);
+        }
+        for u in unknown.iter() {
+            println!("unknown element {}", u);
+        }
+        if !unknown.is_empty() {
+            bail!("found {} unknown elements", unknown.len());
+        }
+        return Ok(());
     }
 
-    if cli.port {
-        let xml = kconf::Ctlportlist::as_xml().context("getting port list")?;
-        println!("{}", xml);
+    match cli.format {
+        Format::Xml => {
+            if lun {
+                println!("{}", lun_xml()?);
+            }
+            if port {
+                println!("{}", port_xml()?);
+            }
+            if session {
+                println!("{}", session_xml()?);
+            }
+        },
+        Format::Json => {
+            let mut dump = Dump{luns: None, ports: None, sessions: None};
+            if lun {
+                dump.luns = Some(kconf::Ctllunlist::from_xml(&lun_xml()?)?.lun);
+            }
+            if port {
+                dump.ports = Some(kconf::Ctlportlist::from_xml(&port_xml()?)?.targ_port);
+            }
+            if session {
+                dump.sessions = Some(kconf::Ctlislist::from_xml(&session_xml()?)?.connection);
+            }
+            let json = serde_json::to_string_pretty(&dump).context("serializing JSON")?;
+            println!("{}", json);
+        },
+        Format::Ucl => {
+            if session {
+                bail!("sessions cannot be dumped as UCL");
+            }
+            // A ctl.conf needs both lists, regardless of which were requested
+            let llist = kconf::Ctllunlist::from_xml(&lun_xml()?)?;
+            let plist = kconf::Ctlportlist::from_xml(&port_xml()?)?;
+            let conf = Conf::from_kernel(&llist, &plist).context("converting to ctl.conf")?;
+            print!("{}", conf.to_ucl());
+        },
+        Format::Table => {
+            if lun {
+                let llist = kconf::Ctllunlist::from_xml(&lun_xml()?)?;
+                print!("{}", table::luns(&serde_json::to_value(&llist.lun)?));
+            }
+            if port {
+                if lun {
+                    println!();
+                }
+                let plist = kconf::Ctlportlist::from_xml(&port_xml()?)?;
+                print!("{}", table::ports(&serde_json::to_value(&plist.targ_port)?));
+            }
+            if session {
+                if lun || port {
+                    println!();
+                }
+                let islist = kconf::Ctlislist::from_xml(&session_xml()?)?;
+                print!("{}", table::sessions(&serde_json::to_value(&islist.connection)?));
+            }
+        }
     }
 
     Ok(())
 }
-
@@ -538,3 +538,1082 @@ fn bindgen_test_layout_ctl_lun_list() {
         )
     );
 }
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum ctl_iscsi_type {
+    CTL_ISCSI_HANDOFF = 0,
+    CTL_ISCSI_LIST = 1,
+    CTL_ISCSI_LOGOUT = 2,
+    CTL_ISCSI_TERMINATE = 3,
+    CTL_ISCSI_LIMITS = 4,
+    CTL_ISCSI_LISTEN = 5,
+    CTL_ISCSI_ACCEPT = 6,
+    CTL_ISCSI_SEND = 7,
+    CTL_ISCSI_RECEIVE = 8,
+}
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum ctl_iscsi_digest {
+    CTL_ISCSI_DIGEST_NONE = 0,
+    CTL_ISCSI_DIGEST_CRC32C = 1,
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ctl_iscsi_handoff_params {
+    pub initiator_name: [::std::os::raw::c_char; 224usize],
+    pub initiator_addr: [::std::os::raw::c_char; 47usize],
+    pub initiator_alias: [::std::os::raw::c_char; 128usize],
+    pub initiator_isid: [u8; 6usize],
+    pub target_name: [::std::os::raw::c_char; 224usize],
+    pub socket: ::std::os::raw::c_int,
+    pub portal_group_tag: ::std::os::raw::c_int,
+    pub header_digest: ctl_iscsi_digest,
+    pub data_digest: ctl_iscsi_digest,
+    pub cmdsn: u32,
+    pub statsn: u32,
+    pub max_recv_data_segment_length: ::std::os::raw::c_int,
+    pub max_burst_length: ::std::os::raw::c_int,
+    pub first_burst_length: ::std::os::raw::c_int,
+    pub immediate_data: u32,
+    pub offload: [::std::os::raw::c_char; 8usize],
+    pub spare: ::std::os::raw::c_int,
+    pub max_send_data_segment_length: ::std::os::raw::c_int,
+}
+#[test]
+fn bindgen_test_layout_ctl_iscsi_handoff_params() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_iscsi_handoff_params> =
+        ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_iscsi_handoff_params>(),
+        688usize,
+        concat!("Size of: ", stringify!(ctl_iscsi_handoff_params))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_iscsi_handoff_params>(),
+        4usize,
+        concat!("Alignment of ", stringify!(ctl_iscsi_handoff_params))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).initiator_name) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(initiator_name)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).initiator_addr) as usize - ptr as usize },
+        224usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(initiator_addr)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).initiator_alias) as usize - ptr as usize },
+        271usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(initiator_alias)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).initiator_isid) as usize - ptr as usize },
+        399usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(initiator_isid)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).target_name) as usize - ptr as usize },
+        405usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(target_name)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).socket) as usize - ptr as usize },
+        632usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(socket)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).portal_group_tag) as usize - ptr as usize },
+        636usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(portal_group_tag)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).header_digest) as usize - ptr as usize },
+        640usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(header_digest)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).data_digest) as usize - ptr as usize },
+        644usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(data_digest)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).cmdsn) as usize - ptr as usize },
+        648usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(cmdsn)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).statsn) as usize - ptr as usize },
+        652usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(statsn)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            ::std::ptr::addr_of!((*ptr).max_recv_data_segment_length) as usize - ptr as usize
+        },
+        656usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(max_recv_data_segment_length)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).max_burst_length) as usize - ptr as usize },
+        660usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(max_burst_length)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).first_burst_length) as usize - ptr as usize },
+        664usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(first_burst_length)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).immediate_data) as usize - ptr as usize },
+        668usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(immediate_data)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).offload) as usize - ptr as usize },
+        672usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(offload)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).spare) as usize - ptr as usize },
+        680usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(spare)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            ::std::ptr::addr_of!((*ptr).max_send_data_segment_length) as usize - ptr as usize
+        },
+        684usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_handoff_params),
+            "::",
+            stringify!(max_send_data_segment_length)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ctl_iscsi_list_params {
+    pub alloc_len: u32,
+    pub conn_xml: *mut ::std::os::raw::c_char,
+    pub fill_len: u32,
+    pub spare: [::std::os::raw::c_int; 4usize],
+}
+#[test]
+fn bindgen_test_layout_ctl_iscsi_list_params() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_iscsi_list_params> =
+        ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_iscsi_list_params>(),
+        40usize,
+        concat!("Size of: ", stringify!(ctl_iscsi_list_params))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_iscsi_list_params>(),
+        8usize,
+        concat!("Alignment of ", stringify!(ctl_iscsi_list_params))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).alloc_len) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_list_params),
+            "::",
+            stringify!(alloc_len)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).conn_xml) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_list_params),
+            "::",
+            stringify!(conn_xml)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).fill_len) as usize - ptr as usize },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_list_params),
+            "::",
+            stringify!(fill_len)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).spare) as usize - ptr as usize },
+        20usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_list_params),
+            "::",
+            stringify!(spare)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ctl_iscsi_logout_params {
+    pub connection_id: ::std::os::raw::c_int,
+    pub initiator_name: [::std::os::raw::c_char; 224usize],
+    pub initiator_addr: [::std::os::raw::c_char; 47usize],
+    pub all: ::std::os::raw::c_int,
+    pub spare: [::std::os::raw::c_int; 4usize],
+}
+#[test]
+fn bindgen_test_layout_ctl_iscsi_logout_params() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_iscsi_logout_params> =
+        ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_iscsi_logout_params>(),
+        296usize,
+        concat!("Size of: ", stringify!(ctl_iscsi_logout_params))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_iscsi_logout_params>(),
+        4usize,
+        concat!("Alignment of ", stringify!(ctl_iscsi_logout_params))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).connection_id) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_logout_params),
+            "::",
+            stringify!(connection_id)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).initiator_name) as usize - ptr as usize },
+        4usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_logout_params),
+            "::",
+            stringify!(initiator_name)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).initiator_addr) as usize - ptr as usize },
+        228usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_logout_params),
+            "::",
+            stringify!(initiator_addr)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).all) as usize - ptr as usize },
+        276usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_logout_params),
+            "::",
+            stringify!(all)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).spare) as usize - ptr as usize },
+        280usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_logout_params),
+            "::",
+            stringify!(spare)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ctl_iscsi_terminate_params {
+    pub connection_id: ::std::os::raw::c_int,
+    pub initiator_name: [::std::os::raw::c_char; 224usize],
+    pub initiator_addr: [::std::os::raw::c_char; 224usize],
+    pub all: ::std::os::raw::c_int,
+    pub spare: [::std::os::raw::c_int; 4usize],
+}
+#[test]
+fn bindgen_test_layout_ctl_iscsi_terminate_params() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_iscsi_terminate_params> =
+        ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_iscsi_terminate_params>(),
+        472usize,
+        concat!("Size of: ", stringify!(ctl_iscsi_terminate_params))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_iscsi_terminate_params>(),
+        4usize,
+        concat!("Alignment of ", stringify!(ctl_iscsi_terminate_params))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).connection_id) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_terminate_params),
+            "::",
+            stringify!(connection_id)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).initiator_name) as usize - ptr as usize },
+        4usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_terminate_params),
+            "::",
+            stringify!(initiator_name)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).initiator_addr) as usize - ptr as usize },
+        228usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_terminate_params),
+            "::",
+            stringify!(initiator_addr)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).all) as usize - ptr as usize },
+        452usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_terminate_params),
+            "::",
+            stringify!(all)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).spare) as usize - ptr as usize },
+        456usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_terminate_params),
+            "::",
+            stringify!(spare)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ctl_iscsi_limits_params {
+    pub offload: [::std::os::raw::c_char; 8usize],
+    pub socket: ::std::os::raw::c_int,
+    pub spare: ::std::os::raw::c_int,
+    pub max_recv_data_segment_length: ::std::os::raw::c_int,
+    pub max_send_data_segment_length: ::std::os::raw::c_int,
+    pub max_burst_length: ::std::os::raw::c_int,
+    pub first_burst_length: ::std::os::raw::c_int,
+}
+#[test]
+fn bindgen_test_layout_ctl_iscsi_limits_params() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_iscsi_limits_params> =
+        ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_iscsi_limits_params>(),
+        32usize,
+        concat!("Size of: ", stringify!(ctl_iscsi_limits_params))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_iscsi_limits_params>(),
+        4usize,
+        concat!("Alignment of ", stringify!(ctl_iscsi_limits_params))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).offload) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_limits_params),
+            "::",
+            stringify!(offload)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).socket) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_limits_params),
+            "::",
+            stringify!(socket)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).spare) as usize - ptr as usize },
+        12usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_limits_params),
+            "::",
+            stringify!(spare)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            ::std::ptr::addr_of!((*ptr).max_recv_data_segment_length) as usize - ptr as usize
+        },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_limits_params),
+            "::",
+            stringify!(max_recv_data_segment_length)
+        )
+    );
+    assert_eq!(
+        unsafe {
+            ::std::ptr::addr_of!((*ptr).max_send_data_segment_length) as usize - ptr as usize
+        },
+        20usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_limits_params),
+            "::",
+            stringify!(max_send_data_segment_length)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).max_burst_length) as usize - ptr as usize },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_limits_params),
+            "::",
+            stringify!(max_burst_length)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).first_burst_length) as usize - ptr as usize },
+        28usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_limits_params),
+            "::",
+            stringify!(first_burst_length)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union ctl_iscsi_data {
+    pub handoff: ctl_iscsi_handoff_params,
+    pub list: ctl_iscsi_list_params,
+    pub logout: ctl_iscsi_logout_params,
+    pub terminate: ctl_iscsi_terminate_params,
+    pub limits: ctl_iscsi_limits_params,
+}
+#[test]
+fn bindgen_test_layout_ctl_iscsi_data() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_iscsi_data> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_iscsi_data>(),
+        688usize,
+        concat!("Size of: ", stringify!(ctl_iscsi_data))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_iscsi_data>(),
+        8usize,
+        concat!("Alignment of ", stringify!(ctl_iscsi_data))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).handoff) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_data),
+            "::",
+            stringify!(handoff)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).list) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_data),
+            "::",
+            stringify!(list)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).logout) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_data),
+            "::",
+            stringify!(logout)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).terminate) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_data),
+            "::",
+            stringify!(terminate)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).limits) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi_data),
+            "::",
+            stringify!(limits)
+        )
+    );
+}
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum ctl_iscsi_status {
+    CTL_ISCSI_OK = 0,
+    CTL_ISCSI_ERROR = 1,
+    CTL_ISCSI_LIST_NEED_MORE_SPACE = 2,
+    CTL_ISCSI_SESSION_NOT_FOUND = 3,
+}
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ctl_iscsi {
+    pub type_: ctl_iscsi_type,
+    pub data: ctl_iscsi_data,
+    pub status: ctl_iscsi_status,
+    pub error_str: [::std::os::raw::c_char; 160usize],
+}
+#[test]
+fn bindgen_test_layout_ctl_iscsi() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_iscsi> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_iscsi>(),
+        864usize,
+        concat!("Size of: ", stringify!(ctl_iscsi))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_iscsi>(),
+        8usize,
+        concat!("Alignment of ", stringify!(ctl_iscsi))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).type_) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi),
+            "::",
+            stringify!(type_)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).data) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi),
+            "::",
+            stringify!(data)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).status) as usize - ptr as usize },
+        696usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi),
+            "::",
+            stringify!(status)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).error_str) as usize - ptr as usize },
+        700usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_iscsi),
+            "::",
+            stringify!(error_str)
+        )
+    );
+}
+pub type __int64_t = ::std::os::raw::c_long;
+pub type __time_t = __int64_t;
+pub type time_t = __time_t;
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct timespec {
+    pub tv_sec: time_t,
+    pub tv_nsec: ::std::os::raw::c_long,
+}
+#[test]
+fn bindgen_test_layout_timespec() {
+    const UNINIT: ::std::mem::MaybeUninit<timespec> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<timespec>(),
+        16usize,
+        concat!("Size of: ", stringify!(timespec))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<timespec>(),
+        8usize,
+        concat!("Alignment of ", stringify!(timespec))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).tv_sec) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(timespec),
+            "::",
+            stringify!(tv_sec)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).tv_nsec) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(timespec),
+            "::",
+            stringify!(tv_nsec)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct bintime {
+    pub sec: time_t,
+    pub frac: u64,
+}
+#[test]
+fn bindgen_test_layout_bintime() {
+    const UNINIT: ::std::mem::MaybeUninit<bintime> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<bintime>(),
+        16usize,
+        concat!("Size of: ", stringify!(bintime))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<bintime>(),
+        8usize,
+        concat!("Alignment of ", stringify!(bintime))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).sec) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(bintime),
+            "::",
+            stringify!(sec)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).frac) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(bintime),
+            "::",
+            stringify!(frac)
+        )
+    );
+}
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum ctl_stat_types {
+    CTL_STATS_NO_IO = 0,
+    CTL_STATS_READ = 1,
+    CTL_STATS_WRITE = 2,
+}
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum ctl_stats_status {
+    CTL_SS_OK = 0,
+    CTL_SS_NEED_MORE_SPACE = 1,
+    CTL_SS_ERROR = 2,
+}
+impl ctl_stats_flags {
+    pub const CTL_STATS_FLAG_NONE: ctl_stats_flags = ctl_stats_flags(0);
+}
+impl ctl_stats_flags {
+    pub const CTL_STATS_FLAG_TIME_VALID: ctl_stats_flags = ctl_stats_flags(1);
+}
+impl ::std::ops::BitOr<ctl_stats_flags> for ctl_stats_flags {
+    type Output = Self;
+    #[inline]
+    fn bitor(self, other: Self) -> Self {
+        ctl_stats_flags(self.0 | other.0)
+    }
+}
+impl ::std::ops::BitOrAssign for ctl_stats_flags {
+    #[inline]
+    fn bitor_assign(&mut self, rhs: ctl_stats_flags) {
+        self.0 |= rhs.0;
+    }
+}
+impl ::std::ops::BitAnd<ctl_stats_flags> for ctl_stats_flags {
+    type Output = Self;
+    #[inline]
+    fn bitand(self, other: Self) -> Self {
+        ctl_stats_flags(self.0 & other.0)
+    }
+}
+impl ::std::ops::BitAndAssign for ctl_stats_flags {
+    #[inline]
+    fn bitand_assign(&mut self, rhs: ctl_stats_flags) {
+        self.0 &= rhs.0;
+    }
+}
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct ctl_stats_flags(pub ::std::os::raw::c_uint);
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ctl_io_stats {
+    pub item: u32,
+    pub bytes: [u64; 3usize],
+    pub operations: [u64; 3usize],
+    pub dmas: [u64; 3usize],
+    pub time: [bintime; 3usize],
+    pub dma_time: [bintime; 3usize],
+}
+#[test]
+fn bindgen_test_layout_ctl_io_stats() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_io_stats> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_io_stats>(),
+        176usize,
+        concat!("Size of: ", stringify!(ctl_io_stats))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_io_stats>(),
+        8usize,
+        concat!("Alignment of ", stringify!(ctl_io_stats))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).item) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_io_stats),
+            "::",
+            stringify!(item)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).bytes) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_io_stats),
+            "::",
+            stringify!(bytes)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).operations) as usize - ptr as usize },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_io_stats),
+            "::",
+            stringify!(operations)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).dmas) as usize - ptr as usize },
+        56usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_io_stats),
+            "::",
+            stringify!(dmas)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).time) as usize - ptr as usize },
+        80usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_io_stats),
+            "::",
+            stringify!(time)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).dma_time) as usize - ptr as usize },
+        128usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_io_stats),
+            "::",
+            stringify!(dma_time)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ctl_get_io_stats {
+    pub stats: *mut ctl_io_stats,
+    pub alloc_len: usize,
+    pub fill_len: usize,
+    pub first_item: ::std::os::raw::c_int,
+    pub num_items: ::std::os::raw::c_int,
+    pub status: ctl_stats_status,
+    pub flags: ctl_stats_flags,
+    pub timestamp: timespec,
+}
+#[test]
+fn bindgen_test_layout_ctl_get_io_stats() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_get_io_stats> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_get_io_stats>(),
+        56usize,
+        concat!("Size of: ", stringify!(ctl_get_io_stats))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_get_io_stats>(),
+        8usize,
+        concat!("Alignment of ", stringify!(ctl_get_io_stats))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).stats) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_get_io_stats),
+            "::",
+            stringify!(stats)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).alloc_len) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_get_io_stats),
+            "::",
+            stringify!(alloc_len)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).fill_len) as usize - ptr as usize },
+        16usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_get_io_stats),
+            "::",
+            stringify!(fill_len)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).first_item) as usize - ptr as usize },
+        24usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_get_io_stats),
+            "::",
+            stringify!(first_item)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).num_items) as usize - ptr as usize },
+        28usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_get_io_stats),
+            "::",
+            stringify!(num_items)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).status) as usize - ptr as usize },
+        32usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_get_io_stats),
+            "::",
+            stringify!(status)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).flags) as usize - ptr as usize },
+        36usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_get_io_stats),
+            "::",
+            stringify!(flags)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).timestamp) as usize - ptr as usize },
+        40usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_get_io_stats),
+            "::",
+            stringify!(timestamp)
+        )
+    );
+}
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct ctl_lun_map {
+    pub port: u32,
+    pub plun: u32,
+    pub lun: u32,
+}
+#[test]
+fn bindgen_test_layout_ctl_lun_map() {
+    const UNINIT: ::std::mem::MaybeUninit<ctl_lun_map> = ::std::mem::MaybeUninit::uninit();
+    let ptr = UNINIT.as_ptr();
+    assert_eq!(
+        ::std::mem::size_of::<ctl_lun_map>(),
+        12usize,
+        concat!("Size of: ", stringify!(ctl_lun_map))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<ctl_lun_map>(),
+        4usize,
+        concat!("Alignment of ", stringify!(ctl_lun_map))
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).port) as usize - ptr as usize },
+        0usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_lun_map),
+            "::",
+            stringify!(port)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).plun) as usize - ptr as usize },
+        4usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_lun_map),
+            "::",
+            stringify!(plun)
+        )
+    );
+    assert_eq!(
+        unsafe { ::std::ptr::addr_of!((*ptr).lun) as usize - ptr as usize },
+        8usize,
+        concat!(
+            "Offset of field: ",
+            stringify!(ctl_lun_map),
+            "::",
+            stringify!(lun)
+        )
+    );
+}
@@ -3,13 +3,18 @@
 
 #[cfg(not(test))]
 pub mod ioc {
-    use nix::ioctl_readwrite;
+    use nix::{ioctl_read, ioctl_readwrite, ioctl_write_ptr};
 
     use crate::ffi;
 
     ioctl_readwrite!(ctl_lun_list, 225, 0x22, ffi::ctl_lun_list);
     ioctl_readwrite!(ctl_port_list, 225, 0x27, ffi::ctl_lun_list);
     ioctl_readwrite!(ctl_lun_req, 225, 0x21, ffi::ctl_lun_req);
+    ioctl_readwrite!(ctl_iscsi, 225, 0x25, ffi::ctl_iscsi);
+    ioctl_write_ptr!(ctl_lun_map, 225, 0x28, ffi::ctl_lun_map);
+    ioctl_readwrite!(ctl_get_lun_stats, 225, 0x29, ffi::ctl_get_io_stats);
+    ioctl_readwrite!(ctl_get_port_stats, 225, 0x2a, ffi::ctl_get_io_stats);
+    ioctl_read!(diocgmediasize, b'd', 129, nix::libc::off_t);
 }
 // Mockall doesn't understand Nix's ioctl_readwrite! macro, so we need to write the mocks manually
 #[cfg(test)]
@@ -26,7 +31,109 @@ mod mockable {
             -> nix::Result<i32> { unimplemented!() }
         pub unsafe fn ctl_lun_req(_fd: RawFd, _data: *mut ffi::ctl_lun_req)
             -> nix::Result<i32> { unimplemented!() }
+        pub unsafe fn ctl_iscsi(_fd: RawFd, _data: *mut ffi::ctl_iscsi)
+            -> nix::Result<i32> { unimplemented!() }
+        pub unsafe fn ctl_lun_map(_fd: RawFd, _data: *const ffi::ctl_lun_map)
+            -> nix::Result<i32> { unimplemented!() }
+        pub unsafe fn ctl_get_lun_stats(_fd: RawFd, _data: *mut ffi::ctl_get_io_stats)
+            -> nix::Result<i32> { unimplemented!() }
+        pub unsafe fn ctl_get_port_stats(_fd: RawFd, _data: *mut ffi::ctl_get_io_stats)
+            -> nix::Result<i32> { unimplemented!() }
+        pub unsafe fn diocgmediasize(_fd: RawFd, _data: *mut nix::libc::off_t)
+            -> nix::Result<i32> { unimplemented!() }
     }
 }
 #[cfg(test)]
 pub use mockable::mock_ioc;
+
+// Mockall's expectations for free functions are global, so every test that sets them, in any
+// module, must hold the corresponding lock.
+/// Serialize ioc::ctl_lun_list and ioc::ctl_port_list calls and expectations
+#[cfg(test)]
+pub static CTL_LIST_MTX: std::sync::Mutex<()> = std::sync::Mutex::new(());
+/// Serialize ioc::ctl_lun_req calls and expectations
+#[cfg(test)]
+pub static CTL_LUN_REQ_MTX: std::sync::Mutex<()> = std::sync::Mutex::new(());
+/// Serialize ioc::ctl_iscsi calls and expectations
+#[cfg(test)]
+pub static CTL_ISCSI_MTX: std::sync::Mutex<()> = std::sync::Mutex::new(());
+/// Serialize ioc::ctl_lun_map calls and expectations
+#[cfg(test)]
+pub static CTL_LUN_MAP_MTX: std::sync::Mutex<()> = std::sync::Mutex::new(());
+/// Serialize ioc::ctl_get_lun_stats and ioc::ctl_get_port_stats calls and expectations
+#[cfg(test)]
+pub static CTL_STATS_MTX: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Complete a mocked CTL_LUN_LIST or CTL_PORT_LIST request, returning `xml`
+///
+/// # Safety
+///
+/// `list` must be the request passed to the mock, with a buffer of `alloc_len` bytes.
+#[cfg(test)]
+pub unsafe fn fill_lun_list(list: *mut crate::ffi::ctl_lun_list, xml: &str) {
+    let list = &mut *list;
+    assert!(xml.len() < list.alloc_len as usize, "XML doesn't fit in the buffer");
+    std::ptr::copy_nonoverlapping(xml.as_ptr(), list.lun_xml as *mut u8, xml.len());
+    *list.lun_xml.add(xml.len()) = 0;
+    list.fill_len = xml.len() as u32 + 1;
+    list.status = crate::ffi::ctl_lun_list_status::CTL_LUN_LIST_OK;
+}
+
+/// Complete a mocked CTL_ISCSI_LIST request, returning `xml`
+///
+/// # Safety
+///
+/// `req` must be the CTL_ISCSI_LIST request passed to the mock, with a buffer of `alloc_len`
+/// bytes.
+#[cfg(test)]
+pub unsafe fn fill_iscsi_list(req: *mut crate::ffi::ctl_iscsi, xml: &str) {
+    let req = &mut *req;
+    let list = &mut req.data.list;
+    assert!(xml.len() < list.alloc_len as usize, "XML doesn't fit in the buffer");
+    std::ptr::copy_nonoverlapping(xml.as_ptr(), list.conn_xml as *mut u8, xml.len());
+    *list.conn_xml.add(xml.len()) = 0;
+    list.fill_len = xml.len() as u32 + 1;
+    req.status = crate::ffi::ctl_iscsi_status::CTL_ISCSI_OK;
+}
+
+/// Complete a mocked CTL_GET_LUN_STATS or CTL_GET_PORT_STATS request, returning `stats`
+///
+/// # Safety
+///
+/// `req` must be the request passed to the mock, with a buffer of `alloc_len` bytes.
+#[cfg(test)]
+pub unsafe fn fill_stats(
+    req: *mut crate::ffi::ctl_get_io_stats,
+    stats: &[crate::ffi::ctl_io_stats]
+) {
+    let req = &mut *req;
+    let len = std::mem::size_of_val(stats);
+    req.num_items = stats.len() as i32;
+    if len > req.alloc_len {
+        req.status = crate::ffi::ctl_stats_status::CTL_SS_NEED_MORE_SPACE;
+        return;
+    }
+    std::ptr::copy_nonoverlapping(stats.as_ptr(), req.stats, stats.len());
+    req.fill_len = len;
+    req.status = crate::ffi::ctl_stats_status::CTL_SS_OK;
+    req.flags = crate::ffi::ctl_stats_flags::CTL_STATS_FLAG_TIME_VALID;
+}
+
+/// Make every CTL_LUN_LIST and CTL_PORT_LIST request return the given XML, until the result is
+/// dropped.  The caller must hold CTL_LIST_MTX.
+#[cfg(test)]
+pub fn expect_lists(luns: &'static str, ports: &'static str) -> impl Sized {
+    let lun_ctx = mock_ioc::ctl_lun_list_context();
+    lun_ctx.expect()
+        .returning(move |_fd, list| {
+            unsafe{ fill_lun_list(list, luns) };
+            Ok(0)
+        });
+    let port_ctx = mock_ioc::ctl_port_list_context();
+    port_ctx.expect()
+        .returning(move |_fd, list| {
+            unsafe{ fill_lun_list(list, ports) };
+            Ok(0)
+        });
+    (lun_ctx, port_ctx)
+}
@@ -0,0 +1,822 @@
+//! An iSNS client, as described by RFC 4171.
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::conf::Conf;
+
+/// iSNSP version defined by RFC 4171
+const ISNSP_VERSION: u16 = 1;
+/// Length of the iSNSP header
+const HDR_LEN: usize = 12;
+
+pub const FUNC_DEVATTRREG: u16 = 0x0001;
+pub const FUNC_DEVATTRQRY: u16 = 0x0002;
+pub const FUNC_DEVDEREG: u16 = 0x0004;
+/// Set in the function id of every response
+pub const FUNC_RESPONSE: u16 = 0x8000;
+
+pub const FLAG_CLIENT: u16 = 0x8000;
+pub const FLAG_SERVER: u16 = 0x4000;
+pub const FLAG_REPLACE: u16 = 0x1000;
+pub const FLAG_LAST_PDU: u16 = 0x0800;
+pub const FLAG_FIRST_PDU: u16 = 0x0400;
+
+pub const TAG_DELIMITER: u32 = 0;
+pub const TAG_ENTITY_IDENTIFIER: u32 = 1;
+pub const TAG_ENTITY_PROTOCOL: u32 = 2;
+pub const TAG_REGISTRATION_PERIOD: u32 = 6;
+pub const TAG_PORTAL_IP_ADDRESS: u32 = 16;
+pub const TAG_PORTAL_PORT: u32 = 17;
+pub const TAG_ISCSI_NAME: u32 = 32;
+pub const TAG_ISCSI_NODE_TYPE: u32 = 33;
+pub const TAG_ISCSI_ALIAS: u32 = 34;
+pub const TAG_PG_PORTAL_IP_ADDR: u32 = 49;
+pub const TAG_PG_PORTAL_PORT: u32 = 50;
+pub const TAG_PG_TAG: u32 = 51;
+
+pub const STATUS_SUCCESS: u32 = 0;
+pub const STATUS_SOURCE_UNKNOWN: u32 = 6;
+pub const STATUS_SOURCE_ABSENT: u32 = 7;
+pub const STATUS_SOURCE_UNAUTHORIZED: u32 = 8;
+pub const STATUS_NO_SUCH_ENTRY: u32 = 9;
+
+/// Entity Protocol value for iSCSI
+pub const ENTITY_PROTOCOL_ISCSI: u32 = 2;
+/// iSCSI Node Type bit for targets
+pub const NODE_TYPE_TARGET: u32 = 1;
+
+/// A single tag-length-value attribute
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Tlv {
+    pub tag: u32,
+    pub value: Vec<u8>,
+}
+
+impl Tlv {
+    /// Interpret the value as a NUL-terminated string
+    pub fn as_str(&self) -> Result<&str> {
+        let end = self.value.iter().position(|b| *b == 0).unwrap_or(self.value.len());
+        std::str::from_utf8(&self.value[..end])
+            .map_err(|_| anyhow!("iSNS attribute {} is not valid UTF-8", self.tag))
+    }
+
+    pub fn as_u32(&self) -> Result<u32> {
+        let b: [u8; 4] = self.value.as_slice().try_into()
+            .map_err(|_| anyhow!("iSNS attribute {} is not 32 bits", self.tag))?;
+        Ok(u32::from_be_bytes(b))
+    }
+
+    /// Interpret the value as an IP address, which is always encoded as IPv6
+    pub fn as_addr(&self) -> Result<IpAddr> {
+        let b: [u8; 16] = self.value.as_slice().try_into()
+            .map_err(|_| anyhow!("iSNS attribute {} is not an IP address", self.tag))?;
+        let v6 = std::net::Ipv6Addr::from(b);
+        Ok(match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(v6)
+        })
+    }
+}
+
+/// An iSNSP message.  Messages longer than one PDU are reassembled on receive.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pdu {
+    pub function: u16,
+    pub flags: u16,
+    pub xid: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Pdu {
+    pub fn new(function: u16, flags: u16, xid: u16) -> Self {
+        Pdu {function, flags, xid, payload: Vec::new()}
+    }
+
+    fn add(&mut self, tag: u32, value: &[u8]) {
+        let padded = value.len().next_multiple_of(4);
+        self.payload.extend_from_slice(&tag.to_be_bytes());
+        self.payload.extend_from_slice(&(padded as u32).to_be_bytes());
+        self.payload.extend_from_slice(value);
+        self.payload.resize(self.payload.len() + padded - value.len(), 0);
+    }
+
+    /// Add a string attribute.  It will be NUL-terminated and padded.
+    pub fn add_str(&mut self, tag: u32, value: &str) {
+        let mut v = Vec::with_capacity(value.len() + 1);
+        v.extend_from_slice(value.as_bytes());
+        v.push(0);
+        self.add(tag, &v);
+    }
+
+    pub fn add_u32(&mut self, tag: u32, value: u32) {
+        self.add(tag, &value.to_be_bytes());
+    }
+
+    /// Add an IP address attribute, encoding IPv4 addresses as IPv4-mapped IPv6 addresses
+    pub fn add_addr(&mut self, tag: u32, addr: IpAddr) {
+        let v6 = match addr {
+            IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+            IpAddr::V6(v6) => v6
+        };
+        self.add(tag, &v6.octets());
+    }
+
+    /// Add a TCP port attribute
+    pub fn add_port(&mut self, tag: u32, port: u16) {
+        self.add_u32(tag, port.into());
+    }
+
+    /// Add a zero-length attribute, as used to request attributes in queries
+    pub fn add_empty(&mut self, tag: u32) {
+        self.add(tag, &[]);
+    }
+
+    /// Add the delimiter between the source and message key attributes and the operating
+    /// attributes.
+    pub fn add_delim(&mut self) {
+        self.add(TAG_DELIMITER, &[]);
+    }
+
+    /// Encode as a single PDU
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let len = u16::try_from(self.payload.len())
+            .map_err(|_| anyhow!("iSNS message too long"))?;
+        let flags = self.flags | FLAG_FIRST_PDU | FLAG_LAST_PDU;
+        let mut buf = Vec::with_capacity(HDR_LEN + self.payload.len());
+        buf.extend_from_slice(&ISNSP_VERSION.to_be_bytes());
+        buf.extend_from_slice(&self.function.to_be_bytes());
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&self.xid.to_be_bytes());
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        Ok(buf)
+    }
+
+    /// Read one message, which may span several PDUs
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Self> {
+        let mut pdu: Option<Pdu> = None;
+        loop {
+            let mut hdr = [0u8; HDR_LEN];
+            r.read_exact(&mut hdr).context("reading iSNS header")?;
+            let field = |i: usize| u16::from_be_bytes([hdr[i], hdr[i + 1]]);
+            if field(0) != ISNSP_VERSION {
+                bail!("unsupported iSNSP version {}", field(0));
+            }
+            let mut payload = vec![0u8; field(4).into()];
+            r.read_exact(&mut payload).context("reading iSNS payload")?;
+            let flags = field(6);
+            let p = pdu.get_or_insert_with(|| Pdu::new(field(2), flags, field(8)));
+            p.flags |= flags;
+            p.payload.extend_from_slice(&payload);
+            if flags & FLAG_LAST_PDU != 0 {
+                break;
+            }
+        }
+        Ok(pdu.unwrap())
+    }
+
+    /// Decode the attributes.  For responses, this excludes the leading status code.
+    pub fn tlvs(&self) -> Result<Vec<Tlv>> {
+        let mut buf = &self.payload[..];
+        if self.function & FUNC_RESPONSE != 0 {
+            buf = buf.get(4..).ok_or_else(|| anyhow!("iSNS response too short"))?;
+        }
+        let mut tlvs = Vec::new();
+        while !buf.is_empty() {
+            if buf.len() < 8 {
+                bail!("truncated iSNS attribute");
+            }
+            let tag = u32::from_be_bytes(buf[0..4].try_into().unwrap());
+            let len = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize;
+            let value = buf.get(8..8 + len).ok_or_else(|| anyhow!("truncated iSNS attribute"))?;
+            tlvs.push(Tlv {tag, value: value.to_vec()});
+            buf = &buf[8 + len..];
+        }
+        Ok(tlvs)
+    }
+
+    /// Get the status code of a response
+    pub fn status(&self) -> Result<u32> {
+        let b = self.payload.get(0..4).ok_or_else(|| anyhow!("iSNS response too short"))?;
+        Ok(u32::from_be_bytes(b.try_into().unwrap()))
+    }
+}
+
+/// Everything that ctld registers about one target
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TargetReg {
+    pub alias: Option<String>,
+    /// Portal group tags, and the portals in each group
+    pub portal_groups: Vec<(u16, Vec<SocketAddr>)>,
+}
+
+/// Everything that ctld registers with an iSNS server
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Registration {
+    /// The Entity Identifier.  ctld uses the hostname.
+    pub eid: String,
+    /// Registration period, in seconds
+    pub period: u32,
+    /// Every portal of every portal group
+    pub portals: Vec<SocketAddr>,
+    pub targets: BTreeMap<String, TargetReg>,
+}
+
+impl Registration {
+    /// Build a registration for a configuration whose portal group tags have been assigned
+    pub fn from_conf(conf: &Conf, eid: String) -> Result<Self> {
+        let period = u32::try_from(conf.isns_period).context("isns-period")?;
+        // Like ctld(8), only register portal groups that are used by some target
+        let mut portals: Vec<SocketAddr> = conf.targets.values()
+            .flat_map(|target| target.portal_groups.iter())
+            .filter_map(|tpg| conf.portal_groups.get(&tpg.name))
+            .flat_map(|pg| pg.listen.iter().cloned())
+            .collect();
+        portals.sort();
+        portals.dedup();
+        let targets = conf.targets.iter()
+            .map(|(name, target)| {
+                let portal_groups = target.portal_groups.iter()
+                    .filter_map(|tpg| conf.portal_groups.get(&tpg.name))
+                    .filter_map(|pg| pg.tag.map(|tag| (tag, pg.listen.clone())))
+                    .collect();
+                let treg = TargetReg {
+                    alias: target.alias.clone(),
+                    portal_groups
+                };
+                (name.clone(), treg)
+            }).collect();
+        Ok(Registration {eid, period, portals, targets})
+    }
+
+    /// The source attribute for our requests.  Like ctld(8), use the first target's name.
+    fn source(&self) -> Option<&str> {
+        self.targets.keys().next().map(String::as_str)
+    }
+}
+
+/// A connection-less client for a single iSNS server.  Each request uses a new TCP connection.
+#[derive(Debug)]
+pub struct Client {
+    server: SocketAddr,
+    timeout: Duration,
+    xid: u16,
+}
+
+impl Client {
+    pub fn new(server: SocketAddr, timeout: Duration) -> Self {
+        Client {server, timeout, xid: 0}
+    }
+
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    /// Send a request and wait for its response, checking the response's status
+    pub fn transact(&mut self, req: Pdu) -> Result<Pdu> {
+        let resp = self.transact_raw(req)?;
+        let status = resp.status()?;
+        if status != STATUS_SUCCESS {
+            bail!("iSNS server {} returned error status {}", self.server, status);
+        }
+        Ok(resp)
+    }
+
+    /// Send a request and wait for its response, without checking the response's status
+    fn transact_raw(&mut self, mut req: Pdu) -> Result<Pdu> {
+        self.xid = self.xid.wrapping_add(1);
+        req.xid = self.xid;
+        let mut sock = TcpStream::connect_timeout(&self.server, self.timeout)
+            .with_context(|| format!("connecting to iSNS server {}", self.server))?;
+        sock.set_read_timeout(Some(self.timeout))?;
+        sock.set_write_timeout(Some(self.timeout))?;
+        sock.write_all(&req.to_bytes()?)
+            .with_context(|| format!("sending to iSNS server {}", self.server))?;
+        let resp = Pdu::read_from(&mut sock)
+            .with_context(|| format!("receiving from iSNS server {}", self.server))?;
+        if resp.function != req.function | FUNC_RESPONSE {
+            bail!("unexpected iSNS response function {:#x}", resp.function);
+        }
+        if resp.xid != req.xid {
+            bail!("unexpected iSNS transaction id {}", resp.xid);
+        }
+        Ok(resp)
+    }
+
+    /// Register our entity, portals, and targets, replacing any previous registration.
+    pub fn register(&mut self, reg: &Registration) -> Result<()> {
+        let Some(source) = reg.source() else {
+            return Ok(());
+        };
+        let mut req = Pdu::new(FUNC_DEVATTRREG, FLAG_CLIENT | FLAG_REPLACE, 0);
+        req.add_str(TAG_ISCSI_NAME, source);
+        req.add_str(TAG_ENTITY_IDENTIFIER, &reg.eid);
+        req.add_delim();
+        req.add_str(TAG_ENTITY_IDENTIFIER, &reg.eid);
+        req.add_u32(TAG_ENTITY_PROTOCOL, ENTITY_PROTOCOL_ISCSI);
+        req.add_u32(TAG_REGISTRATION_PERIOD, reg.period);
+        for portal in reg.portals.iter() {
+            req.add_addr(TAG_PORTAL_IP_ADDRESS, portal.ip());
+            req.add_port(TAG_PORTAL_PORT, portal.port());
+        }
+        for (name, target) in reg.targets.iter() {
+            req.add_str(TAG_ISCSI_NAME, name);
+            req.add_u32(TAG_ISCSI_NODE_TYPE, NODE_TYPE_TARGET);
+            if let Some(alias) = &target.alias {
+                req.add_str(TAG_ISCSI_ALIAS, alias);
+            }
+            for (tag, portals) in target.portal_groups.iter() {
+                req.add_u32(TAG_PG_TAG, (*tag).into());
+                for portal in portals.iter() {
+                    req.add_addr(TAG_PG_PORTAL_IP_ADDR, portal.ip());
+                    req.add_port(TAG_PG_PORTAL_PORT, portal.port());
+                }
+            }
+        }
+        self.transact(req).context("DevAttrReg")?;
+        Ok(())
+    }
+
+    /// Check whether our entity is still registered.  As a side effect, this refreshes the
+    /// registration period.
+    pub fn check(&mut self, reg: &Registration) -> Result<()> {
+        let Some(source) = reg.source() else {
+            return Ok(());
+        };
+        let mut req = Pdu::new(FUNC_DEVATTRQRY, FLAG_CLIENT, 0);
+        req.add_str(TAG_ISCSI_NAME, source);
+        req.add_str(TAG_ENTITY_IDENTIFIER, &reg.eid);
+        req.add_delim();
+        req.add_empty(TAG_ENTITY_IDENTIFIER);
+        req.add_empty(TAG_ISCSI_NAME);
+        let resp = self.transact(req).context("DevAttrQry")?;
+        let registered = resp.tlvs()?
+            .iter()
+            .filter(|tlv| tlv.tag == TAG_ISCSI_NAME)
+            .filter_map(|tlv| tlv.as_str().ok().map(String::from))
+            .collect::<Vec<_>>();
+        if reg.targets.keys().any(|name| !registered.contains(name)) {
+            bail!("registration is incomplete");
+        }
+        Ok(())
+    }
+
+    /// Deregister the whole entity
+    pub fn deregister(&mut self, reg: &Registration) -> Result<()> {
+        let Some(source) = reg.source() else {
+            return Ok(());
+        };
+        let mut req = Pdu::new(FUNC_DEVDEREG, FLAG_CLIENT, 0);
+        req.add_str(TAG_ISCSI_NAME, source);
+        req.add_delim();
+        req.add_str(TAG_ENTITY_IDENTIFIER, &reg.eid);
+        self.transact(req).context("DevDereg")?;
+        Ok(())
+    }
+
+    /// Deregister individual targets, leaving the rest of the entity registered
+    pub fn deregister_targets<'a, I>(&mut self, source: &str, names: I) -> Result<()>
+        where I: IntoIterator<Item = &'a String>
+    {
+        let mut req = Pdu::new(FUNC_DEVDEREG, FLAG_CLIENT, 0);
+        req.add_str(TAG_ISCSI_NAME, source);
+        req.add_delim();
+        for name in names {
+            req.add_str(TAG_ISCSI_NAME, name);
+        }
+        self.transact(req).context("DevDereg")?;
+        Ok(())
+    }
+}
+
+enum Msg {
+    Update(Registration),
+}
+
+/// Registers ctld with every configured iSNS server, in a background thread.  Registrations are
+/// refreshed every registration period, and are removed when the Service is dropped.
+#[derive(Debug)]
+pub struct Service {
+    tx: Option<mpsc::Sender<Msg>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Service {
+    pub fn new(servers: &[SocketAddr], timeout: Duration, reg: Registration) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let clients = servers.iter()
+            .map(|server| Client::new(*server, timeout))
+            .collect();
+        let thread = thread::spawn(move || Self::run(clients, reg, rx));
+        Service {
+            tx: Some(tx),
+            thread: Some(thread)
+        }
+    }
+
+    /// Start the iSNS service for a configuration, if it has any iSNS servers.
+    pub fn from_conf(conf: &Conf) -> Result<Option<Self>> {
+        if conf.isns_server.is_empty() {
+            return Ok(None);
+        }
+        let timeout = u64::try_from(conf.isns_timeout).context("isns-timeout")?;
+        let reg = Registration::from_conf(conf, hostname()?)?;
+        Ok(Some(Self::new(&conf.isns_server, Duration::from_secs(timeout), reg)))
+    }
+
+    /// Replace the registration, for example after a configuration reload.  Targets that are no
+    /// longer present will be deregistered.
+    pub fn update(&self, reg: Registration) {
+        if let Some(tx) = &self.tx {
+            // The only error is if the thread has died, which it will already have reported.
+            let _ = tx.send(Msg::Update(reg));
+        }
+    }
+
+    /// Replace the registration with one built from a reloaded configuration.  The servers are
+    /// not changed; to use different ones, start a new Service.
+    pub fn update_from_conf(&self, conf: &Conf) -> Result<()> {
+        self.update(Registration::from_conf(conf, hostname()?)?);
+        Ok(())
+    }
+
+    fn run(mut clients: Vec<Client>, mut reg: Registration, rx: mpsc::Receiver<Msg>) {
+        for client in clients.iter_mut() {
+            if let Err(e) = client.register(&reg) {
+                crate::error!("iSNS registration with {} failed: {:?}", client.server(), e);
+            }
+        }
+        let mut period = Duration::from_secs(reg.period.into());
+        loop {
+            match rx.recv_timeout(period) {
+                Ok(Msg::Update(newreg)) => {
+                    let removed = reg.targets.keys()
+                        .filter(|name| !newreg.targets.contains_key(*name))
+                        .collect::<Vec<_>>();
+                    for client in clients.iter_mut() {
+                        if let Some(source) = reg.source() {
+                            if !removed.is_empty() {
+                                if let Err(e) = client.deregister_targets(source, removed.clone()) {
+                                    crate::error!("iSNS deregistration with {} failed: {:?}",
+                                        client.server(), e);
+                                }
+                            }
+                        }
+                        if let Err(e) = client.register(&newreg) {
+                            crate::error!("iSNS registration with {} failed: {:?}",
+                                client.server(), e);
+                        }
+                    }
+                    reg = newreg;
+                    period = Duration::from_secs(reg.period.into());
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    for client in clients.iter_mut() {
+                        if client.check(&reg).is_err() {
+                            if let Err(e) = client.register(&reg) {
+                                crate::error!("iSNS registration with {} failed: {:?}",
+                                    client.server(), e);
+                            }
+                        }
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Disconnected) => break
+            }
+        }
+        for client in clients.iter_mut() {
+            if let Err(e) = client.deregister(&reg) {
+                crate::error!("iSNS deregistration with {} failed: {:?}", client.server(), e);
+            }
+        }
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        // Closing the channel tells the thread to deregister and exit
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn hostname() -> Result<String> {
+    nix::unistd::gethostname()
+        .context("gethostname")?
+        .into_string()
+        .map_err(|_| anyhow!("hostname is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        time::Instant,
+    };
+
+    /// A stand-in iSNS server, listening on loopback.  It records every request and answers
+    /// each with the given status.
+    struct FakeServer {
+        addr: SocketAddr,
+        requests: Arc<Mutex<Vec<Pdu>>>,
+        /// The function of each request, sent after it's recorded
+        notify: mpsc::Receiver<u16>,
+    }
+
+    impl FakeServer {
+        fn new(status: u32) -> Self {
+            Self::with_handler(move |req| {
+                let mut resp = Pdu::new(req.function | FUNC_RESPONSE, FLAG_SERVER, req.xid);
+                resp.payload.extend_from_slice(&status.to_be_bytes());
+                resp
+            })
+        }
+
+        /// Create a server that responds to each request with the handler's output
+        fn with_handler<F>(handler: F) -> Self
+            where F: Fn(&Pdu) -> Pdu + Send + 'static
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let requests2 = requests.clone();
+            let (tx, notify) = mpsc::channel();
+            thread::spawn(move || {
+                for sock in listener.incoming() {
+                    let mut sock = sock.unwrap();
+                    let Ok(req) = Pdu::read_from(&mut sock) else {
+                        continue;
+                    };
+                    let resp = handler(&req);
+                    let function = req.function;
+                    requests2.lock().unwrap().push(req);
+                    sock.write_all(&resp.to_bytes().unwrap()).unwrap();
+                    let _ = tx.send(function);
+                }
+            });
+            FakeServer {addr, requests, notify}
+        }
+
+        /// Wait until the server has answered a request with the given function
+        fn wait_for(&self, function: u16) {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match self.notify.recv_timeout(timeout) {
+                    Ok(f) if f == function => return,
+                    Ok(_) => (),
+                    Err(e) => panic!("no request with function {:#x}: {}", function, e)
+                }
+            }
+        }
+
+        fn requests(&self) -> Vec<Pdu> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn registration() -> Registration {
+        let mut targets = BTreeMap::new();
+        targets.insert(String::from("iqn.2018-10.org.example:disk0"), TargetReg {
+            alias: Some(String::from("zero")),
+            portal_groups: vec![(257, vec!["192.0.2.1:3260".parse().unwrap()])],
+        });
+        targets.insert(String::from("iqn.2018-10.org.example:disk1"), TargetReg {
+            alias: None,
+            portal_groups: vec![(258, vec!["[2001:db8::1]:3260".parse().unwrap()])],
+        });
+        Registration {
+            eid: String::from("myhost"),
+            period: 900,
+            portals: vec!["192.0.2.1:3260".parse().unwrap(), "[2001:db8::1]:3260".parse().unwrap()],
+            targets
+        }
+    }
+
+    fn strs(tlvs: &[Tlv], tag: u32) -> Vec<String> {
+        tlvs.iter()
+            .filter(|tlv| tlv.tag == tag)
+            .map(|tlv| tlv.as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    mod codec {
+        use super::*;
+
+        /// Every attribute should be padded to a multiple of 4 bytes
+        #[test]
+        fn padding() {
+            let mut pdu = Pdu::new(FUNC_DEVATTRREG, FLAG_CLIENT, 1);
+            pdu.add_str(TAG_ENTITY_IDENTIFIER, "abcd");
+            assert_eq!(pdu.payload, b"\0\0\0\x01\0\0\0\x08abcd\0\0\0\0");
+        }
+
+        #[test]
+        fn header() {
+            let mut pdu = Pdu::new(FUNC_DEVATTRREG, FLAG_CLIENT, 0x1234);
+            pdu.add_u32(TAG_ENTITY_PROTOCOL, ENTITY_PROTOCOL_ISCSI);
+            let buf = pdu.to_bytes().unwrap();
+            assert_eq!(&buf[0..12], b"\0\x01\0\x01\0\x0c\x8c\0\x12\x34\0\0");
+        }
+
+        /// Attributes should survive an encode/decode cycle
+        #[test]
+        fn round_trip() {
+            let mut pdu = Pdu::new(FUNC_DEVATTRREG, FLAG_CLIENT, 7);
+            pdu.add_str(TAG_ISCSI_NAME, "iqn.2018-10.org.example:disk0");
+            pdu.add_delim();
+            pdu.add_u32(TAG_REGISTRATION_PERIOD, 900);
+            pdu.add_addr(TAG_PORTAL_IP_ADDRESS, "192.0.2.1".parse().unwrap());
+            pdu.add_addr(TAG_PORTAL_IP_ADDRESS, "2001:db8::1".parse().unwrap());
+            pdu.add_port(TAG_PORTAL_PORT, 3260);
+            let buf = pdu.to_bytes().unwrap();
+            let pdu2 = Pdu::read_from(&mut &buf[..]).unwrap();
+            assert_eq!(pdu2.function, FUNC_DEVATTRREG);
+            assert_eq!(pdu2.xid, 7);
+            let tlvs = pdu2.tlvs().unwrap();
+            assert_eq!(tlvs.len(), 6);
+            assert_eq!(tlvs[0].as_str().unwrap(), "iqn.2018-10.org.example:disk0");
+            assert_eq!(tlvs[1], Tlv{tag: TAG_DELIMITER, value: vec![]});
+            assert_eq!(tlvs[2].as_u32().unwrap(), 900);
+            assert_eq!(tlvs[3].as_addr().unwrap(), "192.0.2.1".parse::<IpAddr>().unwrap());
+            assert_eq!(tlvs[4].as_addr().unwrap(), "2001:db8::1".parse::<IpAddr>().unwrap());
+            assert_eq!(tlvs[5].as_u32().unwrap(), 3260);
+        }
+
+        /// Messages spanning several PDUs should be reassembled
+        #[test]
+        fn multi_pdu() {
+            let mut buf = Vec::new();
+            let mut first = Pdu::new(FUNC_DEVATTRQRY | FUNC_RESPONSE, FLAG_SERVER, 3);
+            first.payload.extend_from_slice(&0u32.to_be_bytes());
+            let mut b = first.to_bytes().unwrap();
+            b[6] &= !((FLAG_LAST_PDU >> 8) as u8);
+            buf.extend_from_slice(&b);
+            let mut second = Pdu::new(FUNC_DEVATTRQRY | FUNC_RESPONSE, FLAG_SERVER, 3);
+            second.add_str(TAG_ISCSI_NAME, "foo");
+            buf.extend_from_slice(&second.to_bytes().unwrap());
+
+            let pdu = Pdu::read_from(&mut &buf[..]).unwrap();
+            assert_eq!(pdu.status().unwrap(), 0);
+            assert_eq!(strs(&pdu.tlvs().unwrap(), TAG_ISCSI_NAME), vec!["foo"]);
+        }
+
+        #[test]
+        fn truncated() {
+            let pdu = Pdu {
+                function: FUNC_DEVATTRREG,
+                flags: 0,
+                xid: 0,
+                payload: b"\0\0\0\x01\0\0\0\x08ab".to_vec()
+            };
+            pdu.tlvs().unwrap_err();
+        }
+    }
+
+    mod client {
+        use super::*;
+
+        #[test]
+        fn register() {
+            let server = FakeServer::new(0);
+            let mut client = Client::new(server.addr, Duration::from_secs(5));
+            client.register(&registration()).unwrap();
+
+            let reqs = server.requests();
+            assert_eq!(reqs.len(), 1);
+            assert_eq!(reqs[0].function, FUNC_DEVATTRREG);
+            assert_ne!(reqs[0].flags & FLAG_CLIENT, 0);
+            let tlvs = reqs[0].tlvs().unwrap();
+            let delim = tlvs.iter().position(|tlv| tlv.tag == TAG_DELIMITER).unwrap();
+            assert_eq!(strs(&tlvs[..delim], TAG_ISCSI_NAME), vec!["iqn.2018-10.org.example:disk0"]);
+            let ops = &tlvs[delim + 1..];
+            assert_eq!(strs(ops, TAG_ENTITY_IDENTIFIER), vec!["myhost"]);
+            assert_eq!(strs(ops, TAG_ISCSI_NAME),
+                vec!["iqn.2018-10.org.example:disk0", "iqn.2018-10.org.example:disk1"]);
+            assert_eq!(strs(ops, TAG_ISCSI_ALIAS), vec!["zero"]);
+            let pgts = ops.iter()
+                .filter(|tlv| tlv.tag == TAG_PG_TAG)
+                .map(|tlv| tlv.as_u32().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(pgts, vec![257, 258]);
+            let addrs = ops.iter()
+                .filter(|tlv| tlv.tag == TAG_PORTAL_IP_ADDRESS)
+                .map(|tlv| tlv.as_addr().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(addrs, vec![
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse::<IpAddr>().unwrap()
+            ]);
+        }
+
+        /// Error statuses from the server should be reported
+        #[test]
+        fn register_error() {
+            let server = FakeServer::new(3);   // Invalid Registration
+            let mut client = Client::new(server.addr, Duration::from_secs(5));
+            client.register(&registration()).unwrap_err();
+        }
+
+        /// A server that never responds should time out
+        #[test]
+        fn timeout() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = Client::new(listener.local_addr().unwrap(),
+                Duration::from_millis(100));
+            client.register(&registration()).unwrap_err();
+        }
+
+        #[test]
+        fn deregister() {
+            let server = FakeServer::new(0);
+            let mut client = Client::new(server.addr, Duration::from_secs(5));
+            client.deregister(&registration()).unwrap();
+
+            let reqs = server.requests();
+            assert_eq!(reqs[0].function, FUNC_DEVDEREG);
+            let tlvs = reqs[0].tlvs().unwrap();
+            assert_eq!(tlvs[1].tag, TAG_DELIMITER);
+            assert_eq!(tlvs[2].tag, TAG_ENTITY_IDENTIFIER);
+            assert_eq!(tlvs[2].as_str().unwrap(), "myhost");
+        }
+    }
+
+    mod service {
+        use super::*;
+
+        /// Register on startup and deregister on drop
+        #[test]
+        fn lifecycle() {
+            let server = FakeServer::new(0);
+            let service = Service::new(&[server.addr], Duration::from_secs(5), registration());
+            drop(service);
+
+            let functions = server.requests().iter().map(|r| r.function).collect::<Vec<_>>();
+            assert_eq!(functions, vec![FUNC_DEVATTRREG, FUNC_DEVDEREG]);
+        }
+
+        /// Targets removed by a reload should be deregistered
+        #[test]
+        fn update() {
+            let server = FakeServer::new(0);
+            let service = Service::new(&[server.addr], Duration::from_secs(5), registration());
+            let mut newreg = registration();
+            newreg.targets.remove("iqn.2018-10.org.example:disk1");
+            service.update(newreg);
+            drop(service);
+
+            let reqs = server.requests();
+            let functions = reqs.iter().map(|r| r.function).collect::<Vec<_>>();
+            assert_eq!(functions,
+                vec![FUNC_DEVATTRREG, FUNC_DEVDEREG, FUNC_DEVATTRREG, FUNC_DEVDEREG]);
+            let tlvs = reqs[1].tlvs().unwrap();
+            assert_eq!(strs(&tlvs[2..], TAG_ISCSI_NAME), vec!["iqn.2018-10.org.example:disk1"]);
+            let tlvs = reqs[2].tlvs().unwrap();
+            assert!(!strs(&tlvs, TAG_ISCSI_NAME).contains(&"iqn.2018-10.org.example:disk1".into()));
+        }
+
+        /// Registrations should be refreshed every period
+        #[test]
+        fn refresh() {
+            let server = FakeServer::new(0);
+            let mut reg = registration();
+            reg.period = 1;
+            let service = Service::new(&[server.addr], Duration::from_secs(5), reg);
+            server.wait_for(FUNC_DEVATTRQRY);
+            drop(service);
+
+            let functions = server.requests().iter().map(|r| r.function).collect::<Vec<_>>();
+            assert_eq!(functions[0], FUNC_DEVATTRREG);
+            assert_eq!(functions[1], FUNC_DEVATTRQRY);
+            assert_eq!(*functions.last().unwrap(), FUNC_DEVDEREG);
+        }
+
+        /// A reload that changes the period should take effect right away
+        #[test]
+        fn update_period() {
+            let server = FakeServer::new(0);
+            let service = Service::new(&[server.addr], Duration::from_secs(5), registration());
+            let mut newreg = registration();
+            newreg.period = 1;
+            service.update(newreg);
+            server.wait_for(FUNC_DEVATTRQRY);
+            drop(service);
+
+            let functions = server.requests().iter().map(|r| r.function).collect::<Vec<_>>();
+            assert_eq!(&functions[..3], &[FUNC_DEVATTRREG, FUNC_DEVATTRREG, FUNC_DEVATTRQRY]);
+        }
+    }
+}
@@ -1,19 +1,24 @@
 //! Read the state of CTL in the running kernel.
 use std::{
+    collections::HashMap,
     ffi::{CStr, OsString},
     mem,
     os::{
         fd::AsRawFd,
         unix::ffi::OsStringExt,
     },
-    process,
+    str::FromStr,
+    time::Duration,
 };
 
-use anyhow::{Context, Result};
-use serde_derive::Deserialize;
+use anyhow::{Context, Result, bail};
+use quick_xml::events::Event;
+use serde_derive::{Deserialize, Serialize};
 
 use crate::conf;
 use crate::ffi;
+use crate::kernel;
+use crate::metrics;
 #[mockall_double::double]
 use crate::ioc::ioc;
 
@@ -32,16 +37,20 @@ fn get_lunport_list(port: bool) -> Result<String>
         list.lun_xml = buf.as_mut_ptr() as *mut i8;
         let ctl_fd = crate::ctl();
         if port {
-            unsafe{ ioc::ctl_port_list(ctl_fd.as_raw_fd(), &mut list) }.context("CTL_PORT_LIST")?;
+            metrics::time("CTL_PORT_LIST", || unsafe {
+                ioc::ctl_port_list(ctl_fd.as_raw_fd(), &mut list)
+            }).context("CTL_PORT_LIST")?;
         } else {
-            unsafe{ ioc::ctl_lun_list(ctl_fd.as_raw_fd(), &mut list) }.context("CTL_LUN_LIST")?;
+            metrics::time("CTL_LUN_LIST", || unsafe {
+                ioc::ctl_lun_list(ctl_fd.as_raw_fd(), &mut list)
+            }).context("CTL_LUN_LIST")?;
         }
         match list.status {
             ffi::ctl_lun_list_status::CTL_LUN_LIST_ERROR => {
                 let error_str = unsafe{ CStr::from_ptr(list.error_str.as_ptr()) }
                     .to_string_lossy();
-                eprintln!("error returned from CTL_LUN_LIST: {}", error_str);
-                process::exit(1);
+                bail!("error returned from {}: {}",
+                    if port {"CTL_PORT_LIST"} else {"CTL_LUN_LIST"}, error_str);
             },
             ffi::ctl_lun_list_status::CTL_LUN_LIST_NEED_MORE_SPACE => {
                 bufsiz <<= 1;
@@ -59,10 +68,174 @@ fn get_lunport_list(port: bool) -> Result<String>
         .map_err(|_| anyhow::Error::msg("not a valid UTF-8 string"))
 }
 
-/// A CTL LUN published by the kernel.  The kernel may publish other fields too, which we ignore.
-#[derive(Debug, Deserialize)]
+/// Get the kernel's current iSCSI session list
+fn get_iscsi_list() -> Result<String>
+{
+    let mut bufsiz: usize = 4096;
+    let mut buf = Vec::<u8>::with_capacity(bufsiz);
+
+    // Safe because this is how C does it.
+    let mut req: ffi::ctl_iscsi = unsafe{ mem::zeroed() };
+    loop {
+        buf.reserve(bufsiz - buf.capacity());
+        req.type_ = ffi::ctl_iscsi_type::CTL_ISCSI_LIST;
+        {
+            // Safe because we know that we're listing, and the union is already zero-initialized
+            let list = unsafe{&mut req.data.list};
+            list.alloc_len = bufsiz as u32;
+            list.conn_xml = buf.as_mut_ptr() as *mut i8;
+        }
+        let ctl_fd = crate::ctl();
+        metrics::time("CTL_ISCSI_LIST", || unsafe {
+            ioc::ctl_iscsi(ctl_fd.as_raw_fd(), &mut req)
+        }).context("CTL_ISCSI_LIST")?;
+        match req.status {
+            ffi::ctl_iscsi_status::CTL_ISCSI_OK => break,
+            ffi::ctl_iscsi_status::CTL_ISCSI_LIST_NEED_MORE_SPACE => {
+                bufsiz <<= 1;
+            },
+            _ => {
+                let error_str = unsafe{ CStr::from_ptr(req.error_str.as_ptr()) }
+                    .to_string_lossy();
+                bail!("error returned from CTL_ISCSI_LIST: {}", error_str);
+            }
+        }
+    }
+    // Safe because the request succeeded
+    let mut fill_len = unsafe{ req.data.list.fill_len } as usize;
+    fill_len -= 1; // Trim trailing NUL
+    unsafe{ buf.set_len(fill_len) };
+    OsString::from_vec(buf)
+        .into_string()
+        .map_err(|_| anyhow::Error::msg("not a valid UTF-8 string"))
+}
+
+/// Get either the current LUN or port I/O statistics from the kernel
+fn get_io_stats(port: bool) -> Result<IoStatsList>
+{
+    let mut nitems: usize = 16;
+    let mut buf = Vec::<ffi::ctl_io_stats>::new();
+    let request = if port {"CTL_GET_PORT_STATS"} else {"CTL_GET_LUN_STATS"};
+
+    // Safe because this is how C does it.
+    let mut req: ffi::ctl_get_io_stats = unsafe{ mem::zeroed() };
+    loop {
+        buf.reserve(nitems);
+        req.stats = buf.as_mut_ptr();
+        req.alloc_len = nitems * mem::size_of::<ffi::ctl_io_stats>();
+        req.first_item = 0;
+        let ctl_fd = crate::ctl();
+        if port {
+            metrics::time(request, || unsafe {
+                ioc::ctl_get_port_stats(ctl_fd.as_raw_fd(), &mut req)
+            }).context(request)?;
+        } else {
+            metrics::time(request, || unsafe {
+                ioc::ctl_get_lun_stats(ctl_fd.as_raw_fd(), &mut req)
+            }).context(request)?;
+        }
+        match req.status {
+            ffi::ctl_stats_status::CTL_SS_OK => break,
+            ffi::ctl_stats_status::CTL_SS_NEED_MORE_SPACE => {
+                // num_items is the total number of items, not the number returned
+                nitems = (nitems << 1).max(req.num_items as usize);
+            },
+            ffi::ctl_stats_status::CTL_SS_ERROR => bail!("error returned from {}", request)
+        }
+    }
+    let len = req.fill_len / mem::size_of::<ffi::ctl_io_stats>();
+    assert!(len <= buf.capacity());
+    // Safe because the kernel initialized that many items
+    unsafe{ buf.set_len(len) };
+    let ts = req.timestamp;
+    Ok(IoStatsList {
+        timestamp: Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32),
+        time_valid: req.flags & ffi::ctl_stats_flags::CTL_STATS_FLAG_TIME_VALID ==
+            ffi::ctl_stats_flags::CTL_STATS_FLAG_TIME_VALID,
+        items: buf.iter().map(IoStats::from).collect()
+    })
+}
+
+/// Get the names of the fields that a struct's derived `Deserialize` implementation knows about.
+fn fields_of<'de, T: serde::Deserialize<'de>>() -> &'static [&'static str] {
+    use serde::de::{self, Deserializer, Visitor};
+
+    /// A Deserializer that records the field list it's given, and then gives up
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for Fields<'_> {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _visitor: V)
+            -> std::result::Result<V::Value, Self::Error>
+        {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V
+        ) -> std::result::Result<V::Value, Self::Error>
+        {
+            *self.0 = fields;
+            Err(de::Error::custom("only the field names are needed"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(Fields(&mut fields));
+    fields
+}
+
+/// Find the child elements of each `item` element that are not in `known`.  Returns each one as
+/// an "item id: <element>" string.
+fn unknown_children(xml: &str, item: &str, known: &[&str]) -> Result<Vec<String>> {
+    let mut reader = quick_xml::Reader::from_str(xml);
+    let mut depth = 0;
+    let mut id = String::new();
+    let mut unknown = Vec::new();
+    loop {
+        let (start, empty) = match reader.read_event().context("parsing XML")? {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::End(_) => {
+                depth -= 1;
+                continue;
+            },
+            Event::Eof => break,
+            _ => continue
+        };
+        let name = String::from_utf8_lossy(start.name().as_ref()).into_owned();
+        if depth == 1 && name == item {
+            id = match start.try_get_attribute("id").context("parsing XML")? {
+                Some(attr) => attr.unescape_value().context("parsing XML")?.into_owned(),
+                None => String::from("?")
+            };
+        } else if depth == 2 && !known.contains(&name.as_str()) {
+            unknown.push(format!("{} {}: <{}>", item, id, name));
+        }
+        if !empty {
+            depth += 1;
+        }
+    }
+    Ok(unknown)
+}
+
+/// A CTL LUN published by the kernel.
+///
+/// The kernel publishes each of the LUN's options as a child element.  Those that ctld knows
+/// about are parsed into `options`, and the rest are collected in `options.unknown`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(try_from = "HashMap<String, String>")]
 pub struct Lun {
-    #[serde(rename = "@id")]
     pub id: u64,
     pub backend_type: conf::Backend,
     pub lun_type: conf::DeviceType,
@@ -72,14 +245,52 @@ pub struct Lun {
     pub blocksize: u32,
     pub serial_number: String,
     pub device_id: String,
+    /// Not duplicated in `options`
     pub num_threads: Option<u32>,
     pub file: Option<String>,
     pub ctld_name: Option<String>,
+    pub options: conf::LunOptions,
 }
 
-#[derive(Debug, Deserialize)]
+impl TryFrom<HashMap<String, String>> for Lun {
+    type Error = anyhow::Error;
+
+    fn try_from(mut map: HashMap<String, String>) -> Result<Self> {
+        fn required<T>(map: &mut HashMap<String, String>, key: &str) -> Result<T>
+            where T: FromStr, T::Err: std::error::Error + Send + Sync + 'static
+        {
+            let v = map.remove(key).with_context(|| format!("missing field {}", key))?;
+            v.parse().with_context(|| format!("{}: invalid value \"{}\"", key, v))
+        }
+
+        fn optional<T>(map: &mut HashMap<String, String>, key: &str) -> Result<Option<T>>
+            where T: FromStr, T::Err: std::error::Error + Send + Sync + 'static
+        {
+            map.remove(key)
+                .map(|v| v.parse().with_context(|| format!("{}: invalid value \"{}\"", key, v)))
+                .transpose()
+        }
+
+        let lun = Lun {
+            id: required(&mut map, "@id")?,
+            backend_type: required(&mut map, "backend_type")?,
+            lun_type: required(&mut map, "lun_type")?,
+            size: required(&mut map, "size")?,
+            blocksize: required(&mut map, "blocksize")?,
+            serial_number: map.remove("serial_number").unwrap_or_default(),
+            device_id: map.remove("device_id").unwrap_or_default(),
+            num_threads: optional(&mut map, "num_threads")?,
+            file: map.remove("file"),
+            ctld_name: map.remove("ctld_name"),
+            options: conf::LunOptions::from_kernel(map),
+        };
+        Ok(lun)
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Ctllunlist {
-    #[serde(rename = "$text")]
+    #[serde(rename = "$text", skip_serializing)]
     pub text: Option<String>,
     #[serde(default)]
     pub lun: Vec<Lun>,
@@ -91,7 +302,7 @@ impl Ctllunlist {
         Self::from_xml(&xml)
     }
 
-    fn from_xml(xml: &str) -> Result<Self> {
+    pub fn from_xml(xml: &str) -> Result<Self> {
         let llist: Self = quick_xml::de::from_str(xml).context("parsing XML")?;
         Ok(llist)
     }
@@ -100,22 +311,104 @@ impl Ctllunlist {
     pub fn as_xml() -> Result<String> {
         get_lunport_list(false)
     }
+
+    /// List the elements in a LUN list that ctld doesn't understand.  They are all LUN options.
+    pub fn unknown_elements(xml: &str) -> Result<Vec<String>> {
+        let llist = Self::from_xml(xml)?;
+        Ok(llist.lun.iter()
+            .flat_map(|lun| {
+                lun.options.unknown.keys().map(move |k| format!("lun {}: <{}>", lun.id, k))
+            })
+            .collect())
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// The CTL frontend that owns a port
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Frontend {
+    #[serde(rename = "camsim")]
+    Camsim,
+    #[serde(rename = "camtgt")]
+    Camtgt,
+    #[serde(rename = "ha")]
+    Ha,
+    #[serde(rename = "ioctl")]
+    Ioctl,
+    #[serde(rename = "iscsi")]
+    Iscsi,
+    #[serde(rename = "tpc")]
+    Tpc,
+    #[serde(rename = "umass")]
+    Umass,
+    /// Any frontend that ctld doesn't know about
+    #[serde(other, rename = "other")]
+    Other
+}
+
+/// A port's transport, as defined by `ctl_port_type` in ctl_io.h
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(from = "u32", rename_all(serialize = "lowercase"))]
+pub enum PortType {
+    Fc,
+    Scsi,
+    Ioctl,
+    Internal,
+    Iscsi,
+    Sas,
+    Umass,
+    /// Any port type that ctld doesn't know about, like NVMF (0x80) or ISC (0x100)
+    Other(u32)
+}
+
+impl From<u32> for PortType {
+    fn from(v: u32) -> Self {
+        match v {
+            0x01 => PortType::Fc,
+            0x02 => PortType::Scsi,
+            0x04 => PortType::Ioctl,
+            0x08 => PortType::Internal,
+            0x10 => PortType::Iscsi,
+            0x20 => PortType::Sas,
+            0x40 => PortType::Umass,
+            _ => PortType::Other(v)
+        }
+    }
+}
+
+/// Deserialize one of CTL's boolean spellings: "YES"/"NO" or "on"/"off"
+fn de_bool<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
+    where D: serde::Deserializer<'de>
+{
+    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
+    match s.as_str() {
+        "YES" | "on" => Ok(true),
+        "NO" | "off" => Ok(false),
+        _ => Err(serde::de::Error::custom(format!("invalid boolean \"{}\"", s)))
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct TargPort {
-    #[serde(rename = "@id")]
+    #[serde(rename(deserialize = "@id"))]
     pub id: String,
-    #[serde(rename = "$text")]
+    #[serde(rename = "$text", skip_serializing)]
     pub text: Option<String>,
-    pub frontend_type: String,
-    pub port_type: String,
-    pub online: String,
+    pub frontend_type: Frontend,
+    pub port_type: PortType,
+    #[serde(deserialize_with = "de_bool")]
+    pub online: bool,
     pub port_name: String,
     pub physical_port: String,
     pub virtual_port: String,
-    pub lun: Option<TargetLun>,
-    pub lun_map: Option<String>,
+    /// The port's LUN map, if it has one.  Ports without a LUN map expose every LUN.
+    #[serde(default, rename(deserialize = "lun"))]
+    pub luns: Vec<TargetLun>,
+    /// Is the port's LUN map enabled?
+    #[serde(default, deserialize_with = "de_bool")]
+    pub lun_map: bool,
+    /// Initiators currently logged in to this port
+    #[serde(default, rename(deserialize = "initiator"))]
+    pub initiators: Vec<PortInitiator>,
     pub cfiscsi_portal_group_tag: Option<u16>,
     pub ctld_portal_group_name: Option<String>,
     pub cfiscsi_target: Option<String>,
@@ -124,25 +417,44 @@ pub struct TargPort {
     pub target: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// One entry in a port's LUN map
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct TargetLun {
-    #[serde(rename = "@id")]
-    pub id: String,
-    #[serde(rename = "$text")]
-    pub text: Option<String>,
+    /// The LUN number as seen by initiators on this port
+    #[serde(rename(deserialize = "@id"))]
+    pub id: u32,
+    /// The global CTL LUN number
+    #[serde(rename(deserialize = "$text"))]
+    pub lun: u32,
+}
+
+/// An initiator logged in to a port
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PortInitiator {
+    /// The initiator's index on this port
+    #[serde(rename(deserialize = "@id"))]
+    pub id: u32,
+    /// The initiator's name, such as its IQN and ISID
+    #[serde(rename(deserialize = "$text"))]
+    pub name: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Ctlportlist {
-    #[serde(rename = "$text")]
+    #[serde(rename = "$text", skip_serializing)]
     pub text: Option<String>,
+    #[serde(default)]
     pub targ_port: Vec<TargPort>,
 }
 
 impl Ctlportlist {
     pub fn from_kernel() -> Result<Self> {
         let xml = Self::as_xml()?;
-        let plist: Self = quick_xml::de::from_str(&xml).context("parsing XML")?;
+        Self::from_xml(&xml)
+    }
+
+    pub fn from_xml(xml: &str) -> Result<Self> {
+        let plist: Self = quick_xml::de::from_str(xml).context("parsing XML")?;
         Ok(plist)
     }
 
@@ -150,15 +462,428 @@ impl Ctlportlist {
     pub fn as_xml() -> Result<String> {
         get_lunport_list(true)
     }
+
+    /// List the elements in a port list that `TargPort` doesn't understand
+    pub fn unknown_elements(xml: &str) -> Result<Vec<String>> {
+        unknown_children(xml, "targ_port", fields_of::<TargPort>())
+    }
+}
+
+/// An iSCSI connection published by the kernel's cfiscsi frontend
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Connection {
+    #[serde(rename(deserialize = "@id"))]
+    pub id: u32,
+    pub initiator: String,
+    pub initiator_addr: String,
+    #[serde(default)]
+    pub initiator_alias: String,
+    pub target: String,
+    #[serde(default)]
+    pub target_alias: String,
+    pub target_portal_group_tag: u16,
+    pub header_digest: kernel::Digest,
+    pub data_digest: kernel::Digest,
+    pub max_recv_data_segment_length: u32,
+    pub max_send_data_segment_length: u32,
+    pub max_burst_length: u32,
+    pub first_burst_length: u32,
+    pub immediate_data: bool,
+    pub iser: bool,
+    /// The name of the offload driver, if any
+    #[serde(default)]
+    pub offload: String,
+}
+
+/// The kernel's list of iSCSI sessions
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Ctlislist {
+    #[serde(rename = "$text", skip_serializing)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub connection: Vec<Connection>,
+}
+
+impl Ctlislist {
+    pub fn from_kernel() -> Result<Self> {
+        let xml = Self::as_xml()?;
+        Self::from_xml(&xml)
+    }
+
+    pub fn from_xml(xml: &str) -> Result<Self> {
+        let slist: Self = quick_xml::de::from_str(xml).context("parsing XML")?;
+        Ok(slist)
+    }
+
+    /// Get the kernel's current iSCSI session list as XML
+    pub fn as_xml() -> Result<String> {
+        get_iscsi_list()
+    }
+
+    /// List the elements in a session list that `Connection` doesn't understand
+    pub fn unknown_elements(xml: &str) -> Result<Vec<String>> {
+        unknown_children(xml, "connection", fields_of::<Connection>())
+    }
+}
+
+/// I/O counters for one kind of operation
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct OpStats {
+    pub bytes: u64,
+    pub operations: u64,
+    pub dmas: u64,
+    /// Total time spent processing these operations.  Only valid if the kernel has CTL_TIME_IO.
+    pub time: Duration,
+    /// Total time spent in DMA.  Only valid if the kernel has CTL_TIME_IO.
+    pub dma_time: Duration,
+}
+
+impl OpStats {
+    fn new(stats: &ffi::ctl_io_stats, t: ffi::ctl_stat_types) -> Self {
+        /// Convert a bintime, whose fraction is in units of 2^-64 seconds
+        fn duration(bt: ffi::bintime) -> Duration {
+            Duration::new(bt.sec as u64, (((bt.frac >> 32) * 1_000_000_000) >> 32) as u32)
+        }
+
+        let i = t as usize;
+        OpStats {
+            bytes: stats.bytes[i],
+            operations: stats.operations[i],
+            dmas: stats.dmas[i],
+            time: duration(stats.time[i]),
+            dma_time: duration(stats.dma_time[i]),
+        }
+    }
+
+    fn since(&self, earlier: &Self) -> Self {
+        OpStats {
+            bytes: self.bytes.saturating_sub(earlier.bytes),
+            operations: self.operations.saturating_sub(earlier.operations),
+            dmas: self.dmas.saturating_sub(earlier.dmas),
+            time: self.time.saturating_sub(earlier.time),
+            dma_time: self.dma_time.saturating_sub(earlier.dma_time),
+        }
+    }
+}
+
+/// The cumulative I/O counters of one LUN or port, as reported by ctlstat(8)
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct IoStats {
+    /// The CTL LUN or port number
+    pub item: u32,
+    pub read: OpStats,
+    pub write: OpStats,
+    /// Commands that transfer no data, like TEST UNIT READY
+    pub other: OpStats,
+}
+
+impl IoStats {
+    /// The I/O done between `earlier` and `self`
+    pub fn since(&self, earlier: &Self) -> Self {
+        IoStats {
+            item: self.item,
+            read: self.read.since(&earlier.read),
+            write: self.write.since(&earlier.write),
+            other: self.other.since(&earlier.other),
+        }
+    }
+}
+
+impl From<&ffi::ctl_io_stats> for IoStats {
+    fn from(stats: &ffi::ctl_io_stats) -> Self {
+        IoStats {
+            item: stats.item,
+            read: OpStats::new(stats, ffi::ctl_stat_types::CTL_STATS_READ),
+            write: OpStats::new(stats, ffi::ctl_stat_types::CTL_STATS_WRITE),
+            other: OpStats::new(stats, ffi::ctl_stat_types::CTL_STATS_NO_IO),
+        }
+    }
+}
+
+/// The kernel's I/O counters for every LUN or every port
+#[derive(Clone, Debug, Serialize)]
+pub struct IoStatsList {
+    /// When the counters were read, as time since boot
+    pub timestamp: Duration,
+    /// Whether the kernel keeps track of I/O time
+    pub time_valid: bool,
+    pub items: Vec<IoStats>,
+}
+
+impl IoStatsList {
+    /// Get every LUN's I/O counters
+    pub fn luns() -> Result<Self> {
+        get_io_stats(false)
+    }
+
+    /// Get every port's I/O counters
+    pub fn ports() -> Result<Self> {
+        get_io_stats(true)
+    }
 }
 
 #[cfg(test)]
 mod t {
     use super::*;
 
+    mod ctl_is_list {
+        use super::*;
+
+        /// Parse a Ctlislist that contains no sessions
+        #[test]
+        fn blank() {
+            let xml = "<ctlislist></ctlislist>";
+            let slist = Ctlislist::from_xml(xml).unwrap();
+            assert!(slist.text.is_none());
+            assert!(slist.connection.is_empty());
+        }
+
+        /// Parse a Ctlislist containing two sessions, one of them with an alias and digests
+        #[test]
+        fn two() {
+            let xml =
+"<ctlislist>
+<connection id=\"3\"><initiator>iqn.1994-09.org.freebsd:a</initiator><initiator_addr>192.0.2.1</initiator_addr><initiator_alias>alpha</initiator_alias><target>iqn.2018-10.org.example:disk0</target><target_alias></target_alias><target_portal_group_tag>257</target_portal_group_tag><header_digest>CRC32C</header_digest><data_digest>None</data_digest><max_recv_data_segment_length>262144</max_recv_data_segment_length><max_send_data_segment_length>131072</max_send_data_segment_length><max_burst_length>1048576</max_burst_length><first_burst_length>65536</first_burst_length><immediate_data>1</immediate_data><iser>0</iser><offload>icl</offload></connection>
+<connection id=\"4\"><initiator>iqn.1994-09.org.freebsd:b</initiator><initiator_addr>2001:db8::1</initiator_addr><initiator_alias></initiator_alias><target>iqn.2018-10.org.example:disk1</target><target_alias></target_alias><target_portal_group_tag>258</target_portal_group_tag><header_digest>None</header_digest><data_digest>None</data_digest><max_recv_data_segment_length>8192</max_recv_data_segment_length><max_send_data_segment_length>8192</max_send_data_segment_length><max_burst_length>262144</max_burst_length><first_burst_length>65536</first_burst_length><immediate_data>0</immediate_data><iser>0</iser><offload>icl</offload></connection>
+</ctlislist>";
+            let slist = Ctlislist::from_xml(xml).unwrap();
+            assert_eq!(slist.connection.len(), 2);
+            let c = &slist.connection[0];
+            assert_eq!(c.id, 3);
+            assert_eq!(c.initiator, "iqn.1994-09.org.freebsd:a");
+            assert_eq!(c.initiator_addr, "192.0.2.1");
+            assert_eq!(c.initiator_alias, "alpha");
+            assert_eq!(c.target, "iqn.2018-10.org.example:disk0");
+            assert_eq!(c.target_alias, "");
+            assert_eq!(c.target_portal_group_tag, 257);
+            assert_eq!(c.header_digest, kernel::Digest::Crc32c);
+            assert_eq!(c.data_digest, kernel::Digest::None);
+            assert_eq!(c.max_recv_data_segment_length, 262144);
+            assert_eq!(c.max_send_data_segment_length, 131072);
+            assert_eq!(c.max_burst_length, 1048576);
+            assert_eq!(c.first_burst_length, 65536);
+            assert!(c.immediate_data);
+            assert!(!c.iser);
+            assert_eq!(c.offload, "icl");
+            let c = &slist.connection[1];
+            assert_eq!(c.id, 4);
+            assert_eq!(c.initiator_addr, "2001:db8::1");
+            assert_eq!(c.initiator_alias, "");
+            assert!(!c.immediate_data);
+        }
+
+        /// Elements that Connection doesn't know about are reported
+        #[test]
+        fn unknown_elements() {
+            let xml =
+"<ctlislist>
+<connection id=\"3\"><initiator>iqn.1994-09.org.freebsd:a</initiator><initiator_addr>192.0.2.1</initiator_addr><target>iqn.2018-10.org.example:disk0</target><target_portal_group_tag>257</target_portal_group_tag><header_digest>None</header_digest><data_digest>None</data_digest><max_recv_data_segment_length>8192</max_recv_data_segment_length><max_send_data_segment_length>8192</max_send_data_segment_length><max_burst_length>262144</max_burst_length><first_burst_length>65536</first_burst_length><immediate_data>0</immediate_data><iser>0</iser><tcp_nodelay>1</tcp_nodelay></connection>
+</ctlislist>";
+            assert_eq!(Ctlislist::unknown_elements(xml).unwrap(),
+                vec!["connection 3: <tcp_nodelay>"]);
+        }
+    }
+
+    mod ctl_port_list {
+        use super::*;
+
+        /// The ports that every CTL instance has, before ctld creates any
+        #[test]
+        fn builtin() {
+            let xml =
+"<ctlportlist>
+<targ_port id=\"0\">
+	<frontend_type>camsim</frontend_type>
+	<port_type>8</port_type>
+	<online>NO</online>
+	<port_name>camsim</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<target>naa.5000000e6f7a6501</target>
+	<port>naa.5000000e6f7a6503</port>
+</targ_port>
+<targ_port id=\"1\">
+	<frontend_type>tpc</frontend_type>
+	<port_type>8</port_type>
+	<online>YES</online>
+	<port_name>tpc</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+</targ_port>
+<targ_port id=\"2\">
+	<frontend_type>ioctl</frontend_type>
+	<port_type>4</port_type>
+	<online>NO</online>
+	<port_name>ioctl</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+</targ_port>
+</ctlportlist>";
+            let plist = Ctlportlist::from_xml(xml).unwrap();
+            assert_eq!(plist.targ_port.len(), 3);
+            assert_eq!(plist.targ_port[0].frontend_type, Frontend::Camsim);
+            assert_eq!(plist.targ_port[0].port_type, PortType::Internal);
+            assert!(!plist.targ_port[0].online);
+            assert_eq!(plist.targ_port[0].target.as_deref(), Some("naa.5000000e6f7a6501"));
+            assert_eq!(plist.targ_port[1].frontend_type, Frontend::Tpc);
+            assert!(plist.targ_port[1].online);
+            assert_eq!(plist.targ_port[2].frontend_type, Frontend::Ioctl);
+            assert_eq!(plist.targ_port[2].port_type, PortType::Ioctl);
+            for port in plist.targ_port.iter() {
+                assert!(!port.lun_map);
+                assert!(port.luns.is_empty());
+            }
+        }
+
+        /// An iSCSI port mapping several LUNs
+        #[test]
+        fn iscsi_multi_lun() {
+            let xml =
+"<ctlportlist>
+<targ_port id=\"3\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_state>1</cfiscsi_state>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<ctld_portal_group_name>pg0</ctld_portal_group_name>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">2</lun>
+	<lun id=\"1\">0</lun>
+	<lun id=\"5\">7</lun>
+	<initiator id=\"1\">iqn.2018-10.org.example:i0,i,0x023d00000000</initiator>
+	<target>iqn.2018-10.org.example:t0</target>
+	<port>iqn.2018-10.org.example:t0,t,0x0101</port>
+</targ_port>
+<targ_port id=\"4\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>1</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t1</cfiscsi_target>
+	<cfiscsi_state>1</cfiscsi_state>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<ctld_portal_group_name>pg0</ctld_portal_group_name>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">1</lun>
+	<target>iqn.2018-10.org.example:t1</target>
+	<port>iqn.2018-10.org.example:t1,t,0x0101</port>
+</targ_port>
+</ctlportlist>";
+            let plist = Ctlportlist::from_xml(xml).unwrap();
+            assert_eq!(plist.targ_port.len(), 2);
+            let port = &plist.targ_port[0];
+            assert_eq!(port.frontend_type, Frontend::Iscsi);
+            assert_eq!(port.port_type, PortType::Iscsi);
+            assert!(port.online);
+            assert!(port.lun_map);
+            assert_eq!(port.luns, vec![
+                TargetLun{id: 0, lun: 2},
+                TargetLun{id: 1, lun: 0},
+                TargetLun{id: 5, lun: 7},
+            ]);
+            assert_eq!(port.cfiscsi_portal_group_tag, Some(257));
+            assert_eq!(port.ctld_portal_group_name.as_deref(), Some("pg0"));
+            assert_eq!(port.cfiscsi_target.as_deref(), Some("iqn.2018-10.org.example:t0"));
+            assert_eq!(port.initiators, vec![PortInitiator{
+                id: 1,
+                name: String::from("iqn.2018-10.org.example:i0,i,0x023d00000000")
+            }]);
+            assert_eq!(plist.targ_port[1].luns, vec![TargetLun{id: 0, lun: 1}]);
+            assert!(plist.targ_port[1].initiators.is_empty());
+
+            // dump's JSON format shouldn't leak XML-isms like "@id"
+            let json = serde_json::to_value(&plist.targ_port[0]).unwrap();
+            assert_eq!(json["id"], "3");
+            assert_eq!(json["frontend_type"], "iscsi");
+            assert_eq!(json["port_type"], "iscsi");
+            assert_eq!(json["online"], true);
+            assert_eq!(json["luns"][2], serde_json::json!({"id": 5, "lun": 7}));
+            assert!(json.get("text").is_none());
+        }
+
+        /// Elements that TargPort doesn't know about are reported, but known ones aren't
+        #[test]
+        fn unknown_elements() {
+            let xml =
+"<ctlportlist>
+<targ_port id=\"3\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_target_alias>my target</cfiscsi_target_alias>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">3</lun>
+	<initiator id=\"0\">iqn.2018-10.org.example:i0,i,0x023d00000000</initiator>
+	<new_flag/>
+</targ_port>
+<targ_port id=\"4\">
+	<frontend_type>tpc</frontend_type>
+	<port_type>8</port_type>
+	<online>YES</online>
+	<port_name>tpc</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+</targ_port>
+</ctlportlist>";
+            assert_eq!(Ctlportlist::unknown_elements(xml).unwrap(), vec![
+                "targ_port 3: <cfiscsi_target_alias>",
+                "targ_port 3: <new_flag>",
+            ]);
+        }
+
+        /// Frontends that ctld doesn't know about shouldn't prevent parsing the list
+        #[test]
+        fn unknown_frontend() {
+            let xml =
+"<ctlportlist>
+<targ_port id=\"0\">
+	<frontend_type>nvmf</frontend_type>
+	<port_type>128</port_type>
+	<online>NO</online>
+	<port_name>nvmf</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+</targ_port>
+</ctlportlist>";
+            let plist = Ctlportlist::from_xml(xml).unwrap();
+            assert_eq!(plist.targ_port[0].frontend_type, Frontend::Other);
+            assert_eq!(plist.targ_port[0].port_type, PortType::Other(0x80));
+        }
+
+        #[test]
+        fn bad_online() {
+            let xml =
+"<ctlportlist>
+<targ_port id=\"0\">
+	<frontend_type>tpc</frontend_type>
+	<port_type>8</port_type>
+	<online>MAYBE</online>
+	<port_name>tpc</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+</targ_port>
+</ctlportlist>";
+            Ctlportlist::from_xml(xml).unwrap_err();
+        }
+    }
+
     mod ctl_lun_list {
         use super::*;
 
+        use crate::ioc::{CTL_LIST_MTX, mock_ioc};
+
         /// Parse a CtlLunlist that contains no LUNs.
         #[test]
         fn blank() {
@@ -270,7 +995,219 @@ mod t {
 </lun>
 </ctllunlist>";
             let llist = Ctllunlist::from_xml(xml).unwrap();
-            assert_eq!(llist.lun[0].file, Some(String::from("/tmp/testlun")));
+            let lun = &llist.lun[0];
+            assert_eq!(lun.file, Some(String::from("/tmp/testlun")));
+            assert_eq!(lun.num_threads, Some(32));
+            assert_eq!(lun.options.num_threads, None);
+            assert_eq!(lun.options.vendor.as_deref(), Some("foo"));
+            assert_eq!(lun.options.uuid.as_deref(), Some("2dec855d-895c-40a1-8e98-8cba77d79777"));
+            assert_eq!(lun.options.ha_role, Some(conf::HaRole::Primary));
+            assert_eq!(lun.options.readcache, Some(false));
+            assert_eq!(lun.options.readonly, Some(true));
+            assert_eq!(lun.options.reordering, Some(conf::Reordering::Unrestricted));
+            assert_eq!(lun.options.serseq, Some(conf::Serseq::On));
+            assert_eq!(lun.options.pblocksize, Some(4096));
+            assert_eq!(lun.options.rpm, Some(7200));
+            assert_eq!(lun.options.provisioning_type, Some(conf::ProvisioningType::Thin));
+            assert_eq!(lun.options.unmap, Some(true));
+            assert_eq!(lun.options.used_threshold, Some(81));
+            assert_eq!(lun.options.writecache, Some(false));
+            // Anything unrecognized is kept verbatim
+            assert_eq!(lun.options.unknown.len(), 1);
+            assert_eq!(lun.options.unknown["avail-threashold"], "20");
+
+            // dump's JSON format reports options as strings, just like the kernel
+            let json = serde_json::to_value(lun).unwrap();
+            assert_eq!(json["id"], 0);
+            assert_eq!(json["backend_type"], "block");
+            assert_eq!(json["lun_type"], "disk");
+            assert_eq!(json["options"]["readonly"], "on");
+            assert_eq!(json["options"]["avail-threashold"], "20");
+        }
+
+        /// Unrecognized LUN options are reported as unknown elements
+        #[test]
+        fn unknown_elements() {
+            let xml =
+"<ctllunlist>
+<lun id=\"7\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>MYSERIAL0000</serial_number>
+	<device_id>MYDEVID0000</device_id>
+	<vendor>FreeBSD</vendor>
+	<brand_new_option>on</brand_new_option>
+</lun>
+</ctllunlist>";
+            assert_eq!(Ctllunlist::unknown_elements(xml).unwrap(),
+                vec!["lun 7: <brand_new_option>"]);
+        }
+
+        /// A kernel LUN's options can be compared with the configured ones
+        #[test]
+        fn options_drift() {
+            let xml =
+"<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>MYSERIAL0000</serial_number>
+	<device_id>MYDEVID0000</device_id>
+	<vendor>FreeBSD</vendor>
+	<readonly>off</readonly>
+</lun>
+</ctllunlist>";
+            let llist = Ctllunlist::from_xml(xml).unwrap();
+            let mut expected = conf::LunOptions {
+                vendor: Some(String::from("FreeBSD")),
+                readonly: Some(false),
+                ..Default::default()
+            };
+            assert_eq!(llist.lun[0].options, expected);
+            expected.readonly = Some(true);
+            assert_ne!(llist.lun[0].options, expected);
+        }
+
+        /// An error reported by the kernel is returned, not fatal
+        #[test]
+        fn error() {
+            let _m = CTL_LIST_MTX.lock().unwrap();
+            let ctx = mock_ioc::ctl_lun_list_context();
+            ctx.expect()
+                .times(1)
+                .returning(|_fd, list| {
+                    let list = unsafe{ &mut *list };
+                    for (d, s) in list.error_str.iter_mut().zip(b"no memory".iter()) {
+                        *d = *s as std::os::raw::c_char;
+                    }
+                    list.status = ffi::ctl_lun_list_status::CTL_LUN_LIST_ERROR;
+                    Ok(0)
+                });
+            let e = format!("{:#}", Ctllunlist::as_xml().unwrap_err());
+            assert_eq!(e, "error returned from CTL_LUN_LIST: no memory");
+        }
+
+        /// An option value that ctld can't parse is kept as an unknown option
+        #[test]
+        fn odd_option() {
+            let xml =
+"<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<readonly>maybe</readonly>
+	<rpm>7200</rpm>
+</lun>
+</ctllunlist>";
+            let llist = Ctllunlist::from_xml(xml).unwrap();
+            let options = &llist.lun[0].options;
+            assert_eq!(options.readonly, None);
+            assert_eq!(options.rpm, Some(7200));
+            assert_eq!(options.unknown.get("readonly").map(String::as_str), Some("maybe"));
+        }
+
+        /// A LUN without a mandatory field is an error
+        #[test]
+        fn missing_field() {
+            let xml =
+"<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<blocksize>512</blocksize>
+</lun>
+</ctllunlist>";
+            let e = format!("{:#}", Ctllunlist::from_xml(xml).unwrap_err());
+            assert!(e.contains("missing field size"), "{}", e);
+        }
+    }
+
+    mod io_stats {
+        use super::*;
+
+        use crate::ioc::{CTL_STATS_MTX, fill_stats, mock_ioc};
+
+        fn stats(item: u32, bytes: u64) -> ffi::ctl_io_stats {
+            // Safe because it's plain data
+            let mut stats: ffi::ctl_io_stats = unsafe{ mem::zeroed() };
+            stats.item = item;
+            stats.bytes = [0, bytes, 2 * bytes];
+            stats.operations = [1, 2, 3];
+            stats.time[1] = ffi::bintime{sec: 1, frac: 1 << 63};
+            stats
+        }
+
+        #[test]
+        fn luns() {
+            let _m = CTL_STATS_MTX.lock().unwrap();
+            let ctx = mock_ioc::ctl_get_lun_stats_context();
+            ctx.expect()
+                .times(1)
+                .returning(|_fd, req| {
+                    unsafe{ fill_stats(req, &[stats(0, 4096), stats(3, 512)]) };
+                    Ok(0)
+                });
+            let slist = IoStatsList::luns().unwrap();
+            assert!(slist.time_valid);
+            assert_eq!(slist.items.len(), 2);
+            let s = &slist.items[1];
+            assert_eq!(s.item, 3);
+            assert_eq!(s.read.bytes, 512);
+            assert_eq!(s.read.operations, 2);
+            assert_eq!(s.read.time, Duration::from_millis(1500));
+            assert_eq!(s.write.bytes, 1024);
+            assert_eq!(s.write.operations, 3);
+            assert_eq!(s.other.bytes, 0);
+            assert_eq!(s.other.operations, 1);
+        }
+
+        /// If there are too many items for the buffer, retry with a bigger one
+        #[test]
+        fn need_more_space() {
+            let _m = CTL_STATS_MTX.lock().unwrap();
+            let ctx = mock_ioc::ctl_get_port_stats_context();
+            ctx.expect()
+                .times(2)
+                .returning(|_fd, req| {
+                    let items = (0..100).map(|i| stats(i, 512)).collect::<Vec<_>>();
+                    unsafe{ fill_stats(req, &items) };
+                    Ok(0)
+                });
+            let slist = IoStatsList::ports().unwrap();
+            assert_eq!(slist.items.len(), 100);
+            assert_eq!(slist.items[99].item, 99);
+        }
+
+        #[test]
+        fn error() {
+            let _m = CTL_STATS_MTX.lock().unwrap();
+            let ctx = mock_ioc::ctl_get_lun_stats_context();
+            ctx.expect()
+                .returning(|_fd, req| {
+                    unsafe{ (*req).status = ffi::ctl_stats_status::CTL_SS_ERROR };
+                    Ok(0)
+                });
+            let e = IoStatsList::luns().unwrap_err();
+            assert_eq!(e.to_string(), "error returned from CTL_GET_LUN_STATS");
+        }
+
+        #[test]
+        fn since() {
+            let earlier = IoStats::from(&stats(2, 512));
+            let later = IoStats::from(&stats(2, 1536));
+            let delta = later.since(&earlier);
+            assert_eq!(delta.item, 2);
+            assert_eq!(delta.read.bytes, 1024);
+            assert_eq!(delta.write.bytes, 2048);
+            assert_eq!(delta.read.operations, 0);
+            assert_eq!(delta.read.time, Duration::ZERO);
         }
     }
+
 }
@@ -1,20 +1,22 @@
 //! Create, destroy, and manipulate CTL kernel objects
 
 use std::{
-    ffi::OsStr,
+    ffi::{c_char, CStr, OsStr},
     fs,
     mem,
     os::{
-        fd::AsRawFd,
+        fd::{AsRawFd, OwnedFd, RawFd},
         unix::ffi::OsStrExt,
     },
 };
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use libnv::libnv::NvFlag;
+use serde_derive::{Deserialize, Serialize};
 
 use crate::conf;
 use crate::ffi;
+use crate::metrics;
 #[mockall_double::double]
 use crate::ioc::ioc;
 
@@ -26,6 +28,29 @@ pub struct Lun {
 }
 
 impl Lun {
+    /// Put a LUN's path, name, and options into an nvlist, to be packed into `ctl_lun_req.args`
+    fn lun_args(name: &str, lun: &conf::Lun) -> Result<libnv::libnv::NvList> {
+        let mut nvl = libnv::libnv::NvList::new(NvFlag::None).context("NvList::new")?;
+
+        if let Some(path) = &lun.path {
+            nvl.insert_string("file", path.to_str().context("file is not a valid Str")?)
+                .context("nvlist_add_string(file)")?;
+        }
+        nvl.insert_string("ctld_name", name).context("nvlist_add_string(ctld_name)")?;
+        // TODO: handle scsiname, for target_lun only
+        for (k, v) in lun.options.to_kernel() {
+            nvl.insert_string(k, v.as_str()).context("nvlist_add_string")?;
+        }
+        for (k, v) in lun.raw_options.iter() {
+            if ["file", "ctld_name"].contains(&k.as_str()) {
+                // These options are overwritten by regular fields
+                continue;
+            }
+            nvl.insert_string(k.as_str(), v.as_str()).context("nvlist_add_string")?;
+        }
+        Ok(nvl)
+    }
+
     /// Low-level, non-RAII LUN creation
     fn lunreq_create(ctl_fd: &fs::File, name: &str, lun: &crate::conf::Lun)
         -> Result<ffi::ctl_lun_req>
@@ -52,7 +77,8 @@ impl Lun {
 
             if let Some(s) = &lun.serial {
                 // Safe because we're creating, and the union is already zero-initialized
-                create.serial_num.copy_from_slice(OsStr::new(s).as_bytes());
+                let os_serial = OsStr::new(s);
+                create.serial_num[0..os_serial.len()].copy_from_slice(os_serial.as_bytes());
                 create.flags |= ffi::ctl_backend_lun_flags::CTL_LUN_FLAG_SERIAL_NUM;
             }
 
@@ -63,27 +89,19 @@ impl Lun {
             create.flags |= ffi::ctl_backend_lun_flags::CTL_LUN_FLAG_DEVID;
         }
 
-        let mut nvl = libnv::libnv::NvList::new(NvFlag::None).context("NvList::new")?;
-
-        nvl.insert_string("file", lun.path.to_str().context("file is not a valid Str")?)
-            .context("nvlist_add_string(file)")?;
-        nvl.insert_string("ctld_name", name).context("nvlist_add_string(ctld_name)")?;
-        // TODO: handle scsiname, for target_lun only
-        for (k, v) in lun.options.iter() {
-            if ["file", "ctld_name"].contains(&k.as_str()) {
-                // These options are overwritten by regular fields
-                continue;
-            }
-            nvl.insert_string(k.as_str(), v.as_str()).context("nvlist_add_string")?;
-        }
-                
+        let nvl = Self::lun_args(name, lun)?;
         let mut packed_nvl = nvl.pack().context("nvlist_pack")?;
         req.args = packed_nvl.as_mut_ptr();
         req.args_len = packed_nvl.len();
-        unsafe{ ioc::ctl_lun_req(ctl_fd.as_raw_fd(), &mut req) }.context("CTL_LUNREQ_CREATE")?;
+        metrics::time("CTL_LUNREQ_CREATE", || unsafe {
+            ioc::ctl_lun_req(ctl_fd.as_raw_fd(), &mut req)
+        }).context("CTL_LUNREQ_CREATE")?;
 
-        // TODO: log on error in req.status
-        assert_eq!(req.status, ffi::ctl_lun_status::CTL_LUN_OK);
+        if req.status != ffi::ctl_lun_status::CTL_LUN_OK {
+            // Safe because the kernel always NUL-terminates error_str, and we zero-initialize it.
+            let error_str = unsafe{ CStr::from_ptr(req.error_str.as_ptr()) }.to_string_lossy();
+            bail!("CTL_LUNREQ_CREATE failed: {}", error_str);
+        }
 
         Ok(req)
     }
@@ -97,12 +115,46 @@ impl Lun {
         req.reqtype = ffi::ctl_lunreq_type::CTL_LUNREQ_RM;
         req.reqdata.rm.lun_id = id;
 
-        unsafe{ ioc::ctl_lun_req(ctl_fd.as_raw_fd(), &mut req)}.context("CTL_LUNREQ_RM")?;
+        metrics::time("CTL_LUNREQ_RM", || unsafe {
+            ioc::ctl_lun_req(ctl_fd.as_raw_fd(), &mut req)
+        }).context("CTL_LUNREQ_RM")?;
 
         // TODO: log on error in req.status
         Ok(())
     }
 
+    /// Low-level CTL_LUNREQ_MODIFY.  A size of 0 tells the backend to use the size of the
+    /// backing file or device.
+    fn lunreq_modify(
+        ctl_fd: &fs::File,
+        backend: conf::Backend,
+        id: u32,
+        name: &str,
+        lun: &conf::Lun) -> Result<()>
+    {
+        let mut req: ffi::ctl_lun_req = unsafe{ mem::zeroed() };
+        let backend = OsStr::new(Into::<&str>::into(backend)).as_bytes();
+        let p = backend.as_ptr() as *const i8;
+        unsafe{req.backend.as_mut_ptr().copy_from_nonoverlapping(p, backend.len())};
+        req.reqtype = ffi::ctl_lunreq_type::CTL_LUNREQ_MODIFY;
+        req.reqdata.modify.lun_id = id;
+        req.reqdata.modify.lun_size_bytes = lun.size.unwrap_or(0);
+
+        let nvl = Self::lun_args(name, lun)?;
+        let mut packed_nvl = nvl.pack().context("nvlist_pack")?;
+        req.args = packed_nvl.as_mut_ptr();
+        req.args_len = packed_nvl.len();
+        metrics::time("CTL_LUNREQ_MODIFY", || unsafe {
+            ioc::ctl_lun_req(ctl_fd.as_raw_fd(), &mut req)
+        }).context("CTL_LUNREQ_MODIFY")?;
+        if req.status != ffi::ctl_lun_status::CTL_LUN_OK {
+            // Safe because the kernel always NUL-terminates error_str, and we zero-initialize it.
+            let error_str = unsafe{ CStr::from_ptr(req.error_str.as_ptr()) }.to_string_lossy();
+            bail!("CTL_LUNREQ_MODIFY failed: {}", error_str);
+        }
+        Ok(())
+    }
+
     pub fn create(name: &str, lun: &crate::conf::Lun) -> Result<Self> {
         let ctl_fd = crate::ctl();
         let req = Self::lunreq_create(ctl_fd, name, lun)?;
@@ -112,6 +164,30 @@ impl Lun {
             id
         })
     }
+
+    /// Take ownership of a LUN that already exists in the kernel, for example one left over from
+    /// a previous run.  It will be destroyed on Drop, like any other.
+    pub fn adopt(backend: conf::Backend, id: u32) -> Self {
+        Lun { backend, id }
+    }
+
+    /// The LUN's id within the kernel
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Change the LUN's size, path, and options in place.  The LUN must be given the same name
+    /// as when it was created.
+    pub fn modify(&self, name: &str, lun: &conf::Lun) -> Result<()> {
+        Self::lunreq_modify(crate::ctl(), self.backend, self.id, name, lun)
+    }
+
+    /// Remove the LUN from the kernel now.  Unlike Drop, this reports failure to the caller.
+    pub fn remove(self) -> Result<()> {
+        let r = Self::lunreq_rm(crate::ctl(), self.backend, self.id);
+        mem::forget(self);
+        r
+    }
 }
 
 impl Drop for Lun {
@@ -123,14 +199,420 @@ impl Drop for Lun {
     }
 }
 
+/// Set one entry in a port's LUN map, so that initiators see CTL LUN `lun` as LUN number `plun`.
+/// If `lun` is `None`, remove the entry instead.
+pub fn set_lun_map(port: u32, plun: u32, lun: Option<u32>) -> Result<()> {
+    let map = ffi::ctl_lun_map {
+        port,
+        plun,
+        lun: lun.unwrap_or(u32::MAX)
+    };
+    metrics::time("CTL_LUN_MAP", || unsafe {
+        ioc::ctl_lun_map(crate::ctl().as_raw_fd(), &map)
+    }).context("CTL_LUN_MAP")?;
+    Ok(())
+}
+
+/// Copy a string into a fixed-size, NUL-terminated C character array
+fn copy_cstr(dst: &mut [c_char], src: &str) -> Result<()> {
+    let b = src.as_bytes();
+    if b.contains(&0) {
+        bail!("{:?} contains a NUL byte", src);
+    }
+    if b.len() >= dst.len() {
+        bail!("{:?} is too long; the limit is {} bytes", src, dst.len() - 1);
+    }
+    for (d, s) in dst.iter_mut().zip(b.iter()) {
+        *d = *s as c_char;
+    }
+    dst[b.len()] = 0;
+    Ok(())
+}
+
+/// Get the kernel's error message from a completed CTL_ISCSI request
+fn iscsi_error_str(req: &ffi::ctl_iscsi) -> String {
+    // Safe because the kernel always NUL-terminates error_str, and we zero-initialize it.
+    unsafe{ CStr::from_ptr(req.error_str.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+/// An iSCSI digest type, as negotiated during login
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Digest {
+    #[default]
+    #[serde(rename = "None")]
+    None,
+    #[serde(rename = "CRC32C")]
+    Crc32c
+}
+
+impl From<Digest> for ffi::ctl_iscsi_digest {
+    fn from(d: Digest) -> Self {
+        match d {
+            Digest::None => ffi::ctl_iscsi_digest::CTL_ISCSI_DIGEST_NONE,
+            Digest::Crc32c => ffi::ctl_iscsi_digest::CTL_ISCSI_DIGEST_CRC32C,
+        }
+    }
+}
+
+/// An iSCSI session that has successfully completed the login phase.  It contains everything the
+/// kernel needs to take over the connection for the full feature phase.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    pub initiator_name: String,
+    /// The initiator's address, as a string suitable for display
+    pub initiator_addr: String,
+    pub initiator_alias: Option<String>,
+    pub initiator_isid: [u8; 6],
+    pub target_name: String,
+    pub portal_group_tag: u16,
+    pub header_digest: Digest,
+    pub data_digest: Digest,
+    pub cmdsn: u32,
+    pub statsn: u32,
+    pub max_recv_data_segment_length: u32,
+    pub max_send_data_segment_length: u32,
+    pub max_burst_length: u32,
+    pub first_burst_length: u32,
+    pub immediate_data: bool,
+    /// The name of the offload driver used by the connection, if any
+    pub offload: Option<String>,
+}
+
+/// Low-level CTL_ISCSI_HANDOFF request
+fn iscsi_handoff(ctl_fd: &fs::File, socket: RawFd, session: &Session) -> Result<()> {
+    let mut req: ffi::ctl_iscsi = unsafe{ mem::zeroed() };
+    req.type_ = ffi::ctl_iscsi_type::CTL_ISCSI_HANDOFF;
+    {
+        // Safe because we know that we're handing off, and the union is already zero-initialized
+        let handoff = unsafe{&mut req.data.handoff};
+        copy_cstr(&mut handoff.initiator_name, &session.initiator_name)
+            .context("initiator name")?;
+        copy_cstr(&mut handoff.initiator_addr, &session.initiator_addr)
+            .context("initiator address")?;
+        if let Some(alias) = &session.initiator_alias {
+            copy_cstr(&mut handoff.initiator_alias, alias).context("initiator alias")?;
+        }
+        handoff.initiator_isid = session.initiator_isid;
+        copy_cstr(&mut handoff.target_name, &session.target_name).context("target name")?;
+        if let Some(offload) = &session.offload {
+            copy_cstr(&mut handoff.offload, offload).context("offload")?;
+        }
+        handoff.socket = socket;
+        handoff.portal_group_tag = session.portal_group_tag.into();
+        handoff.header_digest = session.header_digest.into();
+        handoff.data_digest = session.data_digest.into();
+        handoff.cmdsn = session.cmdsn;
+        handoff.statsn = session.statsn;
+        handoff.max_recv_data_segment_length = session.max_recv_data_segment_length
+            .try_into()
+            .context("MaxRecvDataSegmentLength")?;
+        handoff.max_send_data_segment_length = session.max_send_data_segment_length
+            .try_into()
+            .context("MaxSendDataSegmentLength")?;
+        handoff.max_burst_length = session.max_burst_length
+            .try_into()
+            .context("MaxBurstLength")?;
+        handoff.first_burst_length = session.first_burst_length
+            .try_into()
+            .context("FirstBurstLength")?;
+        handoff.immediate_data = session.immediate_data.into();
+    }
+
+    metrics::time("CTL_ISCSI_HANDOFF", || unsafe {
+        ioc::ctl_iscsi(ctl_fd.as_raw_fd(), &mut req)
+    }).context("CTL_ISCSI_HANDOFF")?;
+    if req.status != ffi::ctl_iscsi_status::CTL_ISCSI_OK {
+        bail!("CTL_ISCSI_HANDOFF failed: {}", iscsi_error_str(&req));
+    }
+    Ok(())
+}
+
+/// Hand a logged-in connection off to the kernel.  After this, the kernel's cfiscsi frontend
+/// serves all full-feature-phase traffic on the connection, and ctld no longer needs the socket.
+///
+/// Nothing calls this yet.  Accepting connections and performing the iSCSI login phase, which
+/// would produce the [`Session`], are out of scope until ctld-rs handles client connections.
+pub fn handoff(socket: OwnedFd, session: &Session) -> Result<()> {
+    iscsi_handoff(crate::ctl(), socket.as_raw_fd(), session)
+}
+
+/// Selects the iSCSI sessions to which a logout or terminate request applies
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionFilter {
+    /// A single session, by its kernel connection id
+    Id(u32),
+    /// Every session from the named initiator
+    Initiator(String),
+    /// Every session
+    All
+}
+
+/// Common parts of CTL_ISCSI_LOGOUT and CTL_ISCSI_TERMINATE requests
+fn iscsi_session_req(
+    ctl_fd: &fs::File,
+    reqtype: ffi::ctl_iscsi_type,
+    filter: &SessionFilter) -> Result<()>
+{
+    let mut req: ffi::ctl_iscsi = unsafe{ mem::zeroed() };
+    req.type_ = reqtype;
+    // Safe because the union is already zero-initialized, and the logout and terminate params
+    // have the same leading fields.
+    let (connection_id, initiator_name, all) = unsafe {
+        match reqtype {
+            ffi::ctl_iscsi_type::CTL_ISCSI_LOGOUT => {
+                let p = &mut req.data.logout;
+                (&mut p.connection_id, &mut p.initiator_name[..], &mut p.all)
+            },
+            ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE => {
+                let p = &mut req.data.terminate;
+                (&mut p.connection_id, &mut p.initiator_name[..], &mut p.all)
+            },
+            _ => unreachable!()
+        }
+    };
+    // The kernel matches a session if any of the criteria match, so unused ones must be set to
+    // values that can't match anything.
+    *connection_id = -1;
+    match filter {
+        SessionFilter::Id(id) => {
+            *connection_id = (*id).try_into().context("connection id")?;
+        },
+        SessionFilter::Initiator(name) => {
+            copy_cstr(initiator_name, name).context("initiator name")?;
+        },
+        SessionFilter::All => {
+            *all = 1;
+        }
+    }
+
+    let name = match reqtype {
+        ffi::ctl_iscsi_type::CTL_ISCSI_LOGOUT => "CTL_ISCSI_LOGOUT",
+        _ => "CTL_ISCSI_TERMINATE"
+    };
+    metrics::time(name, || unsafe {
+        ioc::ctl_iscsi(ctl_fd.as_raw_fd(), &mut req)
+    }).context(name)?;
+    match req.status {
+        ffi::ctl_iscsi_status::CTL_ISCSI_OK => Ok(()),
+        ffi::ctl_iscsi_status::CTL_ISCSI_SESSION_NOT_FOUND => {
+            bail!("{}: no matching session for {:?}", name, filter)
+        },
+        _ => bail!("{} failed: {}", name, iscsi_error_str(&req))
+    }
+}
+
+/// Ask the selected iSCSI sessions to log out gracefully.
+pub fn logout(filter: &SessionFilter) -> Result<()> {
+    iscsi_session_req(crate::ctl(), ffi::ctl_iscsi_type::CTL_ISCSI_LOGOUT, filter)
+}
+
+/// Forcibly terminate the selected iSCSI sessions.
+pub fn terminate(filter: &SessionFilter) -> Result<()> {
+    iscsi_session_req(crate::ctl(), ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE, filter)
+}
+
 #[cfg(test)]
 mod t {
     use super::*;
 
-    use std::sync::Mutex;
+    use crate::ioc::{CTL_ISCSI_MTX, CTL_LUN_REQ_MTX};
+
+    fn session() -> Session {
+        Session {
+            initiator_name: String::from("iqn.1994-09.org.freebsd:initiator"),
+            initiator_addr: String::from("192.0.2.1"),
+            initiator_alias: Some(String::from("myalias")),
+            initiator_isid: [0x80, 0, 0, 0, 0, 1],
+            target_name: String::from("iqn.2018-10.org.example:disk0"),
+            portal_group_tag: 257,
+            header_digest: Digest::Crc32c,
+            data_digest: Digest::None,
+            cmdsn: 7,
+            statsn: 11,
+            max_recv_data_segment_length: 262144,
+            max_send_data_segment_length: 131072,
+            max_burst_length: 1048576,
+            first_burst_length: 65536,
+            immediate_data: true,
+            offload: None,
+        }
+    }
+
+    mod handoff {
+        use super::*;
+
+        /// Test that we pass a correctly formatted handoff request to the kernel
+        #[test]
+        fn ok() {
+            let _m = CTL_ISCSI_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+
+            let ctx = ioc::ctl_iscsi_context();
+            ctx.expect()
+                .withf(|_fd, req| unsafe {
+                    let h = &(**req).data.handoff;
+                    let iname = b"iqn.1994-09.org.freebsd:initiator\0";
+                    let uiname = &*(&h.initiator_name as *const [i8] as *const [u8]);
+                    let alias = &*(&h.initiator_alias as *const [i8] as *const [u8]);
+                    (**req).type_ == ffi::ctl_iscsi_type::CTL_ISCSI_HANDOFF &&
+                    uiname[0..iname.len()] == iname[..] &&
+                    alias[0..8] == b"myalias\0"[..] &&
+                    h.initiator_isid == [0x80, 0, 0, 0, 0, 1] &&
+                    h.socket == 42 &&
+                    h.portal_group_tag == 257 &&
+                    h.header_digest == ffi::ctl_iscsi_digest::CTL_ISCSI_DIGEST_CRC32C &&
+                    h.data_digest == ffi::ctl_iscsi_digest::CTL_ISCSI_DIGEST_NONE &&
+                    h.cmdsn == 7 &&
+                    h.statsn == 11 &&
+                    h.max_recv_data_segment_length == 262144 &&
+                    h.max_send_data_segment_length == 131072 &&
+                    h.max_burst_length == 1048576 &&
+                    h.first_burst_length == 65536 &&
+                    h.immediate_data == 1 &&
+                    h.offload[0] == 0
+                })
+                .returning(|_fd, req| {
+                    unsafe{(*req).status = ffi::ctl_iscsi_status::CTL_ISCSI_OK};
+                    Ok(0)
+                });
+
+            iscsi_handoff(&dev_ctl, 42, &session()).unwrap();
+        }
+
+        /// The kernel's error message should be reported to the caller
+        #[test]
+        fn error() {
+            let _m = CTL_ISCSI_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+
+            let ctx = ioc::ctl_iscsi_context();
+            ctx.expect()
+                .returning(|_fd, req| {
+                    unsafe {
+                        (*req).status = ffi::ctl_iscsi_status::CTL_ISCSI_ERROR;
+                        copy_cstr(&mut (*req).error_str, "target not found").unwrap();
+                    }
+                    Ok(0)
+                });
 
-    /// Serialize ioc::ctl_lun_req calls and expectations
-    static CTL_LUN_REQ_MTX: Mutex<()> = Mutex::new(());
+            let e = iscsi_handoff(&dev_ctl, 42, &session()).unwrap_err();
+            assert!(format!("{}", e).contains("target not found"));
+        }
+
+        /// Overlong names must be rejected before calling into the kernel
+        #[test]
+        fn name_too_long() {
+            let _m = CTL_ISCSI_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+            let ctx = ioc::ctl_iscsi_context();
+            ctx.expect().never();
+
+            let mut session = session();
+            session.target_name = "x".repeat(224);
+            iscsi_handoff(&dev_ctl, 42, &session).unwrap_err();
+        }
+    }
+
+    mod logout {
+        use super::*;
+
+        /// Log out every session from one initiator
+        #[test]
+        fn initiator() {
+            let _m = CTL_ISCSI_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+
+            let ctx = ioc::ctl_iscsi_context();
+            ctx.expect()
+                .withf(|_fd, req| unsafe {
+                    let p = &(**req).data.logout;
+                    let iname = b"iqn.1994-09.org.freebsd:initiator\0";
+                    let uiname = &*(&p.initiator_name as *const [i8] as *const [u8]);
+                    (**req).type_ == ffi::ctl_iscsi_type::CTL_ISCSI_LOGOUT &&
+                    p.connection_id == -1 &&
+                    uiname[0..iname.len()] == iname[..] &&
+                    p.initiator_addr[0] == 0 &&
+                    p.all == 0
+                })
+                .returning(|_fd, req| {
+                    unsafe{(*req).status = ffi::ctl_iscsi_status::CTL_ISCSI_OK};
+                    Ok(0)
+                });
+
+            let filter = SessionFilter::Initiator("iqn.1994-09.org.freebsd:initiator".into());
+            iscsi_session_req(&dev_ctl, ffi::ctl_iscsi_type::CTL_ISCSI_LOGOUT, &filter).unwrap();
+        }
+    }
+
+    mod terminate {
+        use super::*;
+
+        /// Terminate every session
+        #[test]
+        fn all() {
+            let _m = CTL_ISCSI_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+
+            let ctx = ioc::ctl_iscsi_context();
+            ctx.expect()
+                .withf(|_fd, req| unsafe {
+                    let p = &(**req).data.terminate;
+                    (**req).type_ == ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE &&
+                    p.connection_id == -1 &&
+                    p.initiator_name[0] == 0 &&
+                    p.all == 1
+                })
+                .returning(|_fd, req| {
+                    unsafe{(*req).status = ffi::ctl_iscsi_status::CTL_ISCSI_OK};
+                    Ok(0)
+                });
+
+            iscsi_session_req(&dev_ctl, ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE,
+                &SessionFilter::All).unwrap();
+        }
+
+        /// Terminate one session by id
+        #[test]
+        fn id() {
+            let _m = CTL_ISCSI_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+
+            let ctx = ioc::ctl_iscsi_context();
+            ctx.expect()
+                .withf(|_fd, req| unsafe {
+                    let p = &(**req).data.terminate;
+                    (**req).type_ == ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE &&
+                    p.connection_id == 5 &&
+                    p.initiator_name[0] == 0 &&
+                    p.all == 0
+                })
+                .returning(|_fd, req| {
+                    unsafe{(*req).status = ffi::ctl_iscsi_status::CTL_ISCSI_OK};
+                    Ok(0)
+                });
+
+            iscsi_session_req(&dev_ctl, ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE,
+                &SessionFilter::Id(5)).unwrap();
+        }
+
+        /// It's an error if no session matches
+        #[test]
+        fn not_found() {
+            let _m = CTL_ISCSI_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+
+            let ctx = ioc::ctl_iscsi_context();
+            ctx.expect()
+                .returning(|_fd, req| {
+                    unsafe{(*req).status = ffi::ctl_iscsi_status::CTL_ISCSI_SESSION_NOT_FOUND};
+                    Ok(0)
+                });
+
+            iscsi_session_req(&dev_ctl, ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE,
+                &SessionFilter::Id(5)).unwrap_err();
+        }
+    }
 
     mod lunreq_create {
         use super::*;
@@ -148,7 +630,8 @@ mod t {
                 device_id: String::from("ramdisk0"),
                 device_type: crate::conf::DeviceType::Disk,
                 options: Default::default(),
-                path: Default::default(),
+                raw_options: Default::default(),
+                path: None,
                 serial: None,
                 size: Some(131072)
             };
@@ -203,4 +686,71 @@ mod t {
             Lun::lunreq_rm(&dev_ctl, conf::Backend::Ramdisk, 42).unwrap();
         }
     }
+
+    mod lunreq_modify {
+        use super::*;
+
+        fn lun(size: Option<u64>) -> conf::Lun {
+            conf::Lun {
+                backend: conf::Backend::Block,
+                blocksize: None,
+                ctl_lun: None,
+                device_id: String::from("disk0"),
+                device_type: conf::DeviceType::Disk,
+                options: Default::default(),
+                raw_options: Default::default(),
+                path: Some("/dev/zvol/tank/disk0".into()),
+                serial: None,
+                size
+            }
+        }
+
+        /// Resize a LUN. Test that we pass a correctly formatted request to the kernel.
+        #[test]
+        fn ok() {
+            let _m = CTL_LUN_REQ_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+
+            let ctx = ioc::ctl_lun_req_context();
+            ctx.expect()
+                .withf(|_fd, req| unsafe {
+                    let ubackend = &*(&(**req).backend as *const [i8] as *const [u8]);
+                    ubackend[0..6] == b"block\0"[0..6] &&
+                    (**req).reqtype == ffi::ctl_lunreq_type::CTL_LUNREQ_MODIFY &&
+                    (**req).reqdata.modify.lun_id == 42 &&
+                    (**req).reqdata.modify.lun_size_bytes == 1 << 30 &&
+                    (**req).args_len > 0
+                })
+                .returning(|_fd, req| {
+                    unsafe{(*req).status = ffi::ctl_lun_status::CTL_LUN_OK};
+                    Ok(0)
+                });
+
+            Lun::lunreq_modify(&dev_ctl, conf::Backend::Block, 42, "disk0", &lun(Some(1 << 30)))
+                .unwrap();
+        }
+
+        /// The kernel's error message should be reported to the caller
+        #[test]
+        fn error() {
+            let _m = CTL_LUN_REQ_MTX.lock().unwrap();
+            let dev_ctl = fs::File::open("/dev/null").unwrap();
+
+            let ctx = ioc::ctl_lun_req_context();
+            ctx.expect()
+                .returning(|_fd, req| {
+                    unsafe {
+                        (*req).status = ffi::ctl_lun_status::CTL_LUN_ERROR;
+                        copy_cstr(&mut (*req).error_str, "LUN 42 is not managed by the block \
+                            backend").unwrap();
+                    }
+                    Ok(0)
+                });
+
+            let e = Lun::lunreq_modify(&dev_ctl, conf::Backend::Block, 42, "disk0", &lun(None))
+                .unwrap_err();
+            assert_eq!(e.to_string(),
+                "CTL_LUNREQ_MODIFY failed: LUN 42 is not managed by the block backend");
+        }
+    }
 }
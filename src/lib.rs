@@ -1,30 +1,50 @@
 //! This is not a real library!  It should be used from within the ctld workspace only.
 
 use std::{
-    ffi::{CStr, OsStr,FromBytesUntilNulError },
     fs,
-    os::unix::ffi::OsStrExt,
     sync::OnceLock
 };
+#[cfg(not(test))]
+use std::{
+    ffi::{CStr, FromBytesUntilNulError, OsStr},
+    os::unix::ffi::OsStrExt,
+};
 
 pub mod conf;
+pub mod control;
+pub mod daemon;
 pub mod ffi;
 pub mod ioc;
+pub mod isns;
 pub mod kconf;
 pub mod kernel;
+pub mod log;
+pub mod metrics;
+pub mod pidfile;
+pub mod plan;
+pub mod secret;
+pub mod state;
+pub mod table;
+pub mod tags;
 
 /// Store a global handle to /dev/ctl.  It needs to be global so it can be used in destructors
 /// without needing to reopen the device.
 static CTLDEV: OnceLock<fs::File> = OnceLock::new();
 
 /// Get a handle to /dev/ctl, opening it if it isn't already open
+#[cfg(not(test))]
 pub fn ctl() -> &'static fs::File {
     CTLDEV.get_or_init(|| {
-        let ctl_dev_path = {
-            const CSTR: std::result::Result<&CStr, FromBytesUntilNulError> =
-                CStr::from_bytes_until_nul(ffi::CTL_DEFAULT_DEV);
-            OsStr::from_bytes(CSTR.unwrap().to_bytes())
-        };
+        const CSTR: std::result::Result<&CStr, FromBytesUntilNulError> =
+            CStr::from_bytes_until_nul(ffi::CTL_DEFAULT_DEV);
+        let ctl_dev_path = OsStr::from_bytes(CSTR.unwrap().to_bytes());
         fs::File::open(ctl_dev_path).expect("opening ctl device file")
     })
 }
+
+/// Get a handle to /dev/ctl, opening it if it isn't already open
+#[cfg(test)]
+pub fn ctl() -> &'static fs::File {
+    // The tests mock every ioctl, so any file will do
+    CTLDEV.get_or_init(|| fs::File::open("/dev/null").expect("opening /dev/null"))
+}
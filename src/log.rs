@@ -0,0 +1,252 @@
+//! Leveled logging, to stderr in the foreground or to syslog when running as a daemon.
+//!
+//! Messages logged while handling a connection are prefixed with the peer's address and, once
+//! known, the initiator's name, like ctld(8).  Never log secrets, such as CHAP secrets.
+use std::{
+    cell::RefCell,
+    ffi::CString,
+    fmt,
+    sync::{
+        Once,
+        atomic::{AtomicBool, AtomicU8, Ordering}
+    },
+};
+
+use nix::libc;
+
+/// Severity of a log message.  Ordered from most to least severe.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+#[repr(u8)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace
+}
+
+impl Level {
+    /// The least severe level that will be logged, given ctl.conf's `debug` setting plus the
+    /// number of `-d` flags.
+    pub fn from_debug(debug: i32) -> Self {
+        match debug {
+            ..=0 => Level::Info,
+            1 => Level::Debug,
+            _ => Level::Trace
+        }
+    }
+
+    fn from_u8(level: u8) -> Self {
+        match level {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            3 => Level::Debug,
+            _ => Level::Trace
+        }
+    }
+
+    fn syslog_priority(self) -> libc::c_int {
+        match self {
+            Level::Error => libc::LOG_ERR,
+            Level::Warn => libc::LOG_WARNING,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug | Level::Trace => libc::LOG_DEBUG
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warning",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace"
+        })
+    }
+}
+
+/// Where log messages go
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Destination {
+    Stderr,
+    Syslog
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static SYSLOG: AtomicBool = AtomicBool::new(false);
+static OPENLOG: Once = Once::new();
+
+thread_local! {
+    /// Context of the connection being handled by the current thread, if any
+    static PEER: RefCell<Option<Peer>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Debug)]
+struct Peer {
+    addr: String,
+    name: Option<String>
+}
+
+/// Set the verbosity and destination.  Until this is called, messages at `Info` and above go to
+/// stderr.  It may be called again, for example after reloading the configuration.
+pub fn init(level: Level, dest: Destination) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+    if dest == Destination::Syslog {
+        OPENLOG.call_once(|| unsafe {
+            libc::openlog(c"ctld".as_ptr(), libc::LOG_PID | libc::LOG_NDELAY, libc::LOG_DAEMON);
+        });
+    }
+    SYSLOG.store(dest == Destination::Syslog, Ordering::Relaxed);
+}
+
+/// Would a message at this level be logged?
+pub fn enabled(level: Level) -> bool {
+    level <= Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Restores the previous connection context when dropped
+#[derive(Debug)]
+#[must_use = "the connection context is cleared when this guard is dropped"]
+pub struct PeerGuard {
+    prev: Option<Peer>
+}
+
+impl PeerGuard {
+    /// Record the peer's name once it is known, like a control client's uid or an initiator's
+    /// name from its login request.
+    pub fn set_name(&self, name: &str) {
+        PEER.with_borrow_mut(|peer| {
+            if let Some(peer) = peer.as_mut() {
+                peer.name = Some(name.to_owned());
+            }
+        });
+    }
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        PEER.set(self.prev.take());
+    }
+}
+
+/// Prefix this thread's messages with a connection's context, until the guard is dropped.
+pub fn peer(addr: &str, name: Option<&str>) -> PeerGuard {
+    let new = Peer {
+        addr: addr.to_owned(),
+        name: name.map(str::to_owned)
+    };
+    PeerGuard {
+        prev: PEER.replace(Some(new))
+    }
+}
+
+/// Format a message, including this thread's connection context
+fn format(args: fmt::Arguments<'_>) -> String {
+    PEER.with_borrow(|peer| match peer {
+        Some(Peer{addr, name: Some(name)}) => format!("{} ({}): {}", addr, name, args),
+        Some(Peer{addr, name: None}) => format!("{}: {}", addr, args),
+        None => args.to_string()
+    })
+}
+
+/// Implementation detail of the logging macros
+#[doc(hidden)]
+pub fn log(level: Level, args: fmt::Arguments<'_>) {
+    if !enabled(level) {
+        return;
+    }
+    let msg = format(args);
+    if SYSLOG.load(Ordering::Relaxed) {
+        let cmsg = CString::new(msg.replace('\0', "\\0")).unwrap();
+        unsafe {
+            libc::syslog(level.syslog_priority(), c"%s".as_ptr(), cmsg.as_ptr());
+        }
+    } else {
+        eprintln!("ctld: {}: {}", level, msg);
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Error, format_args!($($arg)+)) }
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Warn, format_args!($($arg)+)) }
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Info, format_args!($($arg)+)) }
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Debug, format_args!($($arg)+)) }
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log::log($crate::log::Level::Trace, format_args!($($arg)+)) }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn from_debug() {
+        assert_eq!(Level::from_debug(-1), Level::Info);
+        assert_eq!(Level::from_debug(0), Level::Info);
+        assert_eq!(Level::from_debug(1), Level::Debug);
+        assert_eq!(Level::from_debug(2), Level::Trace);
+        assert_eq!(Level::from_debug(99), Level::Trace);
+    }
+
+    #[test]
+    fn round_trip() {
+        for level in [Level::Error, Level::Warn, Level::Info, Level::Debug, Level::Trace] {
+            assert_eq!(Level::from_u8(level as u8), level);
+        }
+    }
+
+    mod peer {
+        use super::*;
+
+        #[test]
+        fn none() {
+            assert_eq!(format(format_args!("hello {}", 1)), "hello 1");
+        }
+
+        #[test]
+        fn addr() {
+            let _guard = peer("192.0.2.1:51234", None);
+            assert_eq!(format(format_args!("hello")), "192.0.2.1:51234: hello");
+        }
+
+        #[test]
+        fn name() {
+            let guard = peer("192.0.2.1:51234", None);
+            guard.set_name("iqn.1994-09.org.freebsd:initiator");
+            assert_eq!(format(format_args!("hello")),
+                "192.0.2.1:51234 (iqn.1994-09.org.freebsd:initiator): hello");
+        }
+
+        /// Dropping the guard restores the previous context
+        #[test]
+        fn nested() {
+            let outer = peer("192.0.2.1:51234", Some("outer"));
+            {
+                let _inner = peer("192.0.2.2:51234", Some("inner"));
+                assert_eq!(format(format_args!("hello")), "192.0.2.2:51234 (inner): hello");
+            }
+            assert_eq!(format(format_args!("hello")), "192.0.2.1:51234 (outer): hello");
+            drop(outer);
+            assert_eq!(format(format_args!("hello")), "hello");
+        }
+    }
+}
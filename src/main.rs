@@ -1,16 +1,27 @@
 use std::{
+    env,
+    os::fd::{FromRawFd, OwnedFd},
     path::PathBuf,
-    sync::atomic::{AtomicU16, Ordering}
+    sync::{Arc, Mutex, PoisonError},
 };
 
 use anyhow::{Context, Result};
 use clap::Parser;
+use nix::{
+    fcntl::{FcntlArg, fcntl},
+    sys::signal::{SigSet, Signal},
+};
 
+use ctld::control;
+use ctld::daemon;
 use ctld::kconf;
-use ctld::kernel;
+use ctld::log;
+use ctld::metrics;
+use ctld::{debug, error, info};
+use ctld::pidfile::Pidfile;
 use ctld::conf::Conf;
-
-static LAST_PORTAL_GROUP_TAG: AtomicU16 = AtomicU16::new(0xff);
+use ctld::plan::Plan;
+use ctld::state::State;
 
 #[derive(Debug, Default, clap::Parser)]
 struct Cli {
@@ -19,52 +30,131 @@ struct Cli {
     config: PathBuf,
     /// test the configuration file for validity and exit
     #[clap(short = 't')]
-    test: bool
+    test: bool,
+    /// print the kernel operations that applying the configuration would cause, and exit
+    #[clap(short = 'n', long = "dry-run")]
+    dry_run: bool,
+    /// print the dry-run plan as JSON
+    #[clap(long, requires = "dry_run")]
+    json: bool,
+    /// increase log verbosity and stay in the foreground.  May be repeated.
+    #[clap(short = 'd', action = clap::ArgAction::Count)]
+    debug: u8,
+    /// stay in the foreground and log to stderr
+    #[clap(long)]
+    foreground: bool,
+    /// when ready, write a newline to file descriptor FD and close it
+    #[clap(long, value_name = "FD", value_parser = clap::value_parser!(i32).range(3..))]
+    ready_fd: Option<i32>
 }
 
-/// Apply an initial configuration to the running kernel
-fn apply_conf(
-    klun_list: &kconf::Ctllunlist,
-    kport_list: &kconf::Ctlportlist,
-    conf: &mut Conf) -> Result<()>
+/// Apply the configuration and start ctld's services
+fn start(pidfile: &mut Pidfile, config: PathBuf, conf: Conf)
+    -> Result<(Arc<Mutex<State>>, control::Server)>
 {
-    assert!(klun_list.lun.is_empty(), "Handling preexisting LUNs is TODO");
-    for kport in kport_list.targ_port.iter() {
-        if !["camsim", "tpc", "ioctl"].contains(&kport.frontend_type.as_str()) {
-            panic!("Handling preexisting ports is TODO");
-        }
-    }
-    // Go through the new portal groups, assigning tags
-    for (_name, pg) in conf.portal_groups.iter_mut() {
-        assert!(pg.tag.is_none(), "todo");
-        pg.tag = Some(LAST_PORTAL_GROUP_TAG.fetch_add(1, Ordering::Relaxed));
+    pidfile.write()?;
+    let socket = conf.control_socket.clone();
+    let metrics_listen = conf.metrics_listen;
+    let state = Arc::new(Mutex::new(State::start(config, conf)?));
+    let server = control::Server::start(&socket, state.clone())
+        .context("starting control socket")?;
+    if let Some(addr) = metrics_listen {
+        let addr = metrics::start(addr, state.clone()).context("starting metrics exporter")?;
+        info!("serving metrics on http://{}/metrics", addr);
     }
-
-    // Add any LUNs from the config file
-    for (name, lun) in conf.luns.iter() {
-        kernel::Lun::create(name.as_str(), lun)?;
-    }
-    todo!()
+    Ok((state, server))
 }
 
 fn main() -> Result<()> {
     let cli: Cli = Cli::parse();
 
-    let mut conf = Conf::open(&cli.config)?;
-    dbg!(&conf);
+    let conf = Conf::open(&cli.config)?;
+    let debug = conf.debug.saturating_add(cli.debug.into());
+    log::init(log::Level::from_debug(debug), log::Destination::Stderr);
+    debug!("{:?}", conf);
     if cli.test {
+        let mut luns = conf.luns.iter().collect::<Vec<_>>();
+        luns.sort_unstable_by_key(|(name, _)| *name);
+        for (name, lun) in luns {
+            if let Some(size) = lun.backing_size()? {
+                println!("lun \"{}\": {} is {} bytes", name, lun.path.as_ref().unwrap().display(),
+                    size);
+            }
+        }
+        return Ok(());
+    }
+
+    if cli.dry_run {
+        let klun_list = kconf::Ctllunlist::from_kernel().context("getting LUN list")?;
+        let kport_list = kconf::Ctlportlist::from_kernel().context("getting port list")?;
+        let plan = Plan::new(&conf, &klun_list, &kport_list);
+        if cli.json {
+            println!("{}", serde_json::to_string_pretty(&plan).context("serializing JSON")?);
+        } else {
+            print!("{}", plan);
+        }
         return Ok(());
     }
 
-    // TODO: open pidfile
-    // TODO: set loglevel based on conf.debug
+    let ready_fd = cli.ready_fd.map(|fd| {
+        fcntl(fd, FcntlArg::F_GETFD).with_context(|| format!("--ready-fd {}", fd))?;
+        // Safe because the fd is open, and nothing else in ctld uses it
+        Ok::<_, anyhow::Error>(unsafe { OwnedFd::from_raw_fd(fd) })
+    }).transpose()?;
+
+    // Lock the pidfile before touching the kernel, so two instances can't fight over it
+    let mut pidfile = Pidfile::open(&conf.pidfile)?;
+    let daemon = if cli.foreground || cli.debug > 0 {
+        None
+    } else {
+        let daemon = daemon::daemonize()?;
+        log::init(log::Level::from_debug(debug), log::Destination::Syslog);
+        Some(daemon)
+    };
 
-    let klun_list = kconf::Ctllunlist::from_kernel().context("getting LUN list")?;
-    dbg!(&klun_list);
-    let kport_list = kconf::Ctlportlist::from_kernel().context("getting port list")?;
-    dbg!(&kport_list);
+    // Block the signals that ctld handles before spawning any threads, so they all inherit the
+    // mask.
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGHUP);
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.thread_block().context("blocking signals")?;
 
-    apply_conf(&klun_list, &kport_list, &mut conf)?;
+    let (state, server) = match start(&mut pidfile, cli.config, conf) {
+        Ok(started) => started,
+        Err(e) => {
+            // In the foreground, returning the error is enough to print it
+            if let Some(daemon) = daemon {
+                error!("{:?}", e);
+                daemon.failed(&e);
+            }
+            return Err(e);
+        }
+    };
+    if let Some(daemon) = daemon {
+        daemon.ready()?;
+    }
+    let notify_socket = env::var_os("NOTIFY_SOCKET").map(PathBuf::from);
+    daemon::notify_ready(ready_fd, notify_socket.as_deref())?;
+    info!("ready");
 
-    Ok(())
+    loop {
+        match signals.wait().context("waiting for signals")? {
+            Signal::SIGHUP => match state.lock() {
+                Ok(mut state) => match state.reload() {
+                    Ok(_) => info!("reloaded configuration"),
+                    Err(e) => error!("reloading configuration: {:?}", e)
+                },
+                Err(_) => error!("not reloading: internal state is unusable after an earlier panic")
+            },
+            signal => {
+                info!("exiting on {}", signal);
+                break;
+            }
+        }
+    }
+    drop(server);
+    // Even after a panic, still try to remove the LUNs we created
+    state.lock().unwrap_or_else(PoisonError::into_inner).shutdown();
+    pidfile.remove()
 }
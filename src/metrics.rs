@@ -0,0 +1,484 @@
+//! Prometheus metrics, served over HTTP in the OpenMetrics text format.
+//!
+//! Counters and ioctl timings are kept in a global registry, so that any layer can record them
+//! without threading state around.  Everything else is read from the kernel and from ctld's
+//! running configuration at scrape time.
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::kconf;
+use crate::state::State;
+use crate::warn;
+
+/// Upper bounds of the ioctl latency histogram's buckets, in seconds
+const BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+/// How long a scraper may take to send its request
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    /// Observations less than or equal to each bucket's bound.  Not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, v: f64) {
+        if let Some(i) = BUCKETS.iter().position(|le| v <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.sum += v;
+        self.count += 1;
+    }
+}
+
+/// Everything that ctld counts for itself
+#[derive(Debug)]
+struct Registry {
+    reload_successes: AtomicU64,
+    reload_failures: AtomicU64,
+    login_successes: AtomicU64,
+    /// Failed iSCSI logins, by reason
+    login_failures: Mutex<BTreeMap<&'static str, u64>>,
+    chap_failures: AtomicU64,
+    /// ioctl latency, by request type
+    ioctls: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        Registry {
+            reload_successes: AtomicU64::new(0),
+            reload_failures: AtomicU64::new(0),
+            login_successes: AtomicU64::new(0),
+            login_failures: Mutex::new(BTreeMap::new()),
+            chap_failures: AtomicU64::new(0),
+            ioctls: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn render(&self, w: &mut Writer) {
+        w.family("ctld_reloads", "counter", "Configuration reloads");
+        w.sample("ctld_reloads_total", &[("result", "success")],
+            self.reload_successes.load(Ordering::Relaxed));
+        w.sample("ctld_reloads_total", &[("result", "failure")],
+            self.reload_failures.load(Ordering::Relaxed));
+
+        w.family("ctld_logins", "counter", "Successful iSCSI logins");
+        w.sample("ctld_logins_total", &[], self.login_successes.load(Ordering::Relaxed));
+        w.family("ctld_login_failures", "counter", "Failed iSCSI logins, by reason");
+        for (reason, n) in self.login_failures.lock().unwrap().iter() {
+            w.sample("ctld_login_failures_total", &[("reason", reason)], n);
+        }
+        w.family("ctld_chap_failures", "counter", "iSCSI logins that failed CHAP authentication");
+        w.sample("ctld_chap_failures_total", &[], self.chap_failures.load(Ordering::Relaxed));
+
+        w.family("ctld_ioctl_duration_seconds", "histogram", "Time spent in CTL ioctls");
+        for (request, h) in self.ioctls.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (le, n) in BUCKETS.iter().zip(h.buckets.iter()) {
+                cumulative += n;
+                w.sample("ctld_ioctl_duration_seconds_bucket",
+                    &[("request", request), ("le", &le.to_string())], cumulative);
+            }
+            w.sample("ctld_ioctl_duration_seconds_bucket", &[("request", request), ("le", "+Inf")],
+                h.count);
+            w.sample("ctld_ioctl_duration_seconds_sum", &[("request", request)], h.sum);
+            w.sample("ctld_ioctl_duration_seconds_count", &[("request", request)], h.count);
+        }
+    }
+}
+
+static REGISTRY: Registry = Registry::new();
+
+/// Time a CTL ioctl, recording it under `request`
+pub fn time<T>(request: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let r = f();
+    let elapsed = start.elapsed().as_secs_f64();
+    REGISTRY.ioctls.lock().unwrap().entry(request).or_default().observe(elapsed);
+    r
+}
+
+/// Count a configuration reload
+pub fn reload(success: bool) {
+    let counter = if success {
+        &REGISTRY.reload_successes
+    } else {
+        &REGISTRY.reload_failures
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count an iSCSI login.  A failed login's reason should be a short, fixed string like
+/// "authentication" or "target-not-found", so the number of label values stays small.
+pub fn login(result: std::result::Result<(), &'static str>) {
+    match result {
+        Ok(()) => {
+            REGISTRY.login_successes.fetch_add(1, Ordering::Relaxed);
+        },
+        Err(reason) => {
+            *REGISTRY.login_failures.lock().unwrap().entry(reason).or_default() += 1;
+        }
+    }
+}
+
+/// Count a failed CHAP authentication.  The login failure should be counted too.
+pub fn chap_failure() {
+    REGISTRY.chap_failures.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Formats OpenMetrics text
+#[derive(Debug, Default)]
+struct Writer {
+    buf: String
+}
+
+impl Writer {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.buf, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(self.buf, "# HELP {} {}", name, help).unwrap();
+    }
+
+    fn sample<V: std::fmt::Display>(&mut self, name: &str, labels: &[(&str, &str)], value: V) {
+        self.buf.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels.iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect::<Vec<_>>()
+                .join(",");
+            write!(self.buf, "{{{}}}", labels).unwrap();
+        }
+        writeln!(self.buf, " {}", value).unwrap();
+    }
+}
+
+/// Escape an OpenMetrics label value
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Write the I/O counters of every LUN or every port.  `labels` identify each item by its CTL
+/// number; items that aren't in it were created since it was read, and are skipped.
+fn io_stats(
+    w: &mut Writer,
+    kind: &str,
+    stats: &kconf::IoStatsList,
+    labels: &BTreeMap<String, Vec<(&str, String)>>
+) {
+    let ops = |s: &kconf::IoStats| [("read", s.read), ("write", s.write), ("other", s.other)];
+    let items = stats.items.iter()
+        .filter_map(|s| Some((labels.get(&s.item.to_string())?, s)))
+        .collect::<Vec<_>>();
+    let mut family = |metric: &str, help: &str, value: &dyn Fn(&kconf::OpStats) -> String| {
+        let name = format!("ctld_{}_{}", kind, metric);
+        w.family(&name, "counter", &format!("{} by each {}, by operation", help, kind));
+        for (labels, s) in items.iter() {
+            for (op, op_stats) in ops(s) {
+                let mut labels = labels.iter().map(|(k, v)| (*k, v.as_str())).collect::<Vec<_>>();
+                labels.push(("op", op));
+                w.sample(&format!("{}_total", name), &labels, value(&op_stats));
+            }
+        }
+    };
+    family("bytes", "Bytes transferred", &|s| s.bytes.to_string());
+    family("operations", "Commands processed", &|s| s.operations.to_string());
+    if stats.time_valid {
+        family("time_seconds", "Time spent processing commands",
+            &|s| s.time.as_secs_f64().to_string());
+    }
+}
+
+/// Log a section of the metrics that couldn't be gathered, and leave it out
+fn section<T>(r: Result<T>) -> Option<T> {
+    r.map_err(|e| warn!("metrics: {:#}", e)).ok()
+}
+
+/// Gather every metric
+fn render(state: &Mutex<State>) -> Result<String> {
+    let mut w = Writer::default();
+    {
+        let state = state.lock().map_err(|_| anyhow!("internal state is unusable"))?;
+        let conf = state.conf();
+        w.family("ctld_configured_luns", "gauge", "LUNs in ctld's running configuration");
+        w.sample("ctld_configured_luns", &[], conf.luns.len());
+        w.family("ctld_configured_targets", "gauge", "Targets in ctld's running configuration");
+        w.sample("ctld_configured_targets", &[], conf.targets.len());
+    }
+
+    // Each section read from the kernel is best-effort, so one failing ioctl doesn't hide the rest
+    let luns = section(kconf::Ctllunlist::from_kernel().context("getting LUN list"))
+        .map(|list| list.lun);
+    if let Some(luns) = &luns {
+        w.family("ctld_luns", "gauge", "LUNs in the kernel");
+        w.sample("ctld_luns", &[], luns.len());
+    }
+
+    let ports = section(kconf::Ctlportlist::from_kernel().context("getting port list"))
+        .map(|list| list.targ_port);
+    if let Some(ports) = &ports {
+        let mut by_frontend = BTreeMap::<&str, [u64; 2]>::new();
+        for port in ports.iter() {
+            by_frontend.entry(port.port_name.as_str()).or_default()[usize::from(port.online)] += 1;
+        }
+        w.family("ctld_ports", "gauge", "Ports in the kernel, by frontend");
+        for (frontend, [offline, online]) in by_frontend.iter() {
+            w.sample("ctld_ports", &[("frontend", frontend), ("online", "yes")], online);
+            w.sample("ctld_ports", &[("frontend", frontend), ("online", "no")], offline);
+        }
+    }
+
+    if let Some(luns) = &luns {
+        let lun_labels = luns.iter().map(|lun| {
+            let name = lun.ctld_name.clone().unwrap_or_default();
+            (lun.id.to_string(), vec![("lun", lun.id.to_string()), ("name", name)])
+        }).collect::<BTreeMap<_, _>>();
+        if let Some(stats) = section(kconf::IoStatsList::luns().context("getting LUN statistics"))
+        {
+            io_stats(&mut w, "lun", &stats, &lun_labels);
+        }
+    }
+    if let Some(ports) = &ports {
+        let port_labels = ports.iter().map(|port| {
+            (port.id.clone(), vec![("port", port.id.clone()), ("frontend", port.port_name.clone())])
+        }).collect::<BTreeMap<_, _>>();
+        if let Some(stats) =
+            section(kconf::IoStatsList::ports().context("getting port statistics"))
+        {
+            io_stats(&mut w, "port", &stats, &port_labels);
+        }
+    }
+
+    if let Some(sessions) =
+        section(kconf::Ctlislist::from_kernel().context("getting session list"))
+    {
+        let mut by_target = BTreeMap::<&str, u64>::new();
+        for conn in sessions.connection.iter() {
+            *by_target.entry(conn.target.as_str()).or_default() += 1;
+        }
+        w.family("ctld_sessions", "gauge", "iSCSI sessions, by target");
+        for (target, n) in by_target.iter() {
+            w.sample("ctld_sessions", &[("target", target)], n);
+        }
+    }
+
+    REGISTRY.render(&mut w);
+    w.buf.push_str("# EOF\n");
+    Ok(w.buf)
+}
+
+/// Serve metrics over HTTP at `addr`, in a background thread.  Returns the bound address.
+pub fn start(addr: SocketAddr, state: Arc<Mutex<State>>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("binding {}", addr))?;
+    let addr = listener.local_addr().context("getsockname")?;
+    thread::spawn(move || {
+        // Scrapes are infrequent, so there's no need to handle them concurrently
+        for stream in listener.incoming() {
+            let r = stream.context("accept").and_then(|stream| serve(stream, &state));
+            if let Err(e) = r {
+                warn!("metrics: {:?}", e);
+            }
+        }
+    });
+    Ok(addr)
+}
+
+/// Answer a single HTTP request
+fn serve(mut stream: TcpStream, state: &Mutex<State>) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT)).context("setting timeout")?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).context("reading request")?;
+    // Ignore the headers, but read them so the client doesn't see a reset
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).context("reading request")? == 0 || line.trim().is_empty()
+        {
+            break;
+        }
+    }
+
+    let mut words = request_line.split_whitespace();
+    let (status, content_type, body) = match (words.next(), words.next()) {
+        (Some("GET"), Some("/metrics")) => match render(state) {
+            Ok(body) => ("200 OK", "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                body),
+            Err(e) => ("500 Internal Server Error", "text/plain", format!("{:#}\n", e))
+        },
+        (Some("GET"), _) => ("404 Not Found", "text/plain", String::from("Not Found\n")),
+        _ => ("405 Method Not Allowed", "text/plain", String::from("Method Not Allowed\n"))
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}", status, content_type, body.len(), body)
+        .context("writing response")
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use std::{fs, io::Read};
+
+    use tempfile::TempDir;
+
+    use crate::conf::Conf;
+    use crate::ffi;
+    use crate::ioc::{
+        CTL_ISCSI_MTX,
+        CTL_LIST_MTX,
+        CTL_LUN_REQ_MTX,
+        CTL_STATS_MTX,
+        expect_lists,
+        fill_iscsi_list,
+        fill_stats,
+        mock_ioc
+    };
+
+    mod registry {
+        use super::*;
+
+        /// Histogram buckets are reported cumulatively
+        #[test]
+        fn histogram() {
+            let registry = Registry::new();
+            {
+                let mut ioctls = registry.ioctls.lock().unwrap();
+                let h = ioctls.entry("CTL_LUN_REQ").or_default();
+                h.observe(0.0002);
+                h.observe(0.002);
+                h.observe(2.0);
+            }
+            let mut w = Writer::default();
+            registry.render(&mut w);
+            assert!(w.buf.contains(
+                "ctld_ioctl_duration_seconds_bucket{request=\"CTL_LUN_REQ\",le=\"0.0001\"} 0\n"));
+            assert!(w.buf.contains(
+                "ctld_ioctl_duration_seconds_bucket{request=\"CTL_LUN_REQ\",le=\"0.0005\"} 1\n"));
+            assert!(w.buf.contains(
+                "ctld_ioctl_duration_seconds_bucket{request=\"CTL_LUN_REQ\",le=\"1\"} 2\n"));
+            assert!(w.buf.contains(
+                "ctld_ioctl_duration_seconds_bucket{request=\"CTL_LUN_REQ\",le=\"+Inf\"} 3\n"));
+            assert!(w.buf.contains("ctld_ioctl_duration_seconds_count{request=\"CTL_LUN_REQ\"} 3\n"));
+        }
+
+        #[test]
+        fn login_failures() {
+            let registry = Registry::new();
+            *registry.login_failures.lock().unwrap().entry("authentication").or_default() += 2;
+            registry.reload_failures.fetch_add(1, Ordering::Relaxed);
+            let mut w = Writer::default();
+            registry.render(&mut w);
+            assert!(w.buf.contains("ctld_login_failures_total{reason=\"authentication\"} 2\n"));
+            assert!(w.buf.contains("ctld_reloads_total{result=\"failure\"} 1\n"));
+            assert!(w.buf.contains("ctld_reloads_total{result=\"success\"} 0\n"));
+        }
+    }
+
+    #[test]
+    fn escape() {
+        let mut w = Writer::default();
+        w.sample("x", &[("target", "a\"b\\c\nd")], 1);
+        assert_eq!(w.buf, "x{target=\"a\\\"b\\\\c\\nd\"} 1\n");
+    }
+
+    /// Scrape a ctld with one LUN and one session.  Reading port statistics fails, but the rest
+    /// should still be served.
+    #[test]
+    fn scrape() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let _m3 = CTL_ISCSI_MTX.lock().unwrap();
+        let _m4 = CTL_STATS_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists("<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number></serial_number>
+	<device_id>dev0</device_id>
+	<ctld_name>disk0</ctld_name>
+</lun>
+</ctllunlist>", "<ctlportlist></ctlportlist>");
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let path = dir.path().join("ctl.conf");
+        fs::write(&path, format!("
+tag-file = \"{}/tags.json\"
+lun {{
+    disk0 {{
+        backend = ramdisk
+        device-id = dev0
+        size = 1048576
+    }}
+}}
+target {{}}
+", dir.path().display())).unwrap();
+        let conf = Conf::open(&path).unwrap();
+        let state = Arc::new(Mutex::new(State::start(path, conf).unwrap()));
+        let iscsi_ctx = mock_ioc::ctl_iscsi_context();
+        iscsi_ctx.expect()
+            .returning(|_fd, req| {
+                unsafe{ fill_iscsi_list(req, "<ctlislist>
+<connection id=\"3\"><initiator>iqn.1994-09.org.freebsd:a</initiator><initiator_addr>192.0.2.1</initiator_addr><target>iqn.2018-10.org.example:t0</target><target_portal_group_tag>257</target_portal_group_tag><header_digest>None</header_digest><data_digest>None</data_digest><max_recv_data_segment_length>8192</max_recv_data_segment_length><max_send_data_segment_length>8192</max_send_data_segment_length><max_burst_length>262144</max_burst_length><first_burst_length>65536</first_burst_length><immediate_data>0</immediate_data><iser>0</iser></connection>
+</ctlislist>") };
+                Ok(0)
+            });
+
+        let lun_stats_ctx = mock_ioc::ctl_get_lun_stats_context();
+        lun_stats_ctx.expect()
+            .returning(|_fd, req| {
+                // Safe because it's plain data
+                let mut stats: ffi::ctl_io_stats = unsafe{ std::mem::zeroed() };
+                stats.bytes[ffi::ctl_stat_types::CTL_STATS_READ as usize] = 4096;
+                unsafe{ fill_stats(req, &[stats]) };
+                Ok(0)
+            });
+        let port_stats_ctx = mock_ioc::ctl_get_port_stats_context();
+        port_stats_ctx.expect()
+            .returning(|_fd, _req| Err(nix::errno::Errno::ENOTTY));
+
+        let addr = start("127.0.0.1:0".parse().unwrap(), state.clone()).unwrap();
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut buf = String::new();
+            stream.read_to_string(&mut buf).unwrap();
+            buf
+        };
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\r\nContent-Type: application/openmetrics-text;"));
+        assert!(response.contains("\nctld_configured_luns 1\n"));
+        assert!(response.contains("\nctld_luns 1\n"));
+        assert!(response.contains(
+            "\nctld_lun_bytes_total{lun=\"0\",name=\"disk0\",op=\"read\"} 4096\n"));
+        assert!(response.contains(
+            "\nctld_lun_time_seconds_total{lun=\"0\",name=\"disk0\",op=\"write\"} 0\n"));
+        assert!(response.contains("\nctld_sessions{target=\"iqn.2018-10.org.example:t0\"} 1\n"));
+        assert!(!response.contains("ctld_port_bytes"));
+        assert!(response.ends_with("\n# EOF\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        ctx.checkpoint();
+        ctx.expect()
+            .withf(|_fd, req| unsafe{ (**req).reqtype == ffi::ctl_lunreq_type::CTL_LUNREQ_RM })
+            .returning(|_fd, _req| Ok(0));
+        state.lock().unwrap().shutdown();
+    }
+}
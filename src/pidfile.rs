@@ -0,0 +1,134 @@
+//! pidfile(3)-style exclusive lock file
+use std::{
+    fs,
+    io,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use nix::{
+    errno::Errno,
+    fcntl::{Flock, FlockArg},
+};
+
+/// An open and locked pidfile.  Only one process at a time may hold it.
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+    file: Flock<fs::File>
+}
+
+impl Pidfile {
+    /// Create and lock the pidfile, but don't write our pid into it yet.
+    ///
+    /// Fails, naming the other process's pid, if another process already holds the lock.
+    pub fn open(path: &Path) -> Result<Self> {
+        let f = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("opening pidfile {}", path.display()))?;
+        match Flock::lock(f, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => Ok(Pidfile{path: path.to_owned(), file}),
+            Err((_, Errno::EWOULDBLOCK)) => {
+                match Self::read_pid(path) {
+                    Some(pid) => bail!("ctld is already running, pid {}", pid),
+                    None => bail!("ctld is already running")
+                }
+            },
+            Err((_, e)) => {
+                Err(io::Error::from(e))
+                    .with_context(|| format!("locking pidfile {}", path.display()))
+            }
+        }
+    }
+
+    /// Read the pid written by whichever process holds the lock.
+    ///
+    /// Like pidfile(3), give the other process a moment to write it, in case it only just
+    /// took the lock.
+    fn read_pid(path: &Path) -> Option<u32> {
+        for _ in 0..5 {
+            if let Some(pid) = fs::read_to_string(path).ok()
+                .and_then(|s| s.trim().parse().ok())
+            {
+                return Some(pid);
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        None
+    }
+
+    /// Record our pid.  Call this after daemonizing, since that changes the pid.
+    pub fn write(&mut self) -> Result<()> {
+        let pid = format!("{}\n", std::process::id());
+        self.file.set_len(0)
+            .and_then(|_| self.file.write_all_at(pid.as_bytes(), 0))
+            .with_context(|| format!("writing pidfile {}", self.path.display()))
+    }
+
+    /// Remove the pidfile on a clean exit.
+    pub fn remove(self) -> Result<()> {
+        // Unlink before closing, so no other process can lock the file we're about to remove.
+        fs::remove_file(&self.path)
+            .with_context(|| format!("removing pidfile {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    #[test]
+    fn write() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ctld.pid");
+        let mut pidfile = Pidfile::open(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        pidfile.write().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+    }
+
+    /// A stale pidfile, left over from a crash, is not locked and should be overwritten
+    #[test]
+    fn stale() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ctld.pid");
+        fs::write(&path, "9999999999\n").unwrap();
+        let mut pidfile = Pidfile::open(&path).unwrap();
+        pidfile.write().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+    }
+
+    /// A second instance should refuse to start, naming the first one's pid
+    #[test]
+    fn locked() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ctld.pid");
+        let mut pidfile = Pidfile::open(&path).unwrap();
+        pidfile.write().unwrap();
+        let e = Pidfile::open(&path).unwrap_err();
+        assert_eq!(e.to_string(),
+            format!("ctld is already running, pid {}", std::process::id()));
+    }
+
+    #[test]
+    fn remove() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ctld.pid");
+        let mut pidfile = Pidfile::open(&path).unwrap();
+        pidfile.write().unwrap();
+        pidfile.remove().unwrap();
+        assert!(!path.exists());
+        // And now another instance may start
+        Pidfile::open(&path).unwrap();
+    }
+}
@@ -0,0 +1,607 @@
+//! Compute the kernel operations needed to make the running kernel match a configuration,
+//! without performing any of them.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::conf::{self, Conf};
+use crate::kconf;
+
+/// One attribute that differs between the kernel and the configuration.  `None` means that the
+/// attribute is unset on that side.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Change {
+    pub attr: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+impl Change {
+    fn new<T: ToString, U: ToString>(attr: &str, old: Option<T>, new: Option<U>) -> Self {
+        Change {
+            attr: attr.to_owned(),
+            old: old.map(|s| s.to_string()),
+            new: new.map(|s| s.to_string())
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let old = self.old.as_deref().unwrap_or("(unset)");
+        let new = self.new.as_deref().unwrap_or("(unset)");
+        write!(f, "{}: {} -> {}", self.attr, old, new)
+    }
+}
+
+/// A single kernel operation
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Op {
+    /// Create a new LUN with CTL_LUNREQ_CREATE
+    CreateLun {
+        name: String,
+    },
+    /// Change a LUN in place with CTL_LUNREQ_MODIFY
+    ModifyLun {
+        name: String,
+        ctl_lun: u64,
+        changes: Vec<Change>
+    },
+    /// Remove a LUN and create it again, because some attribute can't be modified in place
+    ReplaceLun {
+        name: String,
+        ctl_lun: u64,
+        changes: Vec<Change>
+    },
+    /// Remove a LUN with CTL_LUNREQ_RM
+    RemoveLun {
+        name: String,
+        ctl_lun: u64,
+    },
+    /// Create an iSCSI port for a target in a portal group
+    CreatePort {
+        target: String,
+        portal_group: String,
+    },
+    /// Remove an iSCSI port
+    RemovePort {
+        target: String,
+        portal_group: String,
+        port: String,
+    },
+    /// Map a LUN into a port's LUN map, replacing any LUN already mapped at that number
+    MapLun {
+        target: String,
+        portal_group: String,
+        /// The kernel's port id, unless the port is yet to be created
+        port: Option<String>,
+        lun: u64,
+        name: String,
+    },
+    /// Remove an entry from a port's LUN map
+    UnmapLun {
+        target: String,
+        portal_group: String,
+        port: String,
+        lun: u64,
+    },
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::CreateLun{name} => write!(f, "+ lun \"{}\"", name),
+            Op::ModifyLun{name, ctl_lun, changes} => {
+                write!(f, "~ lun \"{}\" (ctl lun {})", name, ctl_lun)?;
+                for change in changes {
+                    write!(f, "\n    {}", change)?;
+                }
+                Ok(())
+            },
+            Op::ReplaceLun{name, ctl_lun, changes} => {
+                write!(f, "-/+ lun \"{}\" (ctl lun {})", name, ctl_lun)?;
+                for change in changes {
+                    write!(f, "\n    {}", change)?;
+                }
+                Ok(())
+            },
+            Op::RemoveLun{name, ctl_lun} => write!(f, "- lun \"{}\" (ctl lun {})", name, ctl_lun),
+            Op::CreatePort{target, portal_group} => {
+                write!(f, "+ port \"{}\" portal-group \"{}\"", target, portal_group)
+            },
+            Op::RemovePort{target, portal_group, port} => {
+                write!(f, "- port \"{}\" portal-group \"{}\" (port {})", target, portal_group,
+                    port)
+            },
+            Op::MapLun{target, portal_group, lun, name, ..} => {
+                write!(f, "+ port \"{}\" portal-group \"{}\": lun {} -> \"{}\"", target,
+                    portal_group, lun, name)
+            },
+            Op::UnmapLun{target, portal_group, lun, ..} => {
+                write!(f, "- port \"{}\" portal-group \"{}\": lun {}", target, portal_group, lun)
+            },
+        }
+    }
+}
+
+/// Every operation needed to apply a configuration, in the order that they should be performed
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Plan {
+    pub ops: Vec<Op>
+}
+
+impl Plan {
+    /// Compare the configuration to the kernel's current state.
+    ///
+    /// Operations are ordered so that no LUN is ever visible where it isn't configured: CTL gives
+    /// each new LUN the lowest free id, and removing a LUN doesn't remove it from LUN maps.  So
+    /// stale map entries and ports are removed first, then stale LUNs, then LUNs are replaced,
+    /// modified or created, and finally new ports are created and LUNs are mapped.
+    ///
+    /// Only LUNs that have a `ctld_name` option are considered to belong to ctld; others are left
+    /// alone.  Likewise, only iSCSI ports are considered.
+    pub fn new(conf: &Conf, klun_list: &kconf::Ctllunlist, kport_list: &kconf::Ctlportlist)
+        -> Self
+    {
+        let mut remove_luns = Vec::new();
+        let mut lun_ops = Vec::new();
+        let mut remove_ports = Vec::new();
+        let mut create_ports = Vec::new();
+        let mut unmaps = Vec::new();
+        let mut maps = Vec::new();
+
+        let kluns = klun_list.lun.iter()
+            .filter_map(|klun| klun.ctld_name.as_ref().map(|name| (name.as_str(), klun)))
+            .collect::<BTreeMap<_, _>>();
+        let luns = conf.luns.iter()
+            .map(|(name, lun)| (name.as_str(), lun))
+            .collect::<BTreeMap<_, _>>();
+        // LUNs whose kernel id will change, and so must be mapped again wherever they're used
+        let mut remapped = Vec::new();
+        for (name, klun) in kluns.iter() {
+            if !luns.contains_key(name) {
+                remove_luns.push(Op::RemoveLun{name: name.to_string(), ctl_lun: klun.id});
+            }
+        }
+        for (name, lun) in luns.iter() {
+            match kluns.get(name) {
+                None => {
+                    lun_ops.push(Op::CreateLun{name: name.to_string()});
+                    remapped.push(*name);
+                },
+                Some(klun) => {
+                    let (replace, modify) = diff_lun(lun, klun);
+                    if !replace.is_empty() {
+                        let mut changes = replace;
+                        changes.extend(modify);
+                        lun_ops.push(Op::ReplaceLun{name: name.to_string(), ctl_lun: klun.id,
+                            changes});
+                        remapped.push(*name);
+                    } else if !modify.is_empty() {
+                        lun_ops.push(Op::ModifyLun{name: name.to_string(), ctl_lun: klun.id,
+                            changes: modify});
+                    }
+                }
+            }
+        }
+
+        let lun_names = klun_list.lun.iter()
+            .filter_map(|klun| klun.ctld_name.as_ref().map(|name| (klun.id, name.as_str())))
+            .collect::<HashMap<_, _>>();
+        let kports = kport_list.targ_port.iter()
+            .filter(|kport| kport.frontend_type == kconf::Frontend::Iscsi)
+            .filter_map(|kport| {
+                let target = kport.cfiscsi_target.as_deref()?;
+                let pg = kport.ctld_portal_group_name.clone()
+                    .or_else(|| {
+                        // Ports created by something other than ctld have no portal group name,
+                        // so look for a portal group with the same tag
+                        let tag = kport.cfiscsi_portal_group_tag?;
+                        conf.portal_groups.iter()
+                            .find(|(_, pg)| pg.tag == Some(tag))
+                            .map(|(name, _)| name.clone())
+                    })
+                    .unwrap_or_else(|| {
+                        format!("pg{}", kport.cfiscsi_portal_group_tag.unwrap_or_default())
+                    });
+                Some(((target.to_owned(), pg), kport))
+            }).collect::<BTreeMap<_, _>>();
+        let ports = conf.targets.iter()
+            .flat_map(|(name, target)| {
+                target.portal_groups.iter()
+                    .map(move |tpg| ((name.clone(), tpg.name.clone()), target))
+            }).collect::<BTreeMap<_, _>>();
+        for ((target, pg), kport) in kports.iter() {
+            if !ports.contains_key(&(target.clone(), pg.clone())) {
+                remove_ports.push(Op::RemovePort{target: target.clone(), portal_group: pg.clone(),
+                    port: kport.id.clone()});
+            }
+        }
+        for ((target_name, pg), target) in ports.iter() {
+            let map = target.lun.iter()
+                .map(|tl| (tl.number, tl.name.as_str()))
+                .collect::<BTreeMap<_, _>>();
+            let kport = kports.get(&(target_name.clone(), pg.clone()));
+            let port = kport.map(|kport| kport.id.clone());
+            let kmap = match kport {
+                Some(kport) => kport.luns.iter()
+                    .map(|tl| (u64::from(tl.id), lun_names.get(&u64::from(tl.lun)).copied()))
+                    .collect::<BTreeMap<_, _>>(),
+                None => {
+                    create_ports.push(Op::CreatePort{target: target_name.clone(),
+                        portal_group: pg.clone()});
+                    BTreeMap::new()
+                }
+            };
+            if let Some(kport) = kport {
+                for number in kmap.keys().filter(|number| !map.contains_key(number)) {
+                    unmaps.push(Op::UnmapLun{target: target_name.clone(), portal_group: pg.clone(),
+                        port: kport.id.clone(), lun: *number});
+                }
+            }
+            for (number, name) in map.iter() {
+                if kmap.get(number) != Some(&Some(*name)) || remapped.contains(name) {
+                    maps.push(Op::MapLun{target: target_name.clone(), portal_group: pg.clone(),
+                        port: port.clone(), lun: *number, name: name.to_string()});
+                }
+            }
+        }
+
+        let mut ops = unmaps;
+        ops.extend(remove_ports);
+        ops.extend(remove_luns);
+        ops.extend(lun_ops);
+        ops.extend(create_ports);
+        ops.extend(maps);
+        Plan{ops}
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ops.is_empty() {
+            return writeln!(f, "No changes");
+        }
+        for op in self.ops.iter() {
+            writeln!(f, "{}", op)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare a configured LUN to the kernel's copy.  Returns the changes that require the LUN to be
+/// recreated, and the changes that CTL_LUNREQ_MODIFY can make in place.
+fn diff_lun(lun: &conf::Lun, klun: &kconf::Lun) -> (Vec<Change>, Vec<Change>) {
+    let mut replace = Vec::new();
+    let mut modify = Vec::new();
+
+    if lun.backend != klun.backend_type {
+        replace.push(Change::new("backend", Some(<&str>::from(klun.backend_type)),
+            Some(<&str>::from(lun.backend))));
+    }
+    if lun.device_type != klun.lun_type {
+        replace.push(Change::new("device-type", Some(<&str>::from(klun.lun_type)),
+            Some(<&str>::from(lun.device_type))));
+    }
+    let blocksize = lun.blocksize();
+    if blocksize != klun.blocksize {
+        replace.push(Change::new("blocksize", Some(klun.blocksize), Some(blocksize)));
+    }
+    if let Some(ctl_lun) = lun.ctl_lun {
+        if u64::from(ctl_lun) != klun.id {
+            replace.push(Change::new("ctl_lun", Some(klun.id), Some(ctl_lun)));
+        }
+    }
+    if lun.device_id != klun.device_id {
+        replace.push(Change::new("device-id", Some(&klun.device_id), Some(&lun.device_id)));
+    }
+    if let Some(serial) = &lun.serial {
+        if *serial != klun.serial_number {
+            replace.push(Change::new("serial", Some(&klun.serial_number), Some(serial)));
+        }
+    }
+
+    let size = klun.size * u64::from(klun.blocksize);
+    if let Some(new_size) = lun.size {
+        if new_size != size {
+            modify.push(Change::new("size", Some(size), Some(new_size)));
+        }
+    }
+    // CTL_LUNREQ_MODIFY only re-reads the size of a backing file that's already open, so a new
+    // path needs a new LUN
+    let path = lun.path.as_ref().map(|p| p.to_string_lossy().into_owned());
+    if path != klun.file {
+        replace.push(Change::new("path", klun.file.as_ref(), path.as_ref()));
+    }
+
+    let mut options = lun.options.to_kernel().into_iter()
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect::<BTreeMap<_, _>>();
+    options.extend(lun.raw_options.iter().map(|(k, v)| (k.clone(), v.clone())));
+    let mut koptions = klun.options.to_kernel().into_iter()
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect::<BTreeMap<_, _>>();
+    // The block backend always reports its number of threads, so only compare it if it was
+    // configured.
+    if let Some(num_threads) = klun.num_threads {
+        if options.contains_key("num_threads") {
+            koptions.insert(String::from("num_threads"), num_threads.to_string());
+        }
+    }
+    let mut keys = options.keys().chain(koptions.keys()).collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys.dedup();
+    for k in keys {
+        let (old, new) = (koptions.get(k), options.get(k));
+        if old != new {
+            modify.push(Change::new(&format!("option {}", k), old, new));
+        }
+    }
+
+    (replace, modify)
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    const CONF: &str = "
+portal-group {
+    pg0 {
+        listen = \"0.0.0.0:3260\"
+    }
+}
+lun {
+    disk0 {
+        backend = ramdisk
+        device-id = dev0
+        serial = ser0
+        size = 1048576
+        option {
+            vendor = \"FreeBSD\"
+        }
+    }
+    disk1 {
+        backend = ramdisk
+        device-id = dev1
+        serial = ser1
+        size = 1048576
+    }
+}
+target {
+    \"iqn.2018-10.org.example:t0\" {
+        auth-group = no-authentication
+        portal-group { name = pg0 }
+        lun = [
+            { number = 0, name = disk0 },
+            { number = 1, name = disk1 },
+        ]
+    }
+}
+";
+
+    /// The kernel state that matches CONF exactly
+    const LUNS: &str = "<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>ser0</serial_number>
+	<device_id>dev0</device_id>
+	<ctld_name>disk0</ctld_name>
+	<vendor>FreeBSD</vendor>
+</lun>
+<lun id=\"1\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>ser1</serial_number>
+	<device_id>dev1</device_id>
+	<ctld_name>disk1</ctld_name>
+</lun>
+</ctllunlist>";
+
+    const PORTS: &str = "<ctlportlist>
+<targ_port id=\"0\">
+	<frontend_type>camsim</frontend_type>
+	<port_type>8</port_type>
+	<online>NO</online>
+	<port_name>camsim</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+</targ_port>
+<targ_port id=\"3\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<ctld_portal_group_name>pg0</ctld_portal_group_name>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">0</lun>
+	<lun id=\"1\">1</lun>
+</targ_port>
+</ctlportlist>";
+
+    const T0: &str = "iqn.2018-10.org.example:t0";
+
+    fn plan(conf: &str, luns: &str, ports: &str) -> Plan {
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(conf.as_bytes()).unwrap();
+        let conf = Conf::open(f.path()).unwrap();
+        let klun_list = kconf::Ctllunlist::from_xml(luns).unwrap();
+        let kport_list = kconf::Ctlportlist::from_xml(ports).unwrap();
+        Plan::new(&conf, &klun_list, &kport_list)
+    }
+
+    fn map(port: Option<&str>, lun: u64, name: &str) -> Op {
+        Op::MapLun{target: T0.to_owned(), portal_group: String::from("pg0"),
+            port: port.map(str::to_owned), lun, name: name.to_owned()}
+    }
+
+    /// Starting from scratch, everything must be created
+    #[test]
+    fn empty_kernel() {
+        let plan = plan(CONF, "<ctllunlist></ctllunlist>", "<ctlportlist></ctlportlist>");
+        assert_eq!(plan.ops, vec![
+            Op::CreateLun{name: String::from("disk0")},
+            Op::CreateLun{name: String::from("disk1")},
+            Op::CreatePort{target: T0.to_owned(), portal_group: String::from("pg0")},
+            map(None, 0, "disk0"),
+            map(None, 1, "disk1"),
+        ]);
+    }
+
+    #[test]
+    fn no_changes() {
+        let plan = plan(CONF, LUNS, PORTS);
+        assert!(plan.is_empty(), "{}", plan);
+        assert_eq!(plan.to_string(), "No changes\n");
+    }
+
+    /// Size and options can be changed in place
+    #[test]
+    fn modify() {
+        let conf = CONF
+            .replace("vendor = \"FreeBSD\"", "vendor = \"Acme\"\n            readonly = \"on\"")
+            .replace("serial = ser1\n        size = 1048576",
+                "serial = ser1\n        size = 2097152");
+        let plan = plan(&conf, LUNS, PORTS);
+        assert_eq!(plan.ops, vec![
+            Op::ModifyLun{name: String::from("disk0"), ctl_lun: 0, changes: vec![
+                Change::new("option readonly", None::<&str>, Some("on")),
+                Change::new("option vendor", Some("FreeBSD"), Some("Acme")),
+            ]},
+            Op::ModifyLun{name: String::from("disk1"), ctl_lun: 1, changes: vec![
+                Change::new("size", Some(1048576), Some(2097152)),
+            ]},
+        ]);
+        assert_eq!(plan.to_string(), "~ lun \"disk0\" (ctl lun 0)
+    option readonly: (unset) -> on
+    option vendor: FreeBSD -> Acme
+~ lun \"disk1\" (ctl lun 1)
+    size: 1048576 -> 2097152
+");
+    }
+
+    /// Changing the blocksize requires recreating the LUN, which changes its CTL LUN number
+    #[test]
+    fn replace() {
+        let conf = CONF.replace("serial = ser1", "serial = ser1\n        blocksize = 4096");
+        let plan = plan(&conf, LUNS, PORTS);
+        assert_eq!(plan.ops, vec![
+            Op::ReplaceLun{name: String::from("disk1"), ctl_lun: 1, changes: vec![
+                Change::new("blocksize", Some(512), Some(4096)),
+            ]},
+            map(Some("3"), 1, "disk1"),
+        ]);
+    }
+
+    /// Changing the backing path requires recreating the LUN
+    #[test]
+    fn replace_path() {
+        let luns = LUNS.replace("<ctld_name>disk1</ctld_name>",
+            "<ctld_name>disk1</ctld_name>\n\t<file>/dev/zvol/old</file>");
+        let plan = plan(CONF, &luns, PORTS);
+        assert_eq!(plan.ops, vec![
+            Op::ReplaceLun{name: String::from("disk1"), ctl_lun: 1, changes: vec![
+                Change::new("path", Some("/dev/zvol/old"), None::<&str>),
+            ]},
+            map(Some("3"), 1, "disk1"),
+        ]);
+    }
+
+    /// A cd LUN without a blocksize gets the kernel's default of 2048, so it isn't replaced
+    #[test]
+    fn cd_blocksize() {
+        let conf = CONF.replace("serial = ser1", "serial = ser1\n        device-type = cd");
+        let luns = LUNS.replace("<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>ser1</serial_number>", "<lun_type>5</lun_type>
+	<size>512</size>
+	<blocksize>2048</blocksize>
+	<serial_number>ser1</serial_number>");
+        let plan = plan(&conf, &luns, PORTS);
+        assert!(plan.is_empty(), "{}", plan);
+    }
+
+    /// Stale LUNs, ports and LUN map entries are removed, but LUNs that ctld didn't create are
+    /// left alone
+    #[test]
+    fn remove() {
+        let conf = CONF.replace("            { number = 1, name = disk1 },\n", "");
+        let luns = LUNS.replace("</ctllunlist>", "<lun id=\"2\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>ser2</serial_number>
+	<device_id>dev2</device_id>
+	<ctld_name>disk2</ctld_name>
+</lun>
+<lun id=\"3\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>manual</serial_number>
+	<device_id>manual</device_id>
+</lun>
+</ctllunlist>");
+        let ports = PORTS.replace("</ctlportlist>", "<targ_port id=\"4\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:old</cfiscsi_target>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<ctld_portal_group_name>pg0</ctld_portal_group_name>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">2</lun>
+</targ_port>
+</ctlportlist>");
+        let plan = plan(&conf, &luns, &ports);
+        assert_eq!(plan.ops, vec![
+            Op::UnmapLun{target: T0.to_owned(), portal_group: String::from("pg0"),
+                port: String::from("3"), lun: 1},
+            Op::RemovePort{target: String::from("iqn.2018-10.org.example:old"),
+                portal_group: String::from("pg0"), port: String::from("4")},
+            Op::RemoveLun{name: String::from("disk2"), ctl_lun: 2},
+        ]);
+    }
+
+    #[test]
+    fn json() {
+        let plan = plan(CONF, "<ctllunlist></ctllunlist>", "<ctlportlist></ctlportlist>");
+        let json = serde_json::to_value(&plan).unwrap();
+        assert_eq!(json["ops"][0], serde_json::json!({"op": "create_lun", "name": "disk0"}));
+        assert_eq!(json["ops"][3], serde_json::json!({
+            "op": "map_lun",
+            "target": T0,
+            "portal_group": "pg0",
+            "port": null,
+            "lun": 0,
+            "name": "disk0"
+        }));
+    }
+}
@@ -0,0 +1,333 @@
+//! A string type for credentials, such as CHAP secrets, and the places they can be loaded from
+use std::{
+    fmt,
+    fs,
+    hint::black_box,
+    io::Read,
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::PathBuf,
+    process::{Command, Stdio},
+    ptr,
+    str::FromStr,
+    sync::atomic::{Ordering, compiler_fence},
+};
+
+use anyhow::{Context, Result, bail};
+use nix::{libc, unistd::geteuid};
+
+/// What a [`Secret`] prints as
+pub const REDACTED: &str = "<redacted>";
+
+/// A secret string.
+///
+/// It prints as `<redacted>`, compares in constant time, and overwrites its contents when
+/// dropped.  Use [`Secret::expose`] to get at the value, and don't log the result.
+#[derive(Clone, Default)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(s: String) -> Self {
+        Secret(s)
+    }
+
+    /// Get the secret value.  Callers must be careful not to log or copy it needlessly.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Overwrite the value with zeros
+    fn zeroize(&mut self) {
+        // Safe because we only write zeros, which are valid UTF-8.
+        let bytes = unsafe { self.0.as_mut_vec() };
+        for b in bytes.iter_mut() {
+            // Volatile, so the compiler can't elide the writes to memory that's about to be freed
+            unsafe { ptr::write_volatile(b, 0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// Compare two byte strings in time that depends only on their lengths
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | black_box(x ^ y));
+    black_box(diff) == 0
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        ct_eq(self.0.as_bytes(), other.0.as_bytes())
+    }
+}
+
+impl Eq for Secret {}
+
+impl PartialEq<str> for Secret {
+    fn eq(&self, other: &str) -> bool {
+        ct_eq(self.0.as_bytes(), other.as_bytes())
+    }
+}
+
+impl PartialEq<&str> for Secret {
+    fn eq(&self, other: &&str) -> bool {
+        ct_eq(self.0.as_bytes(), other.as_bytes())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Secret(s)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Secret(s.to_owned())
+    }
+}
+
+impl FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Secret(s.to_owned()))
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+/// Somewhere other than the configuration file that a secret can be loaded from
+pub trait SecretProvider: fmt::Debug {
+    /// Fetch the secret's current value
+    fn load(&self) -> Result<Secret>;
+}
+
+/// Strip a single trailing newline, as left by most editors and by `echo`
+fn chomp(s: &mut String) {
+    if s.ends_with('\n') {
+        s.pop();
+        if s.ends_with('\r') {
+            s.pop();
+        }
+    }
+}
+
+/// Reads a secret from a file, which must be owned by root or ctld's effective user, and must not
+/// be accessible by anybody but its owner
+#[derive(Clone, Debug)]
+pub struct FileProvider(pub PathBuf);
+
+impl SecretProvider for FileProvider {
+    fn load(&self) -> Result<Secret> {
+        let path = &self.0;
+        // Check and read the same file, so it can't be swapped out in between.  Don't follow
+        // symlinks, and don't block opening a FIFO.
+        let mut f = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(path)
+            .with_context(|| format!("{}", path.display()))?;
+        let md = f.metadata().with_context(|| format!("{}", path.display()))?;
+        if !md.is_file() {
+            bail!("{} is not a regular file", path.display());
+        }
+        if md.uid() != 0 && md.uid() != geteuid().as_raw() {
+            bail!("{} must be owned by root or uid {} (owner is uid {})", path.display(),
+                geteuid(), md.uid());
+        }
+        if md.mode() & 0o077 != 0 {
+            bail!("{} must not be accessible by group or other (mode is {:o})", path.display(),
+                md.mode() & 0o777);
+        }
+        let mut s = String::new();
+        f.read_to_string(&mut s).with_context(|| format!("{}", path.display()))?;
+        chomp(&mut s);
+        let secret = Secret(s);
+        if secret.is_empty() {
+            bail!("{} is empty", path.display());
+        }
+        Ok(secret)
+    }
+}
+
+/// Gets a secret from the standard output of a shell command
+#[derive(Clone, Debug)]
+pub struct CommandProvider(pub String);
+
+impl SecretProvider for CommandProvider {
+    fn load(&self) -> Result<Secret> {
+        let output = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&self.0)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("running \"{}\"", self.0))?;
+        if !output.status.success() {
+            bail!("\"{}\" failed: {}", self.0, output.status);
+        }
+        let mut s = String::from_utf8(output.stdout)
+            .with_context(|| format!("\"{}\" printed invalid UTF-8", self.0))?;
+        chomp(&mut s);
+        let secret = Secret(s);
+        if secret.is_empty() {
+            bail!("\"{}\" printed nothing", self.0);
+        }
+        Ok(secret)
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn debug() {
+        let s = Secret::from("hunter2");
+        assert_eq!(format!("{:?}", s), "<redacted>");
+        assert_eq!(format!("{}", s), "<redacted>");
+        assert_eq!(format!("{:?}", Some(s)), "Some(<redacted>)");
+    }
+
+    #[test]
+    fn eq() {
+        let s = Secret::from("hunter2");
+        assert_eq!(s, Secret::from("hunter2"));
+        assert_eq!(s, "hunter2");
+        assert_ne!(s, Secret::from("hunter3"));
+        assert_ne!(s, Secret::from("hunter22"));
+        assert_ne!(s, Secret::from(""));
+    }
+
+    #[test]
+    fn expose() {
+        let s: Secret = "hunter2".parse().unwrap();
+        assert_eq!(s.expose(), "hunter2");
+        assert_eq!(s.len(), 7);
+        assert!(!s.is_empty());
+    }
+
+    #[test]
+    fn zeroize() {
+        let mut s = Secret::from("hunter2");
+        s.zeroize();
+        assert_eq!(s.expose().as_bytes(), &[0; 7]);
+    }
+
+    mod file {
+        use super::*;
+
+        use std::{io::Write, os::unix::fs::PermissionsExt};
+
+        use tempfile::NamedTempFile;
+
+        fn secret_file(contents: &str, mode: u32) -> NamedTempFile {
+            let mut f = NamedTempFile::new().unwrap();
+            f.write_all(contents.as_bytes()).unwrap();
+            fs::set_permissions(f.path(), fs::Permissions::from_mode(mode)).unwrap();
+            f
+        }
+
+        #[test]
+        fn ok() {
+            let f = secret_file("hunter2\n", 0o600);
+            let secret = FileProvider(f.path().to_owned()).load().unwrap();
+            assert_eq!(secret, "hunter2");
+        }
+
+        #[test]
+        fn no_newline() {
+            let f = secret_file("hunter2", 0o400);
+            let secret = FileProvider(f.path().to_owned()).load().unwrap();
+            assert_eq!(secret, "hunter2");
+        }
+
+        #[test]
+        fn empty() {
+            let f = secret_file("\n", 0o600);
+            let e = FileProvider(f.path().to_owned()).load().unwrap_err();
+            assert!(e.to_string().ends_with("is empty"), "{}", e);
+        }
+
+        #[test]
+        fn group_readable() {
+            let f = secret_file("hunter2\n", 0o640);
+            let e = FileProvider(f.path().to_owned()).load().unwrap_err();
+            assert!(e.to_string().ends_with("must not be accessible by group or other (mode is 640)"),
+                "{}", e);
+        }
+
+        #[test]
+        fn missing() {
+            FileProvider(PathBuf::from("/nonexistent/secret")).load().unwrap_err();
+        }
+
+        /// A symlink could be repointed after it's checked, so it isn't followed at all
+        #[test]
+        fn symlink() {
+            let f = secret_file("hunter2\n", 0o600);
+            let dir = tempfile::TempDir::new().unwrap();
+            let link = dir.path().join("secret");
+            std::os::unix::fs::symlink(f.path(), &link).unwrap();
+            FileProvider(link).load().unwrap_err();
+        }
+
+        #[test]
+        fn directory() {
+            let dir = tempfile::TempDir::new().unwrap();
+            fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+            let e = FileProvider(dir.path().to_owned()).load().unwrap_err();
+            assert!(e.to_string().ends_with("is not a regular file"), "{}", e);
+        }
+    }
+
+    mod command {
+        use super::*;
+
+        #[test]
+        fn ok() {
+            let secret = CommandProvider(String::from("echo hunter2")).load().unwrap();
+            assert_eq!(secret, "hunter2");
+        }
+
+        #[test]
+        fn failed() {
+            let e = CommandProvider(String::from("echo hunter2; false")).load().unwrap_err();
+            assert!(e.to_string().starts_with("\"echo hunter2; false\" failed"), "{}", e);
+        }
+
+        #[test]
+        fn empty() {
+            let e = CommandProvider(String::from("true")).load().unwrap_err();
+            assert_eq!(e.to_string(), "\"true\" printed nothing");
+        }
+    }
+}
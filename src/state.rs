@@ -0,0 +1,734 @@
+//! ctld's runtime state: the active configuration, and the kernel objects created from it
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    path::PathBuf,
+};
+
+use anyhow::{Context, Result, bail};
+use serde_derive::Serialize;
+
+use crate::conf::{self, Conf, sorted};
+use crate::isns;
+use crate::kconf;
+use crate::kernel;
+use crate::metrics;
+use crate::plan::{Op, Plan};
+use crate::tags;
+use crate::{error, info, trace, warn};
+
+/// A configured target, as reported by [`State::status`]
+#[derive(Debug, Serialize)]
+pub struct TargetStatus {
+    pub name: String,
+    pub alias: Option<String>,
+    pub portal_groups: Vec<String>,
+    /// LUN names, by LUN number
+    pub luns: BTreeMap<u64, String>,
+}
+
+/// Everything that an administrator might want to know about a running ctld
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub targets: Vec<TargetStatus>,
+    /// Every LUN in the kernel, including those that don't belong to ctld
+    pub luns: Vec<kconf::Lun>,
+    /// Every port in the kernel, including those that don't belong to ctld
+    pub ports: Vec<kconf::TargPort>,
+    pub sessions: Vec<kconf::Connection>,
+}
+
+/// The running configuration, and the kernel objects that ctld owns.
+///
+/// Changes made at runtime, like [`State::add_lun`], are not saved to the configuration file, so
+/// the next [`State::reload`] will undo them unless the file has been changed to match.
+#[derive(Debug)]
+pub struct State {
+    /// Where the configuration was read from, so it can be reloaded
+    config: PathBuf,
+    conf: Conf,
+    /// LUNs owned by ctld, by name
+    luns: BTreeMap<String, kernel::Lun>,
+    isns: Option<isns::Service>,
+}
+
+impl State {
+    /// Apply an initial configuration to the running kernel, and start ctld's services
+    pub fn start(config: PathBuf, mut conf: Conf) -> Result<Self> {
+        let mut luns = BTreeMap::new();
+        apply(&mut conf, &mut luns)?;
+        let isns = isns::Service::from_conf(&conf).context("starting iSNS")?;
+        Ok(State{config, conf, luns, isns})
+    }
+
+    /// The running configuration
+    pub fn conf(&self) -> &Conf {
+        &self.conf
+    }
+
+    /// Reread the configuration file and apply it.  Returns the operations that were performed.
+    ///
+    /// If the file is invalid, nothing changes.  If applying it fails partway, the kernel is left
+    /// partially updated; a later reload will pick up where this one left off.
+    pub fn reload(&mut self) -> Result<Plan> {
+        let r = self.reload_inner();
+        metrics::reload(r.is_ok());
+        r
+    }
+
+    fn reload_inner(&mut self) -> Result<Plan> {
+        let mut conf = Conf::open(&self.config)?;
+        let plan = apply(&mut conf, &mut self.luns)?;
+        terminate_removed(&self.conf, &conf)?;
+        let same_servers = conf.isns_server == self.conf.isns_server &&
+            conf.isns_timeout == self.conf.isns_timeout &&
+            conf.isns_period == self.conf.isns_period;
+        match &self.isns {
+            Some(isns) if same_servers => isns.update_from_conf(&conf)?,
+            _ => {
+                // Deregister from the old servers before registering with the new ones
+                self.isns = None;
+                self.isns = isns::Service::from_conf(&conf).context("starting iSNS")?;
+            }
+        }
+        self.conf = conf;
+        Ok(plan)
+    }
+
+    /// Compare the configuration file to the kernel, without changing anything
+    pub fn plan(&self) -> Result<Plan> {
+        let conf = Conf::open(&self.config)?;
+        let klun_list = kconf::Ctllunlist::from_kernel().context("getting LUN list")?;
+        let kport_list = kconf::Ctlportlist::from_kernel().context("getting port list")?;
+        Ok(Plan::new(&conf, &klun_list, &kport_list))
+    }
+
+    /// Query the kernel for ctld's targets, and for every LUN, port, and session
+    pub fn status(&self) -> Result<Status> {
+        let targets = sorted(&self.conf.targets).into_iter()
+            .map(|(name, target)| TargetStatus {
+                name: name.clone(),
+                alias: target.alias.clone(),
+                portal_groups: target.portal_groups.iter().map(|tpg| tpg.name.clone()).collect(),
+                luns: target.lun.iter().map(|tl| (tl.number, tl.name.clone())).collect()
+            }).collect();
+        let luns = kconf::Ctllunlist::from_kernel().context("getting LUN list")?.lun;
+        let ports = kconf::Ctlportlist::from_kernel().context("getting port list")?.targ_port;
+        let sessions = kconf::Ctlislist::from_kernel().context("getting session list")?
+            .connection;
+        Ok(Status{targets, luns, ports, sessions})
+    }
+
+    /// Create a new LUN
+    pub fn add_lun(&mut self, name: &str, lun: conf::Lun) -> Result<()> {
+        if self.conf.luns.contains_key(name) {
+            bail!("lun \"{}\" already exists", name);
+        }
+        self.conf.luns.insert(name.to_owned(), lun);
+        let r = self.conf.validate()
+            .and_then(|_| kernel::Lun::create(name, &self.conf.luns[name]));
+        match r {
+            Ok(klun) => {
+                info!("created lun \"{}\" (ctl lun {})", name, klun.id());
+                self.luns.insert(name.to_owned(), klun);
+                Ok(())
+            },
+            Err(e) => {
+                self.conf.luns.remove(name);
+                Err(e)
+            }
+        }
+    }
+
+    /// Remove a LUN.  It must not be used by any target.
+    pub fn remove_lun(&mut self, name: &str) -> Result<()> {
+        if !self.conf.luns.contains_key(name) {
+            bail!("lun \"{}\" does not exist", name);
+        }
+        for (tname, target) in sorted(&self.conf.targets) {
+            if let Some(tl) = target.lun.iter().find(|tl| tl.name == name) {
+                bail!("lun \"{}\" is used by target \"{}\" as lun {}", name, tname, tl.number);
+            }
+        }
+        if let Some(klun) = self.luns.remove(name) {
+            klun.remove().with_context(|| format!("removing lun \"{}\"", name))?;
+        }
+        self.conf.luns.remove(name);
+        info!("removed lun \"{}\"", name);
+        Ok(())
+    }
+
+    /// Change a LUN's size, in bytes
+    pub fn resize_lun(&mut self, name: &str, size: u64) -> Result<()> {
+        let (Some(lun), Some(klun)) = (self.conf.luns.get(name), self.luns.get(name)) else {
+            bail!("lun \"{}\" does not exist", name);
+        };
+        let mut lun = lun.clone();
+        lun.size = Some(size);
+        lun.validate().with_context(|| format!("lun \"{}\"", name))?;
+        klun.modify(name, &lun).with_context(|| format!("resizing lun \"{}\"", name))?;
+        self.conf.luns.insert(name.to_owned(), lun);
+        info!("resized lun \"{}\" to {} bytes", name, size);
+        Ok(())
+    }
+
+    /// Deregister from iSNS, and remove ctld's LUNs from the kernel
+    pub fn shutdown(&mut self) {
+        drop(self.isns.take());
+        for (name, klun) in mem::take(&mut self.luns) {
+            if let Err(e) = klun.remove() {
+                error!("removing lun \"{}\": {:?}", name, e);
+            }
+        }
+    }
+}
+
+/// Make the kernel match the configuration, as far as ctld knows how.  Returns the operations
+/// that were performed, which may be fewer than were planned.
+fn apply(conf: &mut Conf, luns: &mut BTreeMap<String, kernel::Lun>) -> Result<Plan> {
+    let klun_list = kconf::Ctllunlist::from_kernel().context("getting LUN list")?;
+    trace!("{:?}", klun_list);
+    let kport_list = kconf::Ctlportlist::from_kernel().context("getting port list")?;
+    trace!("{:?}", kport_list);
+
+    // Give every portal group a tag, reusing the ones from last time
+    let saved_tags = tags::load(&conf.tag_file)?;
+    tags::assign(conf, &kport_list, &saved_tags).context("assigning portal-group tags")?;
+    tags::save(&conf.tag_file, conf)?;
+
+    // Take ownership of any LUNs left behind by a previous instance of ctld
+    for klun in klun_list.lun.iter() {
+        if let Some(name) = &klun.ctld_name {
+            if !luns.contains_key(name) {
+                let id = u32::try_from(klun.id).context("ctl lun id")?;
+                luns.insert(name.clone(), kernel::Lun::adopt(klun.backend_type, id));
+            }
+        }
+    }
+
+    let plan = Plan::new(conf, &klun_list, &kport_list);
+    let mut performed = Vec::new();
+    for op in plan.ops.into_iter() {
+        match &op {
+            Op::CreateLun{name} => {
+                let klun = kernel::Lun::create(name, &conf.luns[name])
+                    .with_context(|| format!("creating lun \"{}\"", name))?;
+                luns.insert(name.clone(), klun);
+            },
+            Op::ModifyLun{name, ..} => {
+                luns[name].modify(name, &conf.luns[name])
+                    .with_context(|| format!("modifying lun \"{}\"", name))?;
+            },
+            Op::ReplaceLun{name, ..} => {
+                luns.remove(name)
+                    .with_context(|| format!("lun \"{}\" does not exist", name))?
+                    .remove()
+                    .with_context(|| format!("removing lun \"{}\"", name))?;
+                let klun = kernel::Lun::create(name, &conf.luns[name])
+                    .with_context(|| format!("creating lun \"{}\"", name))?;
+                luns.insert(name.clone(), klun);
+            },
+            Op::RemoveLun{name, ..} => {
+                luns.remove(name)
+                    .with_context(|| format!("lun \"{}\" does not exist", name))?
+                    .remove()
+                    .with_context(|| format!("removing lun \"{}\"", name))?;
+            },
+            Op::MapLun{port: Some(port), lun, name, ..} => {
+                let klun = luns.get(name)
+                    .with_context(|| format!("lun \"{}\" does not exist", name))?;
+                kernel::set_lun_map(port_id(port)?, u32::try_from(*lun)?, Some(klun.id()))
+                    .with_context(|| format!("mapping lun \"{}\"", name))?;
+            },
+            Op::UnmapLun{port, lun, ..} => {
+                kernel::set_lun_map(port_id(port)?, u32::try_from(*lun)?, None)
+                    .with_context(|| format!("unmapping lun {}", lun))?;
+            },
+            // TODO: create and remove ports
+            Op::CreatePort{..} | Op::RemovePort{..} | Op::MapLun{port: None, ..} => {
+                warn!("not implemented yet: {}", op);
+                continue;
+            }
+        }
+        info!("{}", op);
+        performed.push(op);
+    }
+    Ok(Plan{ops: performed})
+}
+
+/// Forcibly end every session to a target that's in the old configuration but not the new one
+fn terminate_removed(old: &Conf, new: &Conf) -> Result<()> {
+    let removed = old.targets.keys()
+        .filter(|name| !new.targets.contains_key(*name))
+        .collect::<BTreeSet<_>>();
+    if removed.is_empty() {
+        return Ok(());
+    }
+    // The kernel can't select sessions by target, so terminate them one at a time
+    let sessions = kconf::Ctlislist::from_kernel().context("getting session list")?;
+    for conn in sessions.connection.iter().filter(|conn| removed.contains(&conn.target)) {
+        kernel::terminate(&kernel::SessionFilter::Id(conn.id))
+            .with_context(|| format!("terminating session {}", conn.id))?;
+        info!("terminated session {} from \"{}\" to removed target \"{}\"", conn.id,
+            conn.initiator, conn.target);
+    }
+    Ok(())
+}
+
+/// Parse the kernel's port id, as published in the port list
+fn port_id(port: &str) -> Result<u32> {
+    port.parse().with_context(|| format!("invalid port id \"{}\"", port))
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use std::{
+        fs,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use tempfile::TempDir;
+
+    use crate::ffi;
+    use crate::ioc::{
+        CTL_ISCSI_MTX,
+        CTL_LIST_MTX,
+        CTL_LUN_MAP_MTX,
+        CTL_LUN_REQ_MTX,
+        expect_lists,
+        fill_iscsi_list,
+        mock_ioc
+    };
+
+    const CONF: &str = "
+tag-file = \"@DIR@/tags.json\"
+lun {
+    disk0 {
+        backend = ramdisk
+        device-id = dev0
+        serial = ser0
+        size = 1048576
+    }
+    disk1 {
+        backend = ramdisk
+        device-id = dev1
+        serial = ser1
+        size = 1048576
+    }
+}
+target {
+    \"iqn.2018-10.org.example:t0\" {
+        auth-group = no-authentication
+        lun = [
+            { number = 0, name = disk0 },
+        ]
+    }
+}
+";
+
+    /// The kernel state that matches CONF, as left by a previous ctld
+    const LUNS: &str = "<ctllunlist>
+<lun id=\"0\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>ser0</serial_number>
+	<device_id>dev0</device_id>
+	<ctld_name>disk0</ctld_name>
+</lun>
+<lun id=\"1\">
+	<backend_type>ramdisk</backend_type>
+	<lun_type>0</lun_type>
+	<size>2048</size>
+	<blocksize>512</blocksize>
+	<serial_number>ser1</serial_number>
+	<device_id>dev1</device_id>
+	<ctld_name>disk1</ctld_name>
+</lun>
+</ctllunlist>";
+
+    const NO_LUNS: &str = "<ctllunlist></ctllunlist>";
+    const NO_PORTS: &str = "<ctlportlist></ctlportlist>";
+
+    fn is(req: &*mut ffi::ctl_lun_req, reqtype: ffi::ctl_lunreq_type) -> bool {
+        unsafe{ (**req).reqtype == reqtype }
+    }
+
+    /// Write a configuration file, and start ctld with it
+    fn start(dir: &TempDir, conf: &str) -> State {
+        let path = dir.path().join("ctl.conf");
+        fs::write(&path, conf.replace("@DIR@", dir.path().to_str().unwrap())).unwrap();
+        let conf = Conf::open(&path).unwrap();
+        State::start(path, conf).unwrap()
+    }
+
+    /// Expect LUNs to be created, numbering them from `first`
+    fn expect_create(ctx: &mock_ioc::__ctl_lun_req::Context, first: u32, times: usize) {
+        let next = AtomicU32::new(first);
+        ctx.expect()
+            .withf(|_fd, req| is(req, ffi::ctl_lunreq_type::CTL_LUNREQ_CREATE))
+            .times(times)
+            .returning(move |_fd, req| {
+                unsafe {
+                    (*req).reqdata.create.req_lun_id = next.fetch_add(1, Ordering::Relaxed);
+                    (*req).status = ffi::ctl_lun_status::CTL_LUN_OK;
+                }
+                Ok(0)
+            });
+    }
+
+    /// Expect the LUN with kernel id `id` to be removed
+    fn expect_rm(ctx: &mock_ioc::__ctl_lun_req::Context, id: u32) {
+        ctx.expect()
+            .withf(move |_fd, req| is(req, ffi::ctl_lunreq_type::CTL_LUNREQ_RM) &&
+                unsafe{ (**req).reqdata.rm.lun_id } == id)
+            .times(1)
+            .returning(|_fd, _req| Ok(0));
+    }
+
+    fn lun_ids(state: &State) -> Vec<(&str, u32)> {
+        state.luns.iter().map(|(name, klun)| (name.as_str(), klun.id())).collect()
+    }
+
+    /// Starting with an empty kernel, every LUN gets created.  Shutting down removes them.
+    #[test]
+    fn start_empty() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(NO_LUNS, NO_PORTS);
+        let ctx = mock_ioc::ctl_lun_req_context();
+        expect_create(&ctx, 0, 2);
+
+        let mut state = start(&dir, CONF);
+        assert_eq!(lun_ids(&state), vec![("disk0", 0), ("disk1", 1)]);
+
+        expect_rm(&ctx, 0);
+        expect_rm(&ctx, 1);
+        state.shutdown();
+        assert!(state.luns.is_empty());
+    }
+
+    /// LUNs left by a previous ctld are adopted if they still match, and removed if they don't
+    /// belong to the configuration any more.
+    #[test]
+    fn start_adopt() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let luns = LUNS.replace("<ctld_name>disk1</ctld_name>", "<ctld_name>old</ctld_name>");
+        let _lists = expect_lists(Box::leak(luns.into_boxed_str()), NO_PORTS);
+        let ctx = mock_ioc::ctl_lun_req_context();
+        expect_rm(&ctx, 1);
+        expect_create(&ctx, 2, 1);
+
+        let mut state = start(&dir, CONF);
+        assert_eq!(lun_ids(&state), vec![("disk0", 0), ("disk1", 2)]);
+
+        ctx.checkpoint();
+        expect_rm(&ctx, 0);
+        expect_rm(&ctx, 2);
+        state.shutdown();
+    }
+
+    /// Reloading applies the differences between the new configuration and the kernel
+    #[test]
+    fn reload() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, NO_PORTS);
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let mut state = start(&dir, CONF);
+
+        let conf = CONF.replace("size = 1048576\n    }\n    disk1", "size = 2097152\n    }\n    disk9");
+        fs::write(&state.config, conf.replace("@DIR@", dir.path().to_str().unwrap())).unwrap();
+        expect_rm(&ctx, 1);
+        ctx.expect()
+            .withf(|_fd, req| is(req, ffi::ctl_lunreq_type::CTL_LUNREQ_MODIFY) &&
+                unsafe{ (**req).reqdata.modify.lun_id == 0 &&
+                    (**req).reqdata.modify.lun_size_bytes == 2097152 })
+            .times(1)
+            .returning(|_fd, req| {
+                unsafe{ (*req).status = ffi::ctl_lun_status::CTL_LUN_OK };
+                Ok(0)
+            });
+        expect_create(&ctx, 2, 1);
+        let plan = state.reload().unwrap();
+        assert_eq!(plan.ops.iter().filter(|op| matches!(op, Op::RemoveLun{..})).count(), 1);
+        assert_eq!(lun_ids(&state), vec![("disk0", 0), ("disk9", 2)]);
+        assert_eq!(state.conf().luns["disk0"].size, Some(2097152));
+
+        ctx.checkpoint();
+        expect_rm(&ctx, 0);
+        expect_rm(&ctx, 2);
+        state.shutdown();
+    }
+
+    /// Removing a target terminates its sessions, but no others
+    #[test]
+    fn reload_remove_target() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let _m3 = CTL_ISCSI_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, NO_PORTS);
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let mut state = start(&dir, CONF);
+
+        let conf = CONF.replace("target {\n    \"iqn.2018-10.org.example:t0\"", "target {\n    t1");
+        fs::write(&state.config, conf.replace("@DIR@", dir.path().to_str().unwrap())).unwrap();
+        let iscsi_ctx = mock_ioc::ctl_iscsi_context();
+        iscsi_ctx.expect()
+            .withf(|_fd, req| unsafe{ (**req).type_ == ffi::ctl_iscsi_type::CTL_ISCSI_LIST })
+            .times(1)
+            .returning(|_fd, req| {
+                unsafe{ fill_iscsi_list(req, "<ctlislist>
+<connection id=\"3\"><initiator>iqn.a</initiator><initiator_addr>192.0.2.1</initiator_addr><target>iqn.2018-10.org.example:t0</target><target_portal_group_tag>257</target_portal_group_tag><header_digest>None</header_digest><data_digest>None</data_digest><max_recv_data_segment_length>8192</max_recv_data_segment_length><max_send_data_segment_length>8192</max_send_data_segment_length><max_burst_length>262144</max_burst_length><first_burst_length>65536</first_burst_length><immediate_data>0</immediate_data><iser>0</iser></connection>
+<connection id=\"4\"><initiator>iqn.b</initiator><initiator_addr>192.0.2.2</initiator_addr><target>t1</target><target_portal_group_tag>257</target_portal_group_tag><header_digest>None</header_digest><data_digest>None</data_digest><max_recv_data_segment_length>8192</max_recv_data_segment_length><max_send_data_segment_length>8192</max_send_data_segment_length><max_burst_length>262144</max_burst_length><first_burst_length>65536</first_burst_length><immediate_data>0</immediate_data><iser>0</iser></connection>
+</ctlislist>") };
+                Ok(0)
+            });
+        iscsi_ctx.expect()
+            .withf(|_fd, req| unsafe {
+                (**req).type_ == ffi::ctl_iscsi_type::CTL_ISCSI_TERMINATE &&
+                    (**req).data.terminate.connection_id == 3
+            })
+            .times(1)
+            .returning(|_fd, _req| Ok(0));
+        state.reload().unwrap();
+        assert!(state.conf().targets.contains_key("t1"));
+
+        expect_rm(&ctx, 0);
+        expect_rm(&ctx, 1);
+        state.shutdown();
+    }
+
+    /// Stale entries in an existing port's LUN map are removed.  Only the operations that were
+    /// actually performed are reported.
+    #[test]
+    fn reload_unmap() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let _m3 = CTL_LUN_MAP_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, "<ctlportlist>
+<targ_port id=\"3\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<ctld_portal_group_name>default</ctld_portal_group_name>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">0</lun>
+	<lun id=\"5\">1</lun>
+</targ_port>
+</ctlportlist>");
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let map_ctx = mock_ioc::ctl_lun_map_context();
+        map_ctx.expect()
+            .withf(|_fd, map| unsafe{ (**map).port == 3 && (**map).plun == 5 &&
+                (**map).lun == u32::MAX })
+            .times(1)
+            .returning(|_fd, _map| Ok(0));
+        let mut state = start(&dir, CONF);
+
+        map_ctx.checkpoint();
+        map_ctx.expect()
+            .times(1)
+            .returning(|_fd, _map| Ok(0));
+        let plan = state.reload().unwrap();
+        assert_eq!(plan.ops, vec![Op::UnmapLun{
+            target: String::from("iqn.2018-10.org.example:t0"),
+            portal_group: String::from("default"),
+            port: String::from("3"),
+            lun: 5
+        }]);
+
+        expect_rm(&ctx, 0);
+        expect_rm(&ctx, 1);
+        state.shutdown();
+    }
+
+    /// Planning reports what a reload would do, without doing it
+    #[test]
+    fn plan() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, NO_PORTS);
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let mut state = start(&dir, CONF);
+
+        let conf = CONF.replace("size = 1048576\n    }\n    disk1", "size = 2097152\n    }\n    disk1");
+        fs::write(&state.config, conf.replace("@DIR@", dir.path().to_str().unwrap())).unwrap();
+        let plan = state.plan().unwrap();
+        assert!(matches!(&plan.ops[..], [Op::ModifyLun{name, ..}, ..] if name == "disk0"),
+            "{:?}", plan);
+        assert_eq!(state.conf().luns["disk0"].size, Some(1048576));
+
+        expect_rm(&ctx, 0);
+        expect_rm(&ctx, 1);
+        state.shutdown();
+    }
+
+    /// An invalid configuration file leaves everything as it was
+    #[test]
+    fn reload_invalid() {
+        let _m = CTL_LIST_MTX.lock().unwrap();
+        let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+        let dir = TempDir::new().unwrap();
+        let _lists = expect_lists(LUNS, NO_PORTS);
+        let ctx = mock_ioc::ctl_lun_req_context();
+        let mut state = start(&dir, CONF);
+
+        fs::write(&state.config, "lun {").unwrap();
+        state.reload().unwrap_err();
+        assert_eq!(lun_ids(&state), vec![("disk0", 0), ("disk1", 1)]);
+
+        expect_rm(&ctx, 0);
+        expect_rm(&ctx, 1);
+        state.shutdown();
+    }
+
+    mod lun {
+        use super::*;
+
+        fn ramdisk(device_id: &str, size: Option<u64>) -> conf::Lun {
+            conf::Lun {
+                backend: conf::Backend::Ramdisk,
+                blocksize: None,
+                ctl_lun: None,
+                device_id: device_id.to_owned(),
+                device_type: conf::DeviceType::Disk,
+                options: Default::default(),
+                raw_options: Default::default(),
+                path: None,
+                serial: None,
+                size
+            }
+        }
+
+        #[test]
+        fn add() {
+            let _m = CTL_LIST_MTX.lock().unwrap();
+            let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+            let dir = TempDir::new().unwrap();
+            let _lists = expect_lists(LUNS, NO_PORTS);
+            let ctx = mock_ioc::ctl_lun_req_context();
+            let mut state = start(&dir, CONF);
+
+            expect_create(&ctx, 2, 1);
+            state.add_lun("disk2", ramdisk("dev2", Some(1 << 20))).unwrap();
+            assert_eq!(lun_ids(&state), vec![("disk0", 0), ("disk1", 1), ("disk2", 2)]);
+            assert!(state.conf().luns.contains_key("disk2"));
+
+            ctx.checkpoint();
+            for id in 0..3 {
+                expect_rm(&ctx, id);
+            }
+            state.shutdown();
+        }
+
+        /// A LUN that conflicts with the configuration is rejected before reaching the kernel
+        #[test]
+        fn add_invalid() {
+            let _m = CTL_LIST_MTX.lock().unwrap();
+            let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+            let dir = TempDir::new().unwrap();
+            let _lists = expect_lists(LUNS, NO_PORTS);
+            let ctx = mock_ioc::ctl_lun_req_context();
+            let mut state = start(&dir, CONF);
+
+            let e = state.add_lun("disk1", ramdisk("dev2", Some(1 << 20))).unwrap_err();
+            assert_eq!(e.to_string(), "lun \"disk1\" already exists");
+            let e = state.add_lun("disk2", ramdisk("dev0", Some(1 << 20))).unwrap_err();
+            assert!(e.to_string().contains("dev0"), "{}", e);
+            state.add_lun("disk2", ramdisk("dev2", None)).unwrap_err();
+            assert!(!state.conf().luns.contains_key("disk2"));
+
+            expect_rm(&ctx, 0);
+            expect_rm(&ctx, 1);
+            state.shutdown();
+        }
+
+        #[test]
+        fn remove() {
+            let _m = CTL_LIST_MTX.lock().unwrap();
+            let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+            let dir = TempDir::new().unwrap();
+            let _lists = expect_lists(LUNS, NO_PORTS);
+            let ctx = mock_ioc::ctl_lun_req_context();
+            let mut state = start(&dir, CONF);
+
+            expect_rm(&ctx, 1);
+            state.remove_lun("disk1").unwrap();
+            assert_eq!(lun_ids(&state), vec![("disk0", 0)]);
+            assert!(!state.conf().luns.contains_key("disk1"));
+
+            expect_rm(&ctx, 0);
+            state.shutdown();
+        }
+
+        /// LUNs that are mapped by a target can't be removed
+        #[test]
+        fn remove_in_use() {
+            let _m = CTL_LIST_MTX.lock().unwrap();
+            let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+            let dir = TempDir::new().unwrap();
+            let _lists = expect_lists(LUNS, NO_PORTS);
+            let ctx = mock_ioc::ctl_lun_req_context();
+            let mut state = start(&dir, CONF);
+
+            let e = state.remove_lun("disk0").unwrap_err();
+            assert_eq!(e.to_string(),
+                "lun \"disk0\" is used by target \"iqn.2018-10.org.example:t0\" as lun 0");
+            let e = state.remove_lun("disk9").unwrap_err();
+            assert_eq!(e.to_string(), "lun \"disk9\" does not exist");
+
+            expect_rm(&ctx, 0);
+            expect_rm(&ctx, 1);
+            state.shutdown();
+        }
+
+        #[test]
+        fn resize() {
+            let _m = CTL_LIST_MTX.lock().unwrap();
+            let _m2 = CTL_LUN_REQ_MTX.lock().unwrap();
+            let dir = TempDir::new().unwrap();
+            let _lists = expect_lists(LUNS, NO_PORTS);
+            let ctx = mock_ioc::ctl_lun_req_context();
+            let mut state = start(&dir, CONF);
+
+            ctx.expect()
+                .withf(|_fd, req| is(req, ffi::ctl_lunreq_type::CTL_LUNREQ_MODIFY) &&
+                    unsafe{ (**req).reqdata.modify.lun_id == 1 &&
+                        (**req).reqdata.modify.lun_size_bytes == 4194304 })
+                .times(1)
+                .returning(|_fd, req| {
+                    unsafe{ (*req).status = ffi::ctl_lun_status::CTL_LUN_OK };
+                    Ok(0)
+                });
+            state.resize_lun("disk1", 4194304).unwrap();
+            assert_eq!(state.conf().luns["disk1"].size, Some(4194304));
+
+            // Sizes must still be a multiple of the blocksize
+            let e = state.resize_lun("disk1", 1000).unwrap_err();
+            assert_eq!(format!("{:#}", e),
+                "lun \"disk1\": size 1000 is not a multiple of the blocksize 512");
+            assert_eq!(state.conf().luns["disk1"].size, Some(4194304));
+
+            ctx.checkpoint();
+            expect_rm(&ctx, 0);
+            expect_rm(&ctx, 1);
+            state.shutdown();
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Plain-text tables of LUNs, ports, and sessions, shared by ctlctl and dump.
+//!
+//! The tables read the JSON form of the kconf types, since that's how ctlctl gets them from the
+//! control socket.  dump converts its own lists with `serde_json::to_value`.
+use serde_json::Value;
+
+/// Render a JSON value as a table cell
+pub fn cell(v: &Value) -> String {
+    match v {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Bool(b) => String::from(if *b {"yes"} else {"no"}),
+        v => v.to_string()
+    }
+}
+
+/// Get an array from a JSON object, or an empty one if it has none
+pub fn array<'a>(v: &'a Value, key: &str) -> &'a [Value] {
+    v[key].as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Format rows with each column padded to the width of its widest cell
+pub fn format(header: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths = header.iter().map(|h| h.len()).collect::<Vec<_>>();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let mut out = String::new();
+    let mut push_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let line = cells.zip(widths.iter())
+            .map(|(cell, w)| format!("{:w$}", cell, w = w))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out.push_str(line.trim_end());
+        out.push('\n');
+    };
+    push_row(&mut header.iter().copied());
+    for row in rows {
+        push_row(&mut row.iter().map(String::as_str));
+    }
+    out
+}
+
+/// Format an array of serialized `kconf::Lun`s
+pub fn luns(luns: &Value) -> String {
+    let rows = luns.as_array().map(Vec::as_slice).unwrap_or_default().iter().map(|lun| {
+        let size = lun["size"].as_u64().unwrap_or_default() *
+            lun["blocksize"].as_u64().unwrap_or_default();
+        vec![
+            cell(&lun["id"]),
+            cell(&lun["backend_type"]),
+            size.to_string(),
+            cell(&lun["file"]),
+            cell(&lun["ctld_name"]),
+        ]
+    }).collect::<Vec<_>>();
+    format(&["ID", "BACKEND", "SIZE", "PATH", "CTLD_NAME"], &rows)
+}
+
+/// Format an array of serialized `kconf::TargPort`s
+pub fn ports(ports: &Value) -> String {
+    let rows = ports.as_array().map(Vec::as_slice).unwrap_or_default().iter().map(|port| {
+        let target = if port["cfiscsi_target"].is_null() {
+            cell(&port["target"])
+        } else {
+            cell(&port["cfiscsi_target"])
+        };
+        let lun_map = array(port, "luns").iter()
+            .map(|tl| format!("{}={}", cell(&tl["id"]), cell(&tl["lun"])))
+            .collect::<Vec<_>>()
+            .join(",");
+        vec![
+            cell(&port["id"]),
+            cell(&port["port_name"]),
+            target,
+            cell(&port["cfiscsi_portal_group_tag"]),
+            cell(&port["online"]),
+            lun_map
+        ]
+    }).collect::<Vec<_>>();
+    format(&["ID", "FRONTEND", "TARGET", "TAG", "ONLINE", "LUN_MAP"], &rows)
+}
+
+/// Format an array of serialized `kconf::Connection`s
+pub fn sessions(sessions: &Value) -> String {
+    let rows = sessions.as_array().map(Vec::as_slice).unwrap_or_default().iter().map(|conn| vec![
+        cell(&conn["id"]),
+        cell(&conn["initiator"]),
+        cell(&conn["initiator_addr"]),
+        cell(&conn["target"]),
+        cell(&conn["target_portal_group_tag"]),
+    ]).collect::<Vec<_>>();
+    format(&["ID", "INITIATOR", "ADDRESS", "TARGET", "TAG"], &rows)
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    use crate::kconf;
+
+    #[test]
+    fn align() {
+        let rows = vec![
+            vec![String::from("0"), String::from("disk0")],
+            vec![String::from("10"), String::new()],
+        ];
+        assert_eq!(format(&["ID", "NAME"], &rows), "ID  NAME\n0   disk0\n10\n");
+    }
+
+    /// kconf's lists, converted to JSON, format the same as the control socket's
+    #[test]
+    fn ports_from_kconf() {
+        let plist = kconf::Ctlportlist::from_xml("<ctlportlist>
+<targ_port id=\"3\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t0</cfiscsi_target>
+	<cfiscsi_portal_group_tag>257</cfiscsi_portal_group_tag>
+	<lun_map>on</lun_map>
+	<lun id=\"0\">2</lun>
+	<lun id=\"1\">0</lun>
+</targ_port>
+</ctlportlist>").unwrap();
+        let table = ports(&serde_json::to_value(&plist.targ_port).unwrap());
+        assert_eq!(table, "\
+ID  FRONTEND  TARGET                      TAG  ONLINE  LUN_MAP
+3   iscsi     iqn.2018-10.org.example:t0  257  yes     0=2,1=0
+");
+    }
+}
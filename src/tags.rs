@@ -0,0 +1,244 @@
+//! Assign portal-group tags that stay the same across restarts.
+//!
+//! Initiators use the tags to recognize a target's portal groups, for example to reinstate
+//! sessions with multipath.  So a portal group without an explicit tag keeps the tag it had
+//! before, either recovered from the kernel's existing ports or from a state file.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+
+use crate::conf::Conf;
+use crate::kconf;
+
+/// Automatically assigned tags start here, like ctld(8)
+const FIRST_AUTO_TAG: u16 = 0x100;
+
+/// Read the tags that were saved by a previous run.  A missing file is not an error.
+pub fn load(path: &Path) -> Result<BTreeMap<String, u16>> {
+    match fs::read_to_string(path) {
+        Ok(s) => serde_json::from_str(&s)
+            .with_context(|| format!("parsing {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!("reading {}", path.display()))
+    }
+}
+
+/// Save every portal group's tag, replacing the file atomically.
+pub fn save(path: &Path, conf: &Conf) -> Result<()> {
+    let tags = conf.portal_groups.iter()
+        .filter_map(|(name, pg)| pg.tag.map(|tag| (name.as_str(), tag)))
+        .collect::<BTreeMap<_, _>>();
+    let json = serde_json::to_string_pretty(&tags).context("serializing tags")?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, json).with_context(|| format!("writing {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("renaming {} to {}", tmp.display(),
+        path.display()))
+}
+
+/// Give every portal group a tag.  In order of preference, a portal group gets:
+///
+/// 1. The tag set in the configuration file
+/// 2. The tag of the kernel's existing ports for that portal group
+/// 3. The tag that it had last time, from `saved`
+/// 4. The lowest tag that isn't used by any other portal group
+///
+/// A recovered or saved tag that collides with another portal group's tag is discarded, with a
+/// warning, in favor of a new one.
+pub fn assign(conf: &mut Conf, kport_list: &kconf::Ctlportlist, saved: &BTreeMap<String, u16>)
+    -> Result<()>
+{
+    let mut kernel = BTreeMap::new();
+    for kport in kport_list.targ_port.iter() {
+        if let (Some(name), Some(tag)) =
+            (&kport.ctld_portal_group_name, kport.cfiscsi_portal_group_tag)
+        {
+            if let Some(prev) = kernel.insert(name.as_str(), tag) {
+                if prev != tag {
+                    bail!("kernel ports use both tag {} and tag {} for portal-group \"{}\"",
+                        prev, tag, name);
+                }
+            }
+        }
+    }
+
+    let mut names = conf.portal_groups.keys().cloned().collect::<Vec<_>>();
+    names.sort_unstable();
+    // Tags that are already taken, and by whom
+    let mut used = BTreeMap::new();
+    for name in names.iter() {
+        if let Some(tag) = conf.portal_groups[name].tag {
+            if let Some(other) = used.insert(tag, name.clone()) {
+                bail!("tag {} is used by both \"{}\" and \"{}\"", tag, other, name);
+            }
+        }
+    }
+
+    let mut unassigned = BTreeSet::new();
+    for name in names.iter() {
+        if conf.portal_groups[name].tag.is_some() {
+            continue;
+        }
+        let candidates = [
+            ("kernel", kernel.get(name.as_str()).copied()),
+            ("saved", saved.get(name).copied()),
+        ];
+        let mut assigned = false;
+        for (source, tag) in candidates {
+            let Some(tag) = tag else {
+                continue;
+            };
+            if let Some(other) = used.get(&tag) {
+                crate::warn!("portal-group \"{}\": {} tag {} is already used by \"{}\"",
+                    name, source, tag, other);
+                continue;
+            }
+            used.insert(tag, name.clone());
+            conf.portal_groups.get_mut(name).unwrap().tag = Some(tag);
+            assigned = true;
+            break;
+        }
+        if !assigned {
+            unassigned.insert(name.clone());
+        }
+    }
+
+    let mut next = FIRST_AUTO_TAG;
+    for name in unassigned {
+        while used.contains_key(&next) {
+            next = next.checked_add(1).context("ran out of portal-group tags")?;
+        }
+        used.insert(next, name.clone());
+        conf.portal_groups.get_mut(&name).unwrap().tag = Some(next);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod t {
+    use std::io::Write;
+
+    use super::*;
+
+    use tempfile::{NamedTempFile, TempDir};
+
+    fn conf() -> Conf {
+        let mut f = NamedTempFile::new().unwrap();
+        f.write_all(b"
+portal-group {
+    explicit {
+        listen = \"0.0.0.0:3260\"
+        tag = 256
+    }
+    pga {
+        listen = \"0.0.0.0:3261\"
+    }
+    pgb {
+        listen = \"0.0.0.0:3262\"
+    }
+}
+lun {}
+target {}").unwrap();
+        Conf::open(f.path()).unwrap()
+    }
+
+    fn tags(conf: &Conf) -> BTreeMap<&str, u16> {
+        conf.portal_groups.iter()
+            .map(|(name, pg)| (name.as_str(), pg.tag.unwrap()))
+            .collect()
+    }
+
+    fn kport_list(ports: &[(&str, u16)]) -> kconf::Ctlportlist {
+        let mut xml = String::from("<ctlportlist>");
+        for (i, (pg, tag)) in ports.iter().enumerate() {
+            xml.push_str(&format!("<targ_port id=\"{}\">
+	<frontend_type>iscsi</frontend_type>
+	<port_type>16</port_type>
+	<online>YES</online>
+	<port_name>iscsi</port_name>
+	<physical_port>0</physical_port>
+	<virtual_port>0</virtual_port>
+	<cfiscsi_target>iqn.2018-10.org.example:t{}</cfiscsi_target>
+	<cfiscsi_portal_group_tag>{}</cfiscsi_portal_group_tag>
+	<ctld_portal_group_name>{}</ctld_portal_group_name>
+</targ_port>", i, i, tag, pg));
+        }
+        xml.push_str("</ctlportlist>");
+        kconf::Ctlportlist::from_xml(&xml).unwrap()
+    }
+
+    /// With no history, new tags are allocated in name order, around the explicit ones
+    #[test]
+    fn fresh() {
+        let mut conf = conf();
+        assign(&mut conf, &kport_list(&[]), &BTreeMap::new()).unwrap();
+        let tags = tags(&conf);
+        assert_eq!(tags["explicit"], 256);
+        assert_eq!(tags["default"], 257);
+        assert_eq!(tags["pga"], 258);
+        assert_eq!(tags["pgb"], 259);
+    }
+
+    /// Tags are recovered from the kernel in preference to the state file
+    #[test]
+    fn recovered() {
+        let mut conf = conf();
+        let saved = BTreeMap::from([
+            (String::from("pga"), 1000),
+            (String::from("pgb"), 1001),
+        ]);
+        assign(&mut conf, &kport_list(&[("pga", 500)]), &saved).unwrap();
+        let tags = tags(&conf);
+        assert_eq!(tags["explicit"], 256);
+        assert_eq!(tags["pga"], 500);
+        assert_eq!(tags["pgb"], 1001);
+        assert_eq!(tags["default"], 257);
+    }
+
+    /// A remembered tag that is now claimed explicitly by another portal group is replaced
+    #[test]
+    fn collision() {
+        let mut conf = conf();
+        let saved = BTreeMap::from([(String::from("pga"), 256)]);
+        assign(&mut conf, &kport_list(&[("pgb", 256)]), &saved).unwrap();
+        let tags = tags(&conf);
+        assert_eq!(tags["explicit"], 256);
+        assert_eq!(tags["default"], 257);
+        assert_eq!(tags["pga"], 258);
+        assert_eq!(tags["pgb"], 259);
+    }
+
+    /// The kernel should never use two tags for one portal group
+    #[test]
+    fn kernel_inconsistent() {
+        let mut conf = conf();
+        let e = assign(&mut conf, &kport_list(&[("pga", 500), ("pga", 501)]), &BTreeMap::new())
+            .unwrap_err();
+        assert_eq!(format!("{:#}", e),
+            "kernel ports use both tag 500 and tag 501 for portal-group \"pga\"");
+    }
+
+    /// Tags survive a restart by way of the state file
+    #[test]
+    fn save_and_load() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("subdir/tags.json");
+        assert!(load(&path).unwrap().is_empty());
+
+        let mut conf = conf();
+        assign(&mut conf, &kport_list(&[("pgb", 700)]), &BTreeMap::new()).unwrap();
+        save(&path, &conf).unwrap();
+
+        let mut conf2 = self::conf();
+        assign(&mut conf2, &kport_list(&[]), &load(&path).unwrap()).unwrap();
+        assert_eq!(tags(&conf2), tags(&conf));
+        assert_eq!(tags(&conf2)["pgb"], 700);
+    }
+}